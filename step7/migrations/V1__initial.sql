@@ -0,0 +1,6 @@
+CREATE SCHEMA IF NOT EXISTS users_schema;
+
+CREATE TABLE IF NOT EXISTS users_schema.users (
+	id int8 NOT NULL GENERATED ALWAYS AS IDENTITY,
+	"name" varchar(255) NOT NULL UNIQUE
+);
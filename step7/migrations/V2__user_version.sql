@@ -0,0 +1 @@
+ALTER TABLE users_schema.users ADD COLUMN IF NOT EXISTS version int8 NOT NULL DEFAULT 1;
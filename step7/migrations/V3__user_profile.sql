@@ -0,0 +1,10 @@
+-- users created before this migration have no email, so the column stays nullable
+ALTER TABLE users_schema.users
+    ADD COLUMN IF NOT EXISTS email varchar(255),
+    ADD COLUMN IF NOT EXISTS display_name varchar(255),
+    ADD COLUMN IF NOT EXISTS status varchar(16) NOT NULL DEFAULT 'active'
+        CHECK (status IN ('active', 'suspended', 'deleted')),
+    ADD COLUMN IF NOT EXISTS created_at timestamptz NOT NULL DEFAULT now(),
+    ADD COLUMN IF NOT EXISTS updated_at timestamptz NOT NULL DEFAULT now();
+
+CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_key ON users_schema.users (lower(email));
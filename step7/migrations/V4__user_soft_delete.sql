@@ -0,0 +1,4 @@
+ALTER TABLE users_schema.users ADD COLUMN IF NOT EXISTS deleted_at timestamptz;
+
+-- the purge job looks up users deleted before the retention window
+CREATE INDEX IF NOT EXISTS users_deleted_at_idx ON users_schema.users (deleted_at) WHERE deleted_at IS NOT NULL;
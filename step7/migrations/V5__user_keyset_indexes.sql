@@ -0,0 +1,3 @@
+-- keyset pagination continues after the last id or name and id of a page
+CREATE UNIQUE INDEX IF NOT EXISTS users_id_key ON users_schema.users (id);
+CREATE INDEX IF NOT EXISTS users_name_id_idx ON users_schema.users (name, id);
@@ -0,0 +1,6 @@
+-- fuzzy search by name and display name, pg_trgm is a trusted extension since Postgres 13
+CREATE EXTENSION IF NOT EXISTS pg_trgm;
+
+-- serve both the similarity operator % and the ilike substring match of the search
+CREATE INDEX IF NOT EXISTS users_name_trgm_idx ON users_schema.users USING gin (name gin_trgm_ops);
+CREATE INDEX IF NOT EXISTS users_display_name_trgm_idx ON users_schema.users USING gin (display_name gin_trgm_ops);
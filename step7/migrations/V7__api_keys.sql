@@ -0,0 +1,11 @@
+-- keys of service accounts, the secret is only kept as argon2 hash
+CREATE TABLE IF NOT EXISTS users_schema.api_keys (
+    id text PRIMARY KEY,
+    hash text NOT NULL,
+    owner text NOT NULL,
+    -- space separated permissions, e.g. 'users:read users:write'
+    scopes text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz,
+    last_used_at timestamptz
+);
@@ -0,0 +1,21 @@
+-- argon2id hashes of user passwords, users without a row can not log in
+CREATE TABLE IF NOT EXISTS users_schema.user_passwords (
+    user_id int8 PRIMARY KEY REFERENCES users_schema.users (id) ON DELETE CASCADE,
+    hash text NOT NULL,
+    updated_at timestamptz NOT NULL DEFAULT now()
+);
+
+-- single use refresh tokens, a family is the chain of rotations of one login
+CREATE TABLE IF NOT EXISTS users_schema.refresh_tokens (
+    id text PRIMARY KEY,
+    family text NOT NULL,
+    user_id int8 NOT NULL REFERENCES users_schema.users (id) ON DELETE CASCADE,
+    -- sha256 of the secret
+    hash text NOT NULL,
+    created_at timestamptz NOT NULL DEFAULT now(),
+    expires_at timestamptz NOT NULL,
+    revoked_at timestamptz
+);
+
+CREATE INDEX IF NOT EXISTS refresh_tokens_family_idx ON users_schema.refresh_tokens (family);
+CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_idx ON users_schema.refresh_tokens (user_id);
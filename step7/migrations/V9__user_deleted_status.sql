@@ -0,0 +1,4 @@
+-- the deleted status is kept in step with deleted_at, soft deleted users get it
+-- and users marked deleted by status alone are soft deleted
+UPDATE users_schema.users SET status = 'deleted' WHERE deleted_at IS NOT NULL AND status <> 'deleted';
+UPDATE users_schema.users SET deleted_at = updated_at WHERE deleted_at IS NULL AND status = 'deleted';
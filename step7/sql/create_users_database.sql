@@ -15,5 +15,12 @@ create role rw_user
   connection limit 100
   password '123qweasd';
 
- GRANT CONNECT ON DATABASE users TO rw_user;
+ GRANT CONNECT, CREATE ON DATABASE users TO rw_user;
 
+-- users_schema objects are created by the service on startup (see ../migrations).
+-- refinery keeps its history table in the public schema, so on Postgres 15+
+-- connect to the users database and run:
+-- GRANT CREATE ON SCHEMA public TO rw_user;
+
+-- The search migration installs pg_trgm, which needs a superuser before Postgres 13:
+-- CREATE EXTENSION pg_trgm;
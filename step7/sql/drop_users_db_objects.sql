@@ -4,3 +4,5 @@ revoke all on schema users_schema from rw_user;
 drop table users_schema.users;
 
 drop schema users_schema;
+
+drop table refinery_schema_history;
@@ -0,0 +1,434 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use actix_web::web;
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, SecondsFormat, Utc};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use rbatis::{rbatis::Rbatis, DateTimeUtc};
+use rbson::Bson;
+use serde::{Deserialize, Serialize};
+use validator::Validate;
+
+use crate::auth::{AuthError, Claims, Permission};
+use crate::model::UserDAOError;
+
+pub const HEADER: &str = "X-API-Key";
+
+// Key of a service account, sent as `X-API-Key: {id}.{secret}`. Only the argon2 hash of the secret is stored.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub owner: String,
+    pub scopes: Vec<Permission>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(skip)]
+    pub hash: String,
+}
+
+impl ApiKey {
+    pub fn expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Validate)]
+pub struct NewApiKey {
+    #[validate(length(min = 1, max = 255))]
+    pub owner: String,
+    #[validate(length(min = 1))]
+    pub scopes: Vec<Permission>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+// Response of POST /api-keys, the only time the secret is shown
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CreatedApiKey {
+    #[serde(flatten)]
+    pub key: ApiKey,
+    pub token: String,
+}
+
+#[async_trait]
+pub trait ApiKeyDAO: Sync + Send {
+    async fn insert(&self, key: ApiKey) -> Result<ApiKey, UserDAOError>;
+    async fn list(&self) -> Result<Vec<ApiKey>, UserDAOError>;
+    async fn find(&self, id: &str) -> Result<Option<ApiKey>, UserDAOError>;
+    async fn touch(&self, id: &str, at: DateTime<Utc>) -> Result<(), UserDAOError>;
+    // false when there was no such key
+    async fn delete(&self, id: &str) -> Result<bool, UserDAOError>;
+}
+
+// The secrets are 256 random bits, guessing them offline is hopeless even with a cheap hash.
+// A single light argon2id pass keeps key checks fast on every request.
+fn hasher() -> Argon2<'static> {
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, Params::new(4096, 1, 1, None).expect("valid argon2 params"))
+}
+
+fn hash_secret(secret: &str) -> String {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).expect("valid salt");
+    hasher().hash_password(secret.as_bytes(), &salt).expect("argon2 hash").to_string()
+}
+
+// parameters are read from the hash, keys outlive changes of hasher()
+fn verify_secret(secret: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|parsed| hasher().verify_password(secret.as_bytes(), &parsed).is_ok())
+        .unwrap_or(false)
+}
+
+lazy_static! {
+    // verified against when the id is unknown, so it takes as long as a wrong secret
+    static ref DUMMY_HASH: String = hash_secret("no key has this secret");
+}
+
+fn blocking_error(err: impl std::fmt::Display) -> UserDAOError {
+    UserDAOError::Storage(err.to_string().into())
+}
+
+pub async fn issue(store: &dyn ApiKeyDAO, new_key: &NewApiKey) -> Result<CreatedApiKey, UserDAOError> {
+    new_key.validate()?;
+
+    let mut id = [0u8; 8];
+    let mut secret = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut id);
+    rand::thread_rng().fill_bytes(&mut secret);
+    let id: String = id.iter().map(|b| format!("{:02x}", b)).collect();
+    let secret = base64::encode_config(secret, base64::URL_SAFE_NO_PAD);
+
+    let mut scopes = new_key.scopes.clone();
+    scopes.sort();
+    scopes.dedup();
+    let to_hash = secret.clone();
+    let hash = web::block(move || hash_secret(&to_hash)).await.map_err(blocking_error)?;
+
+    let key = store.insert(ApiKey {
+        id,
+        owner: new_key.owner.clone(),
+        scopes,
+        created_at: Utc::now(),
+        expires_at: new_key.expires_at,
+        last_used_at: None,
+        hash,
+    }).await?;
+    Ok(CreatedApiKey { token: format!("{}.{}", key.id, secret), key })
+}
+
+// Unknown ids and wrong secrets look the same, expiry is only told to holders of the secret
+pub async fn authenticate(store: &dyn ApiKeyDAO, token: &str) -> Result<Claims, actix_web::Error> {
+    let (id, secret) = token.trim().split_once('.').ok_or(AuthError::InvalidKey("malformed API key"))?;
+    let key = store.find(id).await?;
+
+    let hash = key.as_ref().map(|key| key.hash.clone()).unwrap_or_else(|| DUMMY_HASH.clone());
+    let secret = secret.to_string();
+    let verified = web::block(move || verify_secret(&secret, &hash)).await?;
+    let key = key.filter(|_| verified).ok_or(AuthError::InvalidKey("unknown API key"))?;
+    let now = Utc::now();
+    if key.expired(now) {
+        return Err(AuthError::InvalidKey("API key expired").into());
+    }
+
+    store.touch(&key.id, now).await?;
+    Ok(Claims { sub: format!("api-key:{}", key.id), roles: vec![], scopes: key.scopes })
+}
+
+#[derive(Default)]
+pub struct ApiKeyInMemoryDAO {
+    keys: Mutex<BTreeMap<String, ApiKey>>,
+}
+
+#[async_trait]
+impl ApiKeyDAO for ApiKeyInMemoryDAO {
+    async fn insert(&self, key: ApiKey) -> Result<ApiKey, UserDAOError> {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.contains_key(&key.id) {
+            return Err(UserDAOError::AlreadyExists);
+        }
+        keys.insert(key.id.clone(), key.clone());
+        Ok(key)
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKey>, UserDAOError> {
+        let mut keys: Vec<ApiKey> = self.keys.lock().unwrap().values().cloned().collect();
+        keys.sort_by(|a, b| a.created_at.cmp(&b.created_at).then_with(|| a.id.cmp(&b.id)));
+        Ok(keys)
+    }
+
+    async fn find(&self, id: &str) -> Result<Option<ApiKey>, UserDAOError> {
+        Ok(self.keys.lock().unwrap().get(id).cloned())
+    }
+
+    async fn touch(&self, id: &str, at: DateTime<Utc>) -> Result<(), UserDAOError> {
+        if let Some(key) = self.keys.lock().unwrap().get_mut(id) {
+            key.last_used_at = Some(at);
+        }
+        Ok(())
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, UserDAOError> {
+        Ok(self.keys.lock().unwrap().remove(id).is_some())
+    }
+}
+
+// Row of both SQL stores, scopes are kept space separated
+#[derive(Clone, Debug, Deserialize)]
+struct ApiKeyRow {
+    id: String,
+    hash: String,
+    owner: String,
+    scopes: String,
+    created_at: DateTimeUtc,
+    expires_at: Option<DateTimeUtc>,
+    last_used_at: Option<DateTimeUtc>,
+}
+
+impl From<ApiKeyRow> for ApiKey {
+    fn from(row: ApiKeyRow) -> Self {
+        ApiKey {
+            id: row.id,
+            owner: row.owner,
+            // scopes dropped from Permission are no longer granted
+            scopes: row.scopes.split_whitespace().filter_map(|scope| scope.parse().ok()).collect(),
+            created_at: row.created_at.inner,
+            expires_at: row.expires_at.map(|at| at.inner),
+            last_used_at: row.last_used_at.map(|at| at.inner),
+            hash: row.hash,
+        }
+    }
+}
+
+fn timestamp(at: DateTime<Utc>) -> Bson {
+    Bson::String(at.to_rfc3339_opts(SecondsFormat::Micros, true))
+}
+
+// Postgres keeps the parameter types of the first execution of a statement, a key without expiry
+// gets a null literal instead of a null argument so both variants prepare their own statement
+fn insert_args(key: &ApiKey, param: &str) -> (Vec<Bson>, String) {
+    let scopes: Vec<String> = key.scopes.iter().map(Permission::to_string).collect();
+    let mut args = vec![
+        Bson::String(key.id.clone()),
+        Bson::String(key.hash.clone()),
+        Bson::String(key.owner.clone()),
+        Bson::String(scopes.join(" ")),
+        timestamp(key.created_at),
+    ];
+    let expires_at = match key.expires_at {
+        Some(at) => {
+            args.push(timestamp(at));
+            param.to_string()
+        },
+        None => "null".to_string(),
+    };
+    (args, expires_at)
+}
+
+// Shares the pool of UserDbDAO, the table is created by migration V7
+pub struct ApiKeyDbDAO {
+    rb: Arc<Rbatis>,
+}
+
+impl ApiKeyDbDAO {
+    pub fn new(rb: Arc<Rbatis>) -> ApiKeyDbDAO {
+        ApiKeyDbDAO { rb }
+    }
+}
+
+#[async_trait]
+impl ApiKeyDAO for ApiKeyDbDAO {
+    async fn insert(&self, key: ApiKey) -> Result<ApiKey, UserDAOError> {
+        let (args, expires_at) = insert_args(&key, "$6::timestamptz");
+        let sql = format!("insert into users_schema.api_keys (id, hash, owner, scopes, created_at, expires_at) \
+            values ($1, $2, $3, $4, $5::timestamptz, {}) returning *", expires_at);
+        self.rb.fetch::<ApiKeyRow>(&sql, args)
+            .await
+            .map(ApiKey::from)
+            .map_err(UserDAOError::from)
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKey>, UserDAOError> {
+        self.rb.fetch::<Vec<ApiKeyRow>>("select * from users_schema.api_keys order by created_at, id", vec![])
+            .await
+            .map(|rows| rows.into_iter().map(ApiKey::from).collect())
+            .map_err(UserDAOError::from)
+    }
+
+    async fn find(&self, id: &str) -> Result<Option<ApiKey>, UserDAOError> {
+        self.rb.fetch::<Option<ApiKeyRow>>("select * from users_schema.api_keys where id = $1", vec![Bson::String(id.to_string())])
+            .await
+            .map(|row| row.map(ApiKey::from))
+            .map_err(UserDAOError::from)
+    }
+
+    async fn touch(&self, id: &str, at: DateTime<Utc>) -> Result<(), UserDAOError> {
+        self.rb.exec("update users_schema.api_keys set last_used_at = $2::timestamptz where id = $1", vec![Bson::String(id.to_string()), timestamp(at)])
+            .await
+            .map(|_| ())
+            .map_err(UserDAOError::from)
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, UserDAOError> {
+        self.rb.exec("delete from users_schema.api_keys where id = $1", vec![Bson::String(id.to_string())])
+            .await
+            .map(|result| result.rows_affected > 0)
+            .map_err(UserDAOError::from)
+    }
+}
+
+// Shares the pool of UserSqliteDAO, timestamps are kept as text like in users
+pub struct ApiKeySqliteDAO {
+    rb: Arc<Rbatis>,
+}
+
+impl ApiKeySqliteDAO {
+    const CREATE_API_KEYS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS api_keys (\
+        id TEXT PRIMARY KEY, \
+        hash TEXT NOT NULL, \
+        owner TEXT NOT NULL, \
+        scopes TEXT NOT NULL, \
+        created_at TEXT NOT NULL, \
+        expires_at TEXT, \
+        last_used_at TEXT\
+    )";
+    const COLUMNS: &'static str = "id, hash, owner, scopes, created_at, expires_at, last_used_at";
+
+    pub async fn new(rb: Arc<Rbatis>) -> ApiKeySqliteDAO {
+        rb.exec(ApiKeySqliteDAO::CREATE_API_KEYS_TABLE, vec![]).await.expect("api_keys table not created");
+        ApiKeySqliteDAO { rb }
+    }
+}
+
+#[async_trait]
+impl ApiKeyDAO for ApiKeySqliteDAO {
+    async fn insert(&self, key: ApiKey) -> Result<ApiKey, UserDAOError> {
+        let (args, expires_at) = insert_args(&key, "?");
+        let sql = format!("insert into api_keys (id, hash, owner, scopes, created_at, expires_at) values (?, ?, ?, ?, ?, {}) returning {}",
+            expires_at, ApiKeySqliteDAO::COLUMNS);
+        self.rb.fetch::<ApiKeyRow>(&sql, args)
+            .await
+            .map(ApiKey::from)
+            .map_err(UserDAOError::from)
+    }
+
+    async fn list(&self) -> Result<Vec<ApiKey>, UserDAOError> {
+        let sql = format!("select {} from api_keys order by created_at, id", ApiKeySqliteDAO::COLUMNS);
+        self.rb.fetch::<Vec<ApiKeyRow>>(&sql, vec![])
+            .await
+            .map(|rows| rows.into_iter().map(ApiKey::from).collect())
+            .map_err(UserDAOError::from)
+    }
+
+    async fn find(&self, id: &str) -> Result<Option<ApiKey>, UserDAOError> {
+        let sql = format!("select {} from api_keys where id = ?", ApiKeySqliteDAO::COLUMNS);
+        self.rb.fetch::<Option<ApiKeyRow>>(&sql, vec![Bson::String(id.to_string())])
+            .await
+            .map(|row| row.map(ApiKey::from))
+            .map_err(UserDAOError::from)
+    }
+
+    async fn touch(&self, id: &str, at: DateTime<Utc>) -> Result<(), UserDAOError> {
+        self.rb.exec("update api_keys set last_used_at = ? where id = ?", vec![timestamp(at), Bson::String(id.to_string())])
+            .await
+            .map(|_| ())
+            .map_err(UserDAOError::from)
+    }
+
+    async fn delete(&self, id: &str) -> Result<bool, UserDAOError> {
+        self.rb.exec("delete from api_keys where id = ?", vec![Bson::String(id.to_string())])
+            .await
+            .map(|result| result.rows_affected > 0)
+            .map_err(UserDAOError::from)
+    }
+}
+
+#[cfg(test)]
+async fn key_store_conformance(store: &dyn ApiKeyDAO) {
+    use chrono::Duration;
+
+    let new_key = NewApiKey { owner: "billing".to_string(), scopes: vec![Permission::Write, Permission::Read, Permission::Read], expires_at: None };
+    let created = issue(store, &new_key).await.unwrap();
+    assert_eq!(vec![Permission::Read, Permission::Write], created.key.scopes);
+    assert!(created.token.starts_with(&format!("{}.", created.key.id)));
+    assert!(created.key.hash.starts_with("$argon2id$"));
+    assert!(!created.key.hash.contains(created.token.split_once('.').unwrap().1));
+
+    let claims = authenticate(store, &created.token).await.unwrap();
+    assert_eq!(format!("api-key:{}", created.key.id), claims.sub);
+    assert_eq!(vec![Permission::Read, Permission::Write], claims.scopes);
+    assert!(store.find(&created.key.id).await.unwrap().unwrap().last_used_at.is_some());
+
+    let wrong_secret = format!("{}.guessed", created.key.id);
+    assert_eq!("Invalid API key: unknown API key", authenticate(store, &wrong_secret).await.unwrap_err().to_string());
+    assert_eq!("Invalid API key: unknown API key", authenticate(store, "0000000000000000.guessed").await.unwrap_err().to_string());
+    assert_eq!("Invalid API key: malformed API key", authenticate(store, "no-separator").await.unwrap_err().to_string());
+
+    let expired = NewApiKey { owner: "reports".to_string(), scopes: vec![Permission::Read], expires_at: Some(Utc::now() - Duration::minutes(1)) };
+    let expired = issue(store, &expired).await.unwrap();
+    assert_eq!("Invalid API key: API key expired", authenticate(store, &expired.token).await.unwrap_err().to_string());
+    assert_eq!(None, store.find(&expired.key.id).await.unwrap().unwrap().last_used_at);
+
+    let owners: Vec<String> = store.list().await.unwrap().into_iter().map(|key| key.owner).collect();
+    assert_eq!(vec!["billing", "reports"], owners);
+
+    let invalid = NewApiKey { owner: String::new(), scopes: vec![], expires_at: None };
+    assert!(matches!(issue(store, &invalid).await, Err(UserDAOError::Validation(_))));
+
+    assert!(store.delete(&created.key.id).await.unwrap());
+    assert!(!store.delete(&created.key.id).await.unwrap());
+    assert_eq!(None, store.find(&created.key.id).await.unwrap());
+}
+
+#[cfg(test)]
+mod in_memory_conformance {
+    use super::{key_store_conformance, ApiKeyInMemoryDAO};
+
+    #[actix_web::test]
+    async fn conformance_key_store() {
+        key_store_conformance(&ApiKeyInMemoryDAO::default()).await;
+    }
+}
+
+#[cfg(test)]
+mod sqlite_conformance {
+    use crate::configs::Sqlite;
+    use crate::services::UserSqliteDAO;
+    use super::{key_store_conformance, ApiKeySqliteDAO};
+
+    #[actix_web::test]
+    async fn conformance_key_store() {
+        let path = std::env::temp_dir().join(format!("api-keys-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let users = UserSqliteDAO::new(&Sqlite { path: path.to_string_lossy().to_string() }).await;
+        key_store_conformance(&ApiKeySqliteDAO::new(users.pool()).await).await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+#[cfg(test)]
+mod postgres_conformance {
+    use crate::configs::Configuration;
+    use crate::migrations;
+    use crate::services::UserDbDAO;
+    use super::{key_store_conformance, ApiKeyDbDAO};
+
+    #[actix_web::test]
+    #[ignore = "needs a local Postgres"]
+    async fn conformance_key_store() {
+        let cfg = Configuration::load_from_file("tests/db_full.yaml").unwrap();
+        let db = cfg.store.unwrap().db.unwrap();
+        migrations::migrate(&db).await.unwrap();
+
+        let users = UserDbDAO::new(&db).await;
+        users.pool().exec("TRUNCATE users_schema.api_keys", vec![]).await.unwrap();
+        key_store_conformance(&ApiKeyDbDAO::new(users.pool())).await;
+    }
+}
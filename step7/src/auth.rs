@@ -0,0 +1,585 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Display;
+use std::fs;
+use std::future::{ready, Ready};
+use std::marker::PhantomData;
+use std::rc::Rc;
+use std::str::FromStr;
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header, StatusCode},
+    web::Data,
+    Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, ResponseError,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use jsonwebtoken::{
+    decode, decode_header,
+    errors::ErrorKind,
+    jwk::{AlgorithmParameters, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{api_keys::{self, ApiKeyDAO}, configs::Security, problem::Problem};
+
+const REALM: &str = "users";
+
+// reachable without credentials, the login routes check their own
+pub const PUBLIC_PATHS: &[&str] = &["/auth/login", "/auth/refresh", "/auth/logout", "/metrics", "/health/live", "/health/ready"];
+
+// Verified claims of the request token, exp, nbf, aud and iss are checked before handlers see them
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Claims {
+    // id of the user the token was issued to
+    pub sub: String,
+    // mapped to permissions by security.roles
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    // granted directly, by the scopes of an API key
+    #[serde(skip)]
+    pub scopes: Vec<Permission>,
+}
+
+impl Claims {
+    pub fn user_id(&self) -> Option<u64> {
+        self.sub.parse().ok()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Permission {
+    #[serde(rename = "users:read")]
+    Read,
+    #[serde(rename = "users:write")]
+    Write,
+    // other users' records and the admin views of soft deleted users
+    #[serde(rename = "users:admin")]
+    Admin,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum AuthError {
+    Missing,
+    Invalid(&'static str),
+    InvalidKey(&'static str),
+    Forbidden(String),
+}
+
+struct VerifyingKey {
+    kid: Option<String>,
+    algorithm: Algorithm,
+    key: DecodingKey,
+}
+
+// Validates HS256 and RS256 bearer tokens against the keys of the security config
+pub struct JwtAuth {
+    keys: Vec<VerifyingKey>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    leeway: u64,
+    roles: BTreeMap<String, Vec<Permission>>,
+}
+
+impl JwtAuth {
+    pub fn from_config(cfg: &Security) -> std::io::Result<JwtAuth> {
+        let invalid = |what: &str, err: &dyn Display| std::io::Error::other(format!("Invalid {}: {}", what, err));
+
+        let mut keys = vec![];
+        if let Some(secret) = &cfg.hs256_secret {
+            keys.push(VerifyingKey { kid: None, algorithm: Algorithm::HS256, key: DecodingKey::from_secret(secret.as_bytes()) });
+        }
+        if let Some(path) = &cfg.rs256_public_key {
+            let pem = fs::read(path).map_err(|err| invalid(path, &err))?;
+            let key = DecodingKey::from_rsa_pem(&pem).map_err(|err| invalid(path, &err))?;
+            keys.push(VerifyingKey { kid: None, algorithm: Algorithm::RS256, key });
+        }
+        if let Some(path) = &cfg.jwks_file {
+            let json = fs::read(path).map_err(|err| invalid(path, &err))?;
+            let jwks: JwkSet = serde_json::from_slice(&json).map_err(|err| invalid(path, &err))?;
+            for jwk in &jwks.keys {
+                let algorithm = match &jwk.algorithm {
+                    AlgorithmParameters::RSA(_) => Algorithm::RS256,
+                    AlgorithmParameters::OctetKey(_) => Algorithm::HS256,
+                    _ => return Err(invalid(path, &"only RSA and oct keys are supported")),
+                };
+                let key = DecodingKey::from_jwk(jwk).map_err(|err| invalid(path, &err))?;
+                keys.push(VerifyingKey { kid: jwk.common.key_id.clone(), algorithm, key });
+            }
+        }
+
+        if keys.is_empty() {
+            log::info!("No JWT key configured, requests are authenticated by API key only");
+        }
+        Ok(JwtAuth {
+            keys,
+            issuer: cfg.issuer.clone(),
+            audience: cfg.audience.clone(),
+            leeway: cfg.leeway_secs,
+            roles: cfg.roles.clone(),
+        })
+    }
+
+    // roles missing from the config grant nothing
+    pub fn permissions(&self, claims: &Claims) -> BTreeSet<Permission> {
+        claims.roles.iter()
+            .filter_map(|role| self.roles.get(role))
+            .flatten()
+            .chain(&claims.scopes)
+            .copied()
+            .collect()
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+        validation
+    }
+
+    // a token without kid is tried with every key of its algorithm
+    pub fn verify(&self, token: &str) -> Result<Claims, AuthError> {
+        let header = decode_header(token).map_err(|_| AuthError::Invalid("malformed token"))?;
+        let candidates = self.keys.iter()
+            .filter(|k| k.algorithm == header.alg)
+            .filter(|k| header.kid.is_none() || k.kid.is_none() || k.kid == header.kid);
+
+        let mut result = Err(AuthError::Invalid("no key for the token algorithm or kid"));
+        for candidate in candidates {
+            result = decode::<Claims>(token, &candidate.key, &self.validation(header.alg))
+                .map(|data| data.claims)
+                .map_err(|err| AuthError::Invalid(match err.kind() {
+                    ErrorKind::InvalidSignature => "invalid signature",
+                    ErrorKind::ExpiredSignature => "token expired",
+                    ErrorKind::ImmatureSignature => "token not valid yet",
+                    ErrorKind::InvalidAudience => "invalid audience",
+                    ErrorKind::InvalidIssuer => "invalid issuer",
+                    ErrorKind::MissingRequiredClaim(_) => "missing required claim",
+                    _ => "malformed token",
+                }));
+            if result != Err(AuthError::Invalid("invalid signature")) {
+                break;
+            }
+        }
+        result
+    }
+
+    // a bearer token takes precedence over an X-API-Key header
+    async fn authenticate(&self, req: &ServiceRequest) -> Result<Claims, Error> {
+        let bearer = req.headers().get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        if let Some(token) = bearer {
+            return Ok(self.verify(token.trim())?);
+        }
+
+        let api_key = req.headers().get(api_keys::HEADER).ok_or(AuthError::Missing)?;
+        let token = api_key.to_str().map_err(|_| AuthError::InvalidKey("malformed API key"))?;
+        let store = req.app_data::<Data<Box<dyn ApiKeyDAO>>>().ok_or(AuthError::InvalidKey("API keys are not enabled"))?;
+        api_keys::authenticate(store.as_ref().as_ref(), token).await
+    }
+}
+
+// Middleware verifying the bearer token or API key of every request before the handlers run.
+// Requests pass unchecked when no JwtAuth is registered.
+pub struct Authentication;
+
+impl<S, B> Transform<S, ServiceRequest> for Authentication
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = AuthenticationMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<AuthenticationMiddleware<S>, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(AuthenticationMiddleware { service: Rc::new(service) }))
+    }
+}
+
+// API keys are looked up in their store, the inner service is only called after that
+pub struct AuthenticationMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for AuthenticationMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        async move {
+            let public = PUBLIC_PATHS.contains(&req.path());
+            if let Some(auth) = req.app_data::<Data<JwtAuth>>().cloned().filter(|_| !public) {
+                match auth.authenticate(&req).await {
+                    Ok(claims) => {
+                        log::debug!("Credentials of {} accepted", claims.sub);
+                        req.extensions_mut().insert(claims);
+                    },
+                    Err(err) => return Ok(req.error_response(err).map_into_right_body()),
+                }
+            }
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        }.boxed_local()
+    }
+}
+
+// The permission a route requires, see Authorized
+pub trait Scope {
+    const PERMISSION: Permission;
+}
+
+pub enum UsersRead {}
+pub enum UsersWrite {}
+pub enum UsersAdmin {}
+
+impl Scope for UsersRead { const PERMISSION: Permission = Permission::Read; }
+impl Scope for UsersWrite { const PERMISSION: Permission = Permission::Write; }
+impl Scope for UsersAdmin { const PERMISSION: Permission = Permission::Admin; }
+
+// Handler argument declaring the permission of its route, 403 when the token does not grant it.
+// Everything is permitted when no security section is configured.
+pub struct Authorized<S: Scope> {
+    // None when security is off
+    claims: Option<Claims>,
+    permissions: BTreeSet<Permission>,
+    scope: PhantomData<S>,
+}
+
+impl<S: Scope> Authorized<S> {
+    fn permitted(&self, permission: Permission) -> bool {
+        self.claims.is_none() || self.permissions.contains(&permission)
+    }
+
+    // for checks depending on the request, like the admin views of list
+    pub fn require(&self, permission: Permission, what: &str) -> Result<(), AuthError> {
+        if self.permitted(permission) {
+            Ok(())
+        } else {
+            Err(AuthError::Forbidden(format!("{} requires permission {}", what, permission)))
+        }
+    }
+
+    // the token is of the user with this id, never when security is off
+    pub fn is_self(&self, id: u64) -> bool {
+        self.claims.as_ref().and_then(Claims::user_id) == Some(id)
+    }
+
+    // non-admins may only modify their own record
+    pub fn require_self_or_admin(&self, id: u64) -> Result<(), AuthError> {
+        if self.is_self(id) {
+            Ok(())
+        } else {
+            self.require(Permission::Admin, "Changing another user")
+        }
+    }
+}
+
+impl<S: Scope> FromRequest for Authorized<S> {
+    type Error = AuthError;
+    type Future = Ready<Result<Authorized<S>, AuthError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        let auth = match req.app_data::<Data<JwtAuth>>() {
+            Some(auth) => auth,
+            None => return ready(Ok(Authorized { claims: None, permissions: BTreeSet::new(), scope: PhantomData })),
+        };
+        let claims = match req.extensions().get::<Claims>() {
+            Some(claims) => claims.clone(),
+            None => return ready(Err(AuthError::Missing)),
+        };
+
+        let authorized = Authorized { permissions: auth.permissions(&claims), claims: Some(claims), scope: PhantomData };
+        ready(authorized.require(S::PERMISSION, "This route").map(|_| authorized))
+    }
+}
+
+impl FromRequest for Claims {
+    type Error = AuthError;
+    type Future = Ready<Result<Claims, AuthError>>;
+
+    fn from_request(req: &HttpRequest, _payload: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Claims>().cloned().ok_or(AuthError::Missing))
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "Bearer token or API key required"),
+            AuthError::Invalid(reason) => write!(f, "Invalid bearer token: {}", reason),
+            AuthError::InvalidKey(reason) => write!(f, "Invalid API key: {}", reason),
+            AuthError::Forbidden(detail) => write!(f, "{}", detail),
+        }
+    }
+}
+
+impl Display for Permission {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Permission::Read => write!(f, "users:read"),
+            Permission::Write => write!(f, "users:write"),
+            Permission::Admin => write!(f, "users:admin"),
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        [Permission::Read, Permission::Write, Permission::Admin].into_iter()
+            .find(|permission| permission.to_string() == s)
+            .ok_or_else(|| format!("unknown permission {}", s))
+    }
+}
+
+impl ResponseError for AuthError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AuthError::Forbidden(_) => StatusCode::FORBIDDEN,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    // RFC 6750, a request without token gets no error code, neither do API keys which are no bearer tokens
+    fn error_response(&self) -> HttpResponse {
+        let challenge = match self {
+            AuthError::Missing | AuthError::InvalidKey(_) => format!("Bearer realm=\"{}\"", REALM),
+            AuthError::Invalid(reason) => format!("Bearer realm=\"{}\", error=\"invalid_token\", error_description=\"{}\"", REALM, reason),
+            AuthError::Forbidden(_) => {
+                return Problem::new("forbidden", "Forbidden", self.status_code(), self.to_string()).error_response();
+            },
+        };
+        let mut response = Problem::new("unauthorized", "Unauthorized", self.status_code(), self.to_string()).error_response();
+        if let Ok(value) = challenge.parse() {
+            response.headers_mut().insert(header::WWW_AUTHENTICATE, value);
+        }
+        response
+    }
+}
+
+// HS256 setup of the handler tests, tokens carry the user id as subject
+#[cfg(test)]
+pub const TEST_SECRET: &str = "test-secret";
+
+#[cfg(test)]
+pub fn test_auth() -> Data<JwtAuth> {
+    let security = Security { hs256_secret: Some(TEST_SECRET.to_string()), ..Security::default() };
+    Data::new(JwtAuth::from_config(&security).unwrap())
+}
+
+#[cfg(test)]
+pub fn test_token(user_id: u64, roles: &[&str]) -> String {
+    let exp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs() + 60;
+    let claims = serde_json::json!({ "sub": user_id.to_string(), "roles": roles, "exp": exp });
+    jsonwebtoken::encode(&jsonwebtoken::Header::default(), &claims, &jsonwebtoken::EncodingKey::from_secret(TEST_SECRET.as_bytes())).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    use actix_web::{http::StatusCode, test, web::{self, Data}, App};
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use serde_json::{json, Value};
+
+    use crate::api_keys::{self, ApiKeyDAO, ApiKeyInMemoryDAO, NewApiKey};
+    use crate::configs::Security;
+    use crate::problem::Problem;
+    use super::{AuthError, Authentication, Claims, JwtAuth, Permission, TEST_SECRET as SECRET};
+
+    fn now() -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+    }
+
+    fn security() -> Security {
+        Security {
+            hs256_secret: Some(SECRET.to_string()),
+            jwks_file: Some("tests/jwt/jwks.json".to_string()),
+            issuer: Some("https://auth.example.com".to_string()),
+            audience: Some("users-api".to_string()),
+            ..Security::default()
+        }
+    }
+
+    fn claims(sub: &str) -> Value {
+        json!({ "sub": sub, "iss": "https://auth.example.com", "aud": "users-api", "exp": now() + 60 })
+    }
+
+    fn hs256(claims: &Value) -> String {
+        encode(&Header::default(), claims, &EncodingKey::from_secret(SECRET.as_bytes())).unwrap()
+    }
+
+    fn rs256(claims: &Value, kid: Option<&str>) -> String {
+        let key = EncodingKey::from_rsa_pem(include_bytes!("../tests/jwt/rsa_private.pem")).unwrap();
+        let header = Header { kid: kid.map(str::to_string), ..Header::new(Algorithm::RS256) };
+        encode(&header, claims, &key).unwrap()
+    }
+
+    fn with(mut claims: Value, name: &str, value: Value) -> Value {
+        claims[name] = value;
+        claims
+    }
+
+    #[actix_web::test]
+    async fn test_verify_hs256_and_rs256() {
+        let auth = JwtAuth::from_config(&security()).unwrap();
+        let alice = Claims { sub: "alice".to_string(), roles: vec![], scopes: vec![] };
+
+        assert_eq!(Ok(alice.clone()), auth.verify(&hs256(&claims("alice"))));
+        assert_eq!(Ok(alice.clone()), auth.verify(&rs256(&claims("alice"), Some("test-rsa"))));
+        assert_eq!(Ok(alice), auth.verify(&rs256(&claims("alice"), None)));
+    }
+
+    #[actix_web::test]
+    async fn test_reject_invalid_tokens() {
+        let auth = JwtAuth::from_config(&security()).unwrap();
+        let invalid = |reason| Err(AuthError::Invalid(reason));
+
+        assert_eq!(invalid("token expired"), auth.verify(&hs256(&with(claims("bob"), "exp", json!(now() - 120)))));
+        assert_eq!(invalid("token not valid yet"), auth.verify(&hs256(&with(claims("bob"), "nbf", json!(now() + 120)))));
+        assert_eq!(invalid("invalid audience"), auth.verify(&hs256(&with(claims("bob"), "aud", json!("other-api")))));
+        assert_eq!(invalid("invalid issuer"), auth.verify(&hs256(&with(claims("bob"), "iss", json!("https://evil.example.com")))));
+        assert_eq!(invalid("no key for the token algorithm or kid"), auth.verify(&rs256(&claims("bob"), Some("rotated"))));
+
+        let foreign = encode(&Header::default(), &claims("bob"), &EncodingKey::from_secret(b"guessed")).unwrap();
+        assert_eq!(invalid("invalid signature"), auth.verify(&foreign));
+        assert_eq!(invalid("malformed token"), auth.verify("not.a.jwt"));
+    }
+
+    #[actix_web::test]
+    async fn test_permissions_of_roles() {
+        let auth = JwtAuth::from_config(&security()).unwrap();
+        let claims = Claims { sub: "7".to_string(), roles: vec!["reader".to_string(), "writer".to_string(), "unknown".to_string()], scopes: vec![] };
+
+        assert_eq!(Some(7), claims.user_id());
+        assert_eq!(vec![Permission::Read, Permission::Write], auth.permissions(&claims).into_iter().collect::<Vec<_>>());
+    }
+
+    #[actix_web::test]
+    async fn test_config_without_jwt_key() {
+        // API keys only, every bearer token is rejected
+        let auth = JwtAuth::from_config(&Security::default()).unwrap();
+        assert_eq!(Err(AuthError::Invalid("no key for the token algorithm or kid")), auth.verify(&hs256(&claims("alice"))));
+
+        let missing = Security { rs256_public_key: Some("tests/jwt/missing.pem".to_string()), ..Security::default() };
+        assert!(JwtAuth::from_config(&missing).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_middleware() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(JwtAuth::from_config(&security()).unwrap()))
+                .wrap(Authentication)
+                .route("/whoami", web::get().to(|claims: Claims| async move { claims.sub })),
+        ).await;
+
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .insert_header(("Authorization", format!("Bearer {}", hs256(&claims("alice")))))
+            .to_request();
+        assert_eq!("alice", test::call_and_read_body(&app, req).await);
+
+        let req = test::TestRequest::get().uri("/whoami").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert_eq!("Bearer realm=\"users\"", resp.headers().get("www-authenticate").unwrap());
+
+        let expired = hs256(&with(claims("alice"), "exp", json!(now() - 120)));
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .insert_header(("Authorization", format!("Bearer {}", expired)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert_eq!("Bearer realm=\"users\", error=\"invalid_token\", error_description=\"token expired\"",
+            resp.headers().get("www-authenticate").unwrap());
+        assert_eq!("application/problem+json", resp.headers().get("content-type").unwrap());
+    }
+
+    #[actix_web::test]
+    async fn test_api_key() {
+        let store: Box<dyn ApiKeyDAO> = Box::new(ApiKeyInMemoryDAO::default());
+        let new_key = NewApiKey { owner: "billing".to_string(), scopes: vec![Permission::Read], expires_at: None };
+        let created = api_keys::issue(store.as_ref(), &new_key).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(JwtAuth::from_config(&security()).unwrap()))
+                .app_data(Data::new(store))
+                .wrap(Authentication)
+                .route("/whoami", web::get().to(|claims: Claims| async move { format!("{} {:?}", claims.sub, claims.scopes) })),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/whoami").insert_header(("X-API-Key", created.token.clone())).to_request();
+        assert_eq!(format!("api-key:{} [Read]", created.key.id), test::call_and_read_body(&app, req).await);
+
+        // the bearer token wins when both are sent
+        let req = test::TestRequest::get()
+            .uri("/whoami")
+            .insert_header(("X-API-Key", created.token.clone()))
+            .insert_header(("Authorization", format!("Bearer {}", hs256(&claims("alice")))))
+            .to_request();
+        assert_eq!("alice []", test::call_and_read_body(&app, req).await);
+
+        let req = test::TestRequest::get().uri("/whoami").insert_header(("X-API-Key", format!("{}.guessed", created.key.id))).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert_eq!("Bearer realm=\"users\"", resp.headers().get("www-authenticate").unwrap());
+        let problem: Problem = test::read_body_json(resp).await;
+        assert_eq!("Invalid API key: unknown API key", problem.detail);
+    }
+
+    #[actix_web::test]
+    async fn test_api_key_only() {
+        let store: Box<dyn ApiKeyDAO> = Box::new(ApiKeyInMemoryDAO::default());
+        let new_key = NewApiKey { owner: "billing".to_string(), scopes: vec![Permission::Read], expires_at: None };
+        let created = api_keys::issue(store.as_ref(), &new_key).await.unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(JwtAuth::from_config(&Security::default()).unwrap()))
+                .app_data(Data::new(store))
+                .wrap(Authentication)
+                .route("/whoami", web::get().to(|claims: Claims| async move { claims.sub })),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/whoami").insert_header(("X-API-Key", created.token.clone())).to_request();
+        assert_eq!(format!("api-key:{}", created.key.id), test::call_and_read_body(&app, req).await);
+
+        let req = test::TestRequest::get().uri("/whoami").to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+        let req = test::TestRequest::get().uri("/whoami").insert_header(("Authorization", format!("Bearer {}", hs256(&claims("alice"))))).to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn test_open_without_security() {
+        let app = test::init_service(
+            App::new()
+                .wrap(Authentication)
+                .route("/users", web::get().to(|| async { "open" })),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users").to_request();
+        assert_eq!("open", test::call_and_read_body(&app, req).await);
+    }
+}
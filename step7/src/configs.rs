@@ -1,22 +1,54 @@
+use std::collections::BTreeMap;
+
 use config::{Config, ConfigError};
 use serde::{Serialize, Deserialize};
 
+use crate::auth::Permission;
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Configuration {
     pub server: ServerConfig,
     pub store: Option<Store>,
+    #[serde(default)]
+    pub purge: Option<Purge>,
+    #[serde(default)]
+    pub security: Option<Security>,
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+    #[serde(default)]
+    pub metrics: Option<Metrics>,
+    #[serde(default)]
+    pub logging: Option<Logging>,
+    #[serde(default)]
+    pub tracing: Option<Tracing>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct ServerConfig {
     pub host: String,
     pub port: u16,
+    #[serde(default)]
+    pub legacy_update_route: bool,
+    // signs keyset pagination cursors, a random key is used when missing
+    #[serde(default)]
+    pub cursor_secret: Option<String>,
+    // shutdown, the delay included, is done this long after the signal. Requests and background tasks
+    // still running then are dropped
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout_secs: u64,
+    // readiness fails this long before the server stops accepting, load balancers take the instance out meanwhile.
+    // Counts against the shutdown timeout
+    #[serde(default)]
+    pub shutdown_delay_secs: u64,
 }
 
+fn default_shutdown_timeout() -> u64 { 30 }
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 pub struct Store {
     pub inmemory: Option<InMemory>,
     pub db: Option<Db>,
+    pub sqlite: Option<Sqlite>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -31,6 +63,250 @@ pub struct Db {
     pub db_name: String,
     pub user: String,
     pub password: String,
+    #[serde(default)]
+    pub migrate: MigrateMode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Sqlite {
+    pub path: String,
+}
+
+// Hard-deletes soft deleted users once they are older than the retention window
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Purge {
+    pub retention_days: u32,
+    #[serde(default = "default_purge_interval")]
+    pub interval_secs: u64,
+}
+
+fn default_purge_interval() -> u64 { 3600 }
+
+// JWT bearer and API key authentication of every route, the API is open without this section.
+// Without any JWT key only API keys are accepted.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Security {
+    #[serde(default)]
+    pub hs256_secret: Option<String>,
+    // PEM file
+    #[serde(default)]
+    pub rs256_public_key: Option<String>,
+    // local JWKS file, tokens pick their key by kid
+    #[serde(default)]
+    pub jwks_file: Option<String>,
+    #[serde(default)]
+    pub issuer: Option<String>,
+    #[serde(default)]
+    pub audience: Option<String>,
+    // clock skew tolerated on exp and nbf
+    #[serde(default)]
+    pub leeway_secs: u64,
+    // permissions granted by the roles claim of a token
+    #[serde(default = "default_roles")]
+    pub roles: BTreeMap<String, Vec<Permission>>,
+    // password login of users, needs hs256_secret to sign the access tokens
+    #[serde(default)]
+    pub login: Option<Login>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Login {
+    #[serde(default = "default_access_ttl")]
+    pub access_ttl_secs: u64,
+    #[serde(default = "default_refresh_ttl")]
+    pub refresh_ttl_secs: u64,
+    // roles claim of the access tokens
+    #[serde(default = "default_login_roles")]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub argon2: PasswordHashing,
+}
+
+fn default_access_ttl() -> u64 { 900 }
+fn default_refresh_ttl() -> u64 { 14 * 24 * 3600 }
+fn default_login_roles() -> Vec<String> { vec!["writer".to_string()] }
+
+impl Default for Login {
+    fn default() -> Self {
+        Login {
+            access_ttl_secs: default_access_ttl(),
+            refresh_ttl_secs: default_refresh_ttl(),
+            roles: default_login_roles(),
+            argon2: PasswordHashing::default(),
+        }
+    }
+}
+
+// argon2id cost of password hashes, the OWASP minimum by default.
+// Stored hashes keep their own cost, raising it applies to passwords set afterwards.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct PasswordHashing {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for PasswordHashing {
+    fn default() -> Self {
+        PasswordHashing { memory_kib: 19 * 1024, iterations: 2, parallelism: 1 }
+    }
+}
+
+fn default_roles() -> BTreeMap<String, Vec<Permission>> {
+    BTreeMap::from([
+        ("reader".to_string(), vec![Permission::Read]),
+        ("writer".to_string(), vec![Permission::Read, Permission::Write]),
+        ("admin".to_string(), vec![Permission::Read, Permission::Write, Permission::Admin]),
+    ])
+}
+
+impl Default for Security {
+    fn default() -> Self {
+        Security {
+            hs256_secret: None,
+            rs256_public_key: None,
+            jwks_file: None,
+            issuer: None,
+            audience: None,
+            leeway_secs: 0,
+            roles: default_roles(),
+            login: None,
+        }
+    }
+}
+
+// Token buckets per client and route, requests of routes without a limit pass unless there is a default
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
+pub struct RateLimit {
+    #[serde(default)]
+    pub default: Option<Limit>,
+    #[serde(default)]
+    pub routes: Vec<RouteLimit>,
+    // every request of a client address, checked before authentication so failed attempts count too
+    #[serde(default)]
+    pub per_address: Option<Limit>,
+}
+
+// capacity is the burst, refill_per_sec the sustained rate
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq)]
+pub struct Limit {
+    pub capacity: u32,
+    pub refill_per_sec: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct RouteLimit {
+    // any method when missing
+    #[serde(default)]
+    pub method: Option<String>,
+    // route pattern as registered, e.g. /users/{id}
+    pub path: String,
+    pub capacity: u32,
+    pub refill_per_sec: f64,
+}
+
+impl RouteLimit {
+    pub fn limit(&self) -> Limit {
+        Limit { capacity: self.capacity, refill_per_sec: self.refill_per_sec }
+    }
+}
+
+// Prometheus text format at GET /metrics, scrapers need no token
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Metrics {
+    // upper bounds in seconds of the request and DAO latency histograms
+    #[serde(default = "default_latency_buckets")]
+    pub latency_buckets: Vec<f64>,
+    // the user count is read from the store this often, scrapes only render the last one
+    #[serde(default = "default_users_interval_secs")]
+    pub users_interval_secs: u64,
+}
+
+fn default_users_interval_secs() -> u64 {
+    60
+}
+
+fn default_latency_buckets() -> Vec<f64> {
+    vec![0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0]
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics { latency_buckets: default_latency_buckets(), users_interval_secs: default_users_interval_secs() }
+    }
+}
+
+// Human readable lines by default, one JSON object per event for log shippers
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Logging {
+    #[serde(default)]
+    pub format: LogFormat,
+    // tracing filter directives, e.g. info,rbatis=warn
+    #[serde(default = "default_log_level")]
+    pub level: String,
+}
+
+fn default_log_level() -> String {
+    "info".to_string()
+}
+
+impl Default for Logging {
+    fn default() -> Self {
+        Logging { format: LogFormat::default(), level: default_log_level() }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    #[default]
+    Pretty,
+    Json,
+}
+
+// OpenTelemetry spans of requests, handlers, DAO calls and SQL statements, spans below the logging level are dropped
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct Tracing {
+    #[serde(default)]
+    pub exporter: TraceExporter,
+    // OTLP over HTTP, the traces path of a local collector by default
+    #[serde(default = "default_otlp_endpoint")]
+    pub endpoint: String,
+    #[serde(default = "default_service_name")]
+    pub service_name: String,
+    // share of the traces started here that are kept, traces of callers follow their sampling decision
+    #[serde(default = "default_sample_ratio")]
+    pub sample_ratio: f64,
+}
+
+fn default_otlp_endpoint() -> String {
+    "http://localhost:4318/v1/traces".to_string()
+}
+
+fn default_service_name() -> String {
+    "users".to_string()
+}
+
+fn default_sample_ratio() -> f64 {
+    1.0
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TraceExporter {
+    #[default]
+    Otlp,
+    // one JSON line per span, for tests and debugging
+    Stdout,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MigrateMode {
+    #[default]
+    Auto,
+    Check,
+    Off,
 }
 
 impl Configuration {
@@ -47,7 +323,10 @@ impl Configuration {
 
 #[cfg(test)]
 mod tests {
-    use crate::configs::{ServerConfig, Store, InMemory, Db};
+    use std::collections::BTreeMap;
+
+    use crate::auth::Permission;
+    use crate::configs::{ServerConfig, Store, InMemory, Db, MigrateMode, Sqlite, Purge, Security, Login, PasswordHashing, RateLimit, Limit, RouteLimit, Metrics, Logging, LogFormat, Tracing, TraceExporter};
     use super::Configuration;
 
     #[test]
@@ -57,14 +336,25 @@ mod tests {
             Configuration {
                 server: ServerConfig {
                     host: "0.0.0.0".to_string(), 
-                    port: 9090
+                    port: 9090,
+                    legacy_update_route: false,
+                    cursor_secret: None,
+                    shutdown_timeout_secs: 30,
+                    shutdown_delay_secs: 0
                 },
                 store: Some(Store {
                     inmemory: Some(InMemory{
                         users: 10,
                     }),
                     db: None,
+                    sqlite: None,
                 }),
+                purge: None,
+                security: None,
+                rate_limit: None,
+                metrics: None,
+                logging: None,
+                tracing: None
             },  
             cfg
         ); 
@@ -89,9 +379,19 @@ mod tests {
             Configuration {
                 server: ServerConfig {
                     host: "0.0.0.0".to_string(), 
-                    port: 8080
+                    port: 8080,
+                    legacy_update_route: false,
+                    cursor_secret: None,
+                    shutdown_timeout_secs: 30,
+                    shutdown_delay_secs: 0
                 },
-                store: None
+                store: None,
+                purge: None,
+                security: None,
+                rate_limit: None,
+                metrics: None,
+                logging: None,
+                tracing: None
             },  
             cfg
         );
@@ -110,9 +410,19 @@ mod tests {
             Configuration {
                 server: ServerConfig {
                     host: "123".to_string(), 
-                    port: 8080
+                    port: 8080,
+                    legacy_update_route: false,
+                    cursor_secret: None,
+                    shutdown_timeout_secs: 30,
+                    shutdown_delay_secs: 0
                 },
-                store: None
+                store: None,
+                purge: None,
+                security: None,
+                rate_limit: None,
+                metrics: None,
+                logging: None,
+                tracing: None
             },  
             cfg
         );
@@ -125,9 +435,19 @@ mod tests {
             Configuration {
                 server: ServerConfig {
                     host: "0.0.0.0".to_string(), 
-                    port: 9999
+                    port: 9999,
+                    legacy_update_route: false,
+                    cursor_secret: None,
+                    shutdown_timeout_secs: 30,
+                    shutdown_delay_secs: 0
                 },
-                store: None
+                store: None,
+                purge: None,
+                security: None,
+                rate_limit: None,
+                metrics: None,
+                logging: None,
+                tracing: None
             },  
             cfg
         ); 
@@ -140,9 +460,19 @@ mod tests {
             Configuration {
                 server: ServerConfig {
                     host: "345".to_string(), 
-                    port: 1234
+                    port: 1234,
+                    legacy_update_route: true,
+                    cursor_secret: Some("changeme".to_string()),
+                    shutdown_timeout_secs: 10,
+                    shutdown_delay_secs: 5
                 },
-                store: None
+                store: None,
+                purge: None,
+                security: None,
+                rate_limit: None,
+                metrics: None,
+                logging: None,
+                tracing: None
             },  
             cfg
         );
@@ -162,7 +492,11 @@ mod tests {
             Configuration {
                 server: ServerConfig {
                     host: "0.0.0.0".to_string(),
-                    port: 8080
+                    port: 8080,
+                    legacy_update_route: false,
+                    cursor_secret: None,
+                    shutdown_timeout_secs: 30,
+                    shutdown_delay_secs: 0
                 },
                 store: Some(Store {
                     inmemory: None, 
@@ -172,11 +506,137 @@ mod tests {
                         db_name: "users".to_string(), 
                         user: "rw_user".to_string(), 
                         password: "123qweasd".to_string(), 
+                        migrate: MigrateMode::Auto,
                     }),
-                })
+                    sqlite: None,
+                }),
+                purge: None,
+                security: None,
+                rate_limit: None,
+                metrics: None,
+                logging: None,
+                tracing: None
             },
             cfg
         );
     }
-}
 
+    #[test]
+    fn test_load_db_migrate_mode() {
+        let cfg = Configuration::load_from_file("tests/db_migrate_check.yaml").unwrap();
+        let db = cfg.store.unwrap().db.unwrap();
+        assert_eq!(MigrateMode::Check, db.migrate);
+    }
+
+    #[test]
+    fn test_load_db_invalid_migrate_mode() {
+        let result = Configuration::load_from_file("tests/db_migrate_invalid.yaml").unwrap_err();
+        assert_eq!("enum MigrateMode does not have variant constructor sometimes", result.to_string());
+    }
+
+    #[test]
+    fn test_load_sqlite_config() {
+        let cfg = Configuration::load_from_file("tests/sqlite_full.yaml").unwrap();
+        assert_eq!(
+            Some(Store {
+                inmemory: None,
+                db: None,
+                sqlite: Some(Sqlite {
+                    path: "users.db".to_string(),
+                }),
+            }),
+            cfg.store
+        );
+    }
+
+    #[test]
+    fn test_load_purge_config() {
+        let cfg = Configuration::load_from_file("tests/purge.yaml").unwrap();
+        assert_eq!(Some(Purge { retention_days: 30, interval_secs: 3600 }), cfg.purge);
+    }
+
+    #[test]
+    fn test_load_rate_limit_config() {
+        let cfg = Configuration::load_from_file("tests/rate_limit.yaml").unwrap();
+        assert_eq!(
+            Some(RateLimit {
+                default: Some(Limit { capacity: 100, refill_per_sec: 10.0 }),
+                routes: vec![
+                    RouteLimit { method: Some("POST".to_string()), path: "/users".to_string(), capacity: 10, refill_per_sec: 0.5 },
+                    RouteLimit { method: None, path: "/users/search".to_string(), capacity: 20, refill_per_sec: 2.0 },
+                ],
+                per_address: Some(Limit { capacity: 300, refill_per_sec: 20.0 }),
+            }),
+            cfg.rate_limit
+        );
+    }
+
+    #[test]
+    fn test_load_metrics_config() {
+        let cfg = Configuration::load_from_file("tests/metrics.yaml").unwrap();
+        assert_eq!(Some(Metrics { latency_buckets: vec![0.01, 0.1, 1.0], users_interval_secs: 15 }), cfg.metrics);
+
+        let cfg = Configuration::load_from_file("tests/rate_limit.yaml").unwrap();
+        assert_eq!(None, cfg.metrics);
+    }
+
+    #[test]
+    fn test_load_logging_config() {
+        let cfg = Configuration::load_from_file("tests/logging.yaml").unwrap();
+        assert_eq!(Some(Logging { format: LogFormat::Json, level: "info,rbatis=debug".to_string() }), cfg.logging);
+        assert_eq!(Logging { format: LogFormat::Pretty, level: "info".to_string() }, Logging::default());
+    }
+
+    #[test]
+    fn test_load_tracing_config() {
+        let cfg = Configuration::load_from_file("tests/tracing.yaml").unwrap();
+        assert_eq!(
+            Some(Tracing {
+                exporter: TraceExporter::Stdout,
+                endpoint: "http://localhost:4318/v1/traces".to_string(),
+                service_name: "users-test".to_string(),
+                sample_ratio: 0.25,
+            }),
+            cfg.tracing
+        );
+    }
+
+    #[test]
+    fn test_load_security_config() {
+        let cfg = Configuration::load_from_file("tests/security.yaml").unwrap();
+        assert_eq!(
+            Some(Security {
+                hs256_secret: Some("changeme".to_string()),
+                rs256_public_key: None,
+                jwks_file: Some("tests/jwt/jwks.json".to_string()),
+                issuer: Some("https://auth.example.com".to_string()),
+                audience: Some("users-api".to_string()),
+                leeway_secs: 30,
+                roles: BTreeMap::from([
+                    ("support".to_string(), vec![Permission::Read]),
+                    ("operator".to_string(), vec![Permission::Read, Permission::Write, Permission::Admin]),
+                ]),
+                login: Some(Login {
+                    access_ttl_secs: 300,
+                    refresh_ttl_secs: 86400,
+                    roles: vec!["support".to_string()],
+                    argon2: PasswordHashing { memory_kib: 65536, iterations: 3, parallelism: 2 },
+                }),
+            }),
+            cfg.security
+        );
+    }
+
+    #[test]
+    fn test_load_security_default_roles() {
+        let cfg = Configuration::load_from_file("tests/security_minimal.yaml").unwrap();
+        assert_eq!(Some(Security { hs256_secret: Some("changeme".to_string()), ..Security::default() }), cfg.security);
+        assert_eq!(vec![Permission::Read, Permission::Write], cfg.security.unwrap().roles["writer"]);
+    }
+
+    #[test]
+    fn test_load_security_unknown_permission() {
+        let result = Configuration::load_from_file("tests/security_unknown_permission.yaml").unwrap_err();
+        assert_eq!("enum Permission does not have variant constructor users:delete", result.to_string());
+    }
+}
@@ -0,0 +1,604 @@
+use std::fmt::Debug;
+use std::future::Future;
+use std::time::UNIX_EPOCH;
+
+use actix_web::{http::StatusCode, ResponseError};
+
+use chrono::{DateTime, Duration, Utc};
+use futures::TryStreamExt;
+
+use crate::model::{BatchError, BatchOp, Cursor, Page, SearchQuery, Sort, SortField, SortOrder, User, UserDAOError, UserFields, UserStatus, UsersQuery};
+use crate::services::{UserDAO, UserInMemoryDAO};
+
+// Every UserDAO implementation must pass the same checks with the same status codes and messages.
+// The factory gets the number of users to seed with ids 1..N, see UserInMemoryDAO::seed_fields.
+macro_rules! user_dao_conformance {
+    ($factory:expr $(, #[$attr:meta])*) => {
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_list_empty() { crate::conformance::list_empty($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_list_ordered_by_id() { crate::conformance::list_ordered_by_id($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_list_pages() { crate::conformance::list_pages($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_list_sorted_and_filtered() { crate::conformance::list_sorted_and_filtered($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_list_invalid_query() { crate::conformance::list_invalid_query($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_list_stream() { crate::conformance::list_stream($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_list_after_cursor() { crate::conformance::list_after_cursor($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_search() { crate::conformance::search($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_find_by_id() { crate::conformance::find_by_id($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_find_by_name() { crate::conformance::find_by_name($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_create() { crate::conformance::create($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_create_invalid() { crate::conformance::create_invalid($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_update() { crate::conformance::update($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_update_invalid() { crate::conformance::update_invalid($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_delete() { crate::conformance::delete($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_list_include_deleted() { crate::conformance::list_include_deleted($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_restore() { crate::conformance::restore($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_purge_deleted() { crate::conformance::purge_deleted($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_update_version_mismatch() { crate::conformance::update_version_mismatch($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_delete_version_mismatch() { crate::conformance::delete_version_mismatch($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_profile_fields() { crate::conformance::profile_fields($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_email_unique_ignoring_case() { crate::conformance::email_unique_ignoring_case($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_batch_partial_success() { crate::conformance::batch_partial_success($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_batch_atomic_commits() { crate::conformance::batch_atomic_commits($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_batch_atomic_rolls_back() { crate::conformance::batch_atomic_rolls_back($factory).await }
+
+        #[actix_web::test] $(#[$attr])*
+        async fn conformance_health() { crate::conformance::health($factory).await }
+    };
+}
+
+pub(crate) use user_dao_conformance;
+
+pub fn user(id: u64, name: &str) -> User {
+    versioned(id, 1, name)
+}
+
+pub fn versioned(id: u64, version: u64, name: &str) -> User {
+    User { id, version, created_at: epoch(), updated_at: epoch(), deleted_at: None, fields: fields(name) }
+}
+
+pub fn deleted(user: User, version: u64) -> User {
+    User { version, deleted_at: Some(epoch()), fields: UserFields { status: UserStatus::Deleted, ..user.fields }, ..user }
+}
+
+// a user as created by the factories, see UserInMemoryDAO::seed_fields
+pub fn seeded(id: u64) -> User {
+    User { fields: UserInMemoryDAO::seed_fields(id as u16), ..user(id, "") }
+}
+
+pub fn fields(name: &str) -> UserFields {
+    UserFields { name: name.to_string(), ..UserFields::default() }
+}
+
+fn epoch() -> DateTime<Utc> {
+    DateTime::from(UNIX_EPOCH)
+}
+
+// timestamps come from the storage clock, expected users carry the epoch instead
+pub fn timeless(user: User) -> User {
+    User { created_at: epoch(), updated_at: epoch(), deleted_at: user.deleted_at.map(|_| epoch()), ..user }
+}
+
+fn timeless_ok(result: Result<User, UserDAOError>) -> Result<User, UserDAOError> {
+    result.map(timeless)
+}
+
+fn timeless_page(page: Page<User>) -> Page<User> {
+    Page { items: page.items.into_iter().map(timeless).collect(), ..page }
+}
+
+fn assert_error<T: Debug>(result: Result<T, UserDAOError>, status: StatusCode, message: &str) {
+    let err = result.unwrap_err();
+    assert_eq!((status, message.to_string()), (err.status_code(), err.to_string()));
+}
+
+fn names(page: &Page<User>) -> Vec<String> {
+    page.items.iter().map(|u| u.fields.name.clone()).collect()
+}
+
+pub async fn list_empty<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(0).await;
+    assert_eq!(Ok(Page { items: vec![], total: 0 }), dao.list(&UsersQuery::default()).await);
+}
+
+pub async fn list_ordered_by_id<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(3).await;
+    dao.update(1, &fields("User9"), None).await.unwrap();
+
+    let page = dao.list(&UsersQuery::default()).await.unwrap();
+    assert_eq!(Page { items: vec![versioned(1, 2, "User9"), seeded(2), seeded(3)], total: 3 }, timeless_page(page));
+}
+
+pub async fn list_pages<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(5).await;
+
+    let page = dao.list(&UsersQuery { page: 2, page_size: 2, ..UsersQuery::default() }).await.unwrap();
+    assert_eq!(5, page.total);
+    assert_eq!(vec!["User3", "User4"], names(&page));
+
+    let last = dao.list(&UsersQuery { page: 3, page_size: 2, ..UsersQuery::default() }).await.unwrap();
+    assert_eq!(vec!["User5"], names(&last));
+
+    let beyond = dao.list(&UsersQuery { page: 4, page_size: 2, ..UsersQuery::default() }).await.unwrap();
+    assert_eq!(Page { items: vec![], total: 5 }, beyond);
+}
+
+pub async fn list_sorted_and_filtered<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(12).await;
+
+    let query = UsersQuery {
+        sort: Sort { field: SortField::Name, order: SortOrder::Desc },
+        name_prefix: Some("User1".to_string()),
+        ..UsersQuery::default()
+    };
+    let page = dao.list(&query).await.unwrap();
+    assert_eq!(4, page.total);
+    assert_eq!(vec!["User12", "User11", "User10", "User1"], names(&page));
+
+    let by_id_desc = UsersQuery { sort: Sort { field: SortField::Id, order: SortOrder::Desc }, page_size: 2, ..UsersQuery::default() };
+    assert_eq!(vec!["User12", "User11"], names(&dao.list(&by_id_desc).await.unwrap()));
+
+    let by_substring = UsersQuery { name_contains: Some("2".to_string()), ..UsersQuery::default() };
+    assert_eq!(vec!["User2", "User12"], names(&dao.list(&by_substring).await.unwrap()));
+
+    let case_sensitive = UsersQuery { name_contains: Some("user".to_string()), ..UsersQuery::default() };
+    assert_eq!(0, dao.list(&case_sensitive).await.unwrap().total);
+
+    let wildcard = UsersQuery { name_contains: Some("%".to_string()), ..UsersQuery::default() };
+    assert_eq!(0, dao.list(&wildcard).await.unwrap().total);
+}
+
+pub async fn list_invalid_query<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(1).await;
+
+    let zero_page = dao.list(&UsersQuery { page: 0, ..UsersQuery::default() }).await;
+    assert_error(zero_page, StatusCode::BAD_REQUEST, "Validation failed for: field: 'page' errors: 'range'");
+
+    let huge_page = dao.list(&UsersQuery { page_size: 101, ..UsersQuery::default() }).await;
+    assert_error(huge_page, StatusCode::BAD_REQUEST, "Validation failed for: field: 'page_size' errors: 'range'");
+}
+
+pub async fn list_stream<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    // more users than the DAOs read in one chunk
+    let dao = factory(503).await;
+    dao.delete_by_id(2, None).await.unwrap();
+
+    let streamed = |query: UsersQuery| dao.list_stream(&query).map_ok(|u| u.fields.name).try_collect::<Vec<String>>();
+
+    let mut names: Vec<String> = (1..=503).filter(|&i| i != 2).map(|i| format!("User{}", i)).collect();
+    let paged = UsersQuery { page: 3, page_size: 1, ..UsersQuery::default() };
+    assert_eq!(Ok(names.clone()), streamed(paged).await);
+
+    names.sort();
+    let by_name = UsersQuery { sort: Sort { field: SortField::Name, order: SortOrder::Asc }, ..UsersQuery::default() };
+    assert_eq!(Ok(names.clone()), streamed(by_name).await);
+
+    names.reverse();
+    let by_name_desc = UsersQuery { sort: Sort { field: SortField::Name, order: SortOrder::Desc }, ..UsersQuery::default() };
+    assert_eq!(Ok(names), streamed(by_name_desc).await);
+
+    let filtered = UsersQuery {
+        sort: Sort { field: SortField::Id, order: SortOrder::Desc },
+        name_prefix: Some("User2".to_string()),
+        name_contains: Some("0".to_string()),
+        include_deleted: true,
+        ..UsersQuery::default()
+    };
+    let expected: Vec<String> = (1..=503).rev().map(|i| format!("User{}", i))
+        .filter(|name| name.starts_with("User2") && name[4..].contains('0'))
+        .collect();
+    assert_eq!(Ok(expected), streamed(filtered).await);
+
+    let invalid = UsersQuery { page: 0, ..UsersQuery::default() };
+    assert_error(streamed(invalid).await, StatusCode::BAD_REQUEST, "Validation failed for: field: 'page' errors: 'range'");
+}
+
+pub async fn list_after_cursor<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(5).await;
+    let ids = |users: Vec<User>| users.iter().map(|u| u.id).collect::<Vec<u64>>();
+
+    let by_id = UsersQuery::default();
+    let first = dao.list_after(&by_id, None, 2).await.unwrap();
+    assert_eq!(vec![1, 2], ids(first.clone()));
+
+    // a user created after the first page was read is not repeated on the next one
+    dao.create(&fields("Newcomer")).await.unwrap();
+    let after = Cursor::after(first.last().unwrap(), by_id.sort);
+    assert_eq!(vec![3, 4, 5, 6], ids(dao.list_after(&by_id, Some(&after), 10).await.unwrap()));
+
+    // the cursor user itself may be gone by now
+    dao.delete_by_id(4, None).await.unwrap();
+    let by_name_desc = UsersQuery {
+        sort: Sort { field: SortField::Name, order: SortOrder::Desc },
+        name_prefix: Some("User".to_string()),
+        ..UsersQuery::default()
+    };
+    let after = Cursor::after(&seeded(4), by_name_desc.sort);
+    assert_eq!(vec![3, 2], ids(dao.list_after(&by_name_desc, Some(&after), 2).await.unwrap()));
+    assert_eq!(Ok(vec![]), dao.list_after(&by_name_desc, Some(&Cursor::after(&seeded(1), by_name_desc.sort)), 2).await);
+
+    let invalid = UsersQuery { limit: Some(101), ..UsersQuery::default() };
+    assert_error(dao.list_after(&invalid, None, 2).await, StatusCode::BAD_REQUEST, "Validation failed for: field: 'limit' errors: 'range'");
+}
+
+pub async fn search<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(3).await;
+    dao.create(&UserFields { display_name: Some("Alex Smith".to_string()), ..fields("Alexander") }).await.unwrap();
+    dao.create(&fields("Alexandra")).await.unwrap();
+    dao.create(&fields("Alexis")).await.unwrap();
+    dao.delete_by_id(6, None).await.unwrap();
+
+    let dao = dao.as_ref();
+    let hits = |query: SearchQuery| async move {
+        let page = dao.search(&query).await.unwrap();
+        let hits: Vec<(String, f64)> = page.items.into_iter().map(|hit| (hit.user.fields.name, hit.score)).collect();
+        (hits, page.total)
+    };
+    let query = |q: &str| SearchQuery { q: q.to_string(), page: 1, page_size: 10 };
+
+    // the display name matches better than the name, deleted users are not found
+    assert_eq!((vec![
+        ("Alexander".to_string(), (5.0f32 / 11.0) as f64),
+        ("Alexandra".to_string(), (4.0f32 / 11.0) as f64),
+    ], 2), hits(query("alex")).await);
+
+    // misspelled
+    assert_eq!("Alexandra", hits(query("Alexandru")).await.0[0].0);
+
+    // a substring below the similarity threshold
+    assert_eq!((vec![("Alexander".to_string(), (2.0f32 / 13.0) as f64), ("Alexandra".to_string(), (2.0f32 / 13.0) as f64)], 2),
+        hits(query("XAND")).await);
+
+    // "User 1" shares more trigrams than "User1"
+    assert_eq!((vec![("User2".to_string(), (5.0f32 / 7.0) as f64)], 3), hits(SearchQuery { page: 2, page_size: 1, ..query("user") }).await);
+    assert_eq!((vec![], 0), hits(query("Zebra")).await);
+    assert_error(dao.search(&query("")).await, StatusCode::BAD_REQUEST, "Validation failed for: field: 'q' errors: 'length'");
+}
+
+pub async fn find_by_id<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(2).await;
+    assert_eq!(Ok(seeded(2)), timeless_ok(dao.find_by_id(2).await));
+    assert_error(dao.find_by_id(5).await, StatusCode::NOT_FOUND, "User not found");
+}
+
+pub async fn find_by_name<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(2).await;
+    assert_eq!(Ok(seeded(2)), timeless_ok(dao.find_by_name("User2").await));
+    assert_error(dao.find_by_name("user2").await, StatusCode::NOT_FOUND, "User not found");
+
+    dao.delete_by_id(2, None).await.unwrap();
+    assert_error(dao.find_by_name("User2").await, StatusCode::NOT_FOUND, "User not found");
+}
+
+pub async fn create<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(1).await;
+
+    let created = dao.create(&fields("User")).await.unwrap();
+    assert_eq!(user(2, "User"), timeless(created.clone()));
+    assert_eq!(created.created_at, created.updated_at);
+    assert_eq!(Ok(created), dao.find_by_id(2).await);
+
+    let duplicate = dao.create(&fields("User1")).await;
+    assert_error(duplicate, StatusCode::CONFLICT, "User exists");
+    assert_eq!(2, dao.list(&UsersQuery::default()).await.unwrap().total);
+}
+
+pub async fn create_invalid<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(0).await;
+
+    let empty = dao.create(&fields("")).await;
+    assert_error(empty, StatusCode::BAD_REQUEST, "Validation failed for: field: 'name' errors: 'length, regex'");
+
+    let lowercase = dao.create(&fields("user")).await;
+    assert_error(lowercase, StatusCode::BAD_REQUEST, "Validation failed for: field: 'name' errors: 'regex'");
+
+    let bad_email = dao.create(&UserFields { email: Some("user.example.com".to_string()), ..fields("User") }).await;
+    assert_error(bad_email, StatusCode::BAD_REQUEST, "Validation failed for: field: 'email' errors: 'email'");
+
+    let blank_display_name = dao.create(&UserFields { display_name: Some("".to_string()), ..fields("User") }).await;
+    assert_error(blank_display_name, StatusCode::BAD_REQUEST, "Validation failed for: field: 'display_name' errors: 'length'");
+
+    // deleted is only set by delete
+    let deleted_status = dao.create(&UserFields { status: UserStatus::Deleted, ..fields("User") }).await;
+    assert_error(deleted_status, StatusCode::BAD_REQUEST, "Validation failed for: field: 'status' errors: 'settable_status'");
+
+    assert_eq!(0, dao.list(&UsersQuery::default()).await.unwrap().total);
+}
+
+pub async fn update<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(2).await;
+
+    assert_eq!(Ok(versioned(2, 2, "Update_user")), timeless_ok(dao.update(2, &fields("Update_user"), None).await));
+    assert_eq!(Ok(versioned(2, 2, "Update_user")), timeless_ok(dao.find_by_id(2).await));
+    assert_eq!(Ok(seeded(1)), timeless_ok(dao.find_by_id(1).await));
+
+    assert_eq!(Ok(versioned(2, 3, "Update_user")), timeless_ok(dao.update(2, &fields("Update_user"), Some(&[2])).await));
+
+    let missing = dao.update(3, &fields("Test"), None).await;
+    assert_error(missing, StatusCode::NOT_FOUND, "User not found");
+    assert_error(dao.find_by_id(3).await, StatusCode::NOT_FOUND, "User not found");
+
+    let duplicate = dao.update(1, &fields("Update_user"), None).await;
+    assert_error(duplicate, StatusCode::CONFLICT, "User exists");
+    assert_eq!(Ok(seeded(1)), timeless_ok(dao.find_by_id(1).await));
+}
+
+pub async fn update_invalid<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(1).await;
+
+    let result = dao.update(1, &fields(""), None).await;
+    assert_error(result, StatusCode::BAD_REQUEST, "Validation failed for: field: 'name' errors: 'length, regex'");
+    assert_eq!(Ok(seeded(1)), timeless_ok(dao.find_by_id(1).await));
+}
+
+pub async fn delete<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(2).await;
+
+    let removed = dao.delete_by_id(1, None).await.unwrap();
+    assert_eq!(deleted(seeded(1), 2), timeless(removed.clone()));
+    assert_eq!(removed.deleted_at, Some(removed.updated_at));
+    assert_error(dao.find_by_id(1).await, StatusCode::NOT_FOUND, "User not found");
+    assert_eq!(Page { items: vec![seeded(2)], total: 1 }, timeless_page(dao.list(&UsersQuery::default()).await.unwrap()));
+
+    assert_error(dao.delete_by_id(1, None).await, StatusCode::NOT_FOUND, "User not found");
+    assert_error(dao.delete_by_id(1, Some(&[2])).await, StatusCode::NOT_FOUND, "User not found");
+    assert_error(dao.update(1, &fields("Revived"), None).await, StatusCode::NOT_FOUND, "User not found");
+    assert_error(dao.delete_by_id(3, None).await, StatusCode::NOT_FOUND, "User not found");
+}
+
+pub async fn list_include_deleted<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(3).await;
+    dao.delete_by_id(2, None).await.unwrap();
+
+    let admin = UsersQuery { include_deleted: true, ..UsersQuery::default() };
+    let page = timeless_page(dao.list(&admin).await.unwrap());
+    assert_eq!(Page { items: vec![seeded(1), deleted(seeded(2), 2), seeded(3)], total: 3 }, page);
+
+    let filtered = UsersQuery { name_prefix: Some("User2".to_string()), ..UsersQuery::default() };
+    assert_eq!(0, dao.list(&filtered).await.unwrap().total);
+
+    // deleted users keep their name and email until purged
+    assert_error(dao.create(&seeded(2).fields).await, StatusCode::CONFLICT, "User exists");
+}
+
+pub async fn restore<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(2).await;
+    dao.delete_by_id(1, None).await.unwrap();
+
+    assert_error(dao.restore(1, Some(&[1])).await, StatusCode::PRECONDITION_FAILED, "User was modified concurrently");
+    assert_eq!(Ok(User { version: 3, ..seeded(1) }), timeless_ok(dao.restore(1, Some(&[2, 7])).await));
+    assert_eq!(Ok(User { version: 3, ..seeded(1) }), timeless_ok(dao.find_by_id(1).await));
+
+    // restoring a user that is not deleted changes nothing
+    assert_eq!(Ok(seeded(2)), timeless_ok(dao.restore(2, None).await));
+    assert_error(dao.restore(2, Some(&[5])).await, StatusCode::PRECONDITION_FAILED, "User was modified concurrently");
+    assert_error(dao.restore(3, None).await, StatusCode::NOT_FOUND, "User not found");
+}
+
+pub async fn purge_deleted<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(3).await;
+    let removed = dao.delete_by_id(1, None).await.unwrap();
+    dao.delete_by_id(2, None).await.unwrap();
+
+    let deleted_at = removed.deleted_at.unwrap();
+    assert_eq!(Ok(0), dao.purge_deleted(deleted_at).await);
+    assert_eq!(Ok(2), dao.purge_deleted(Utc::now() + Duration::seconds(1)).await);
+
+    assert_error(dao.restore(1, None).await, StatusCode::NOT_FOUND, "User not found");
+    let admin = UsersQuery { include_deleted: true, ..UsersQuery::default() };
+    assert_eq!(Page { items: vec![seeded(3)], total: 1 }, timeless_page(dao.list(&admin).await.unwrap()));
+
+    // purged names can be taken again
+    assert!(dao.create(&seeded(1).fields).await.is_ok());
+}
+
+pub async fn update_version_mismatch<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(1).await;
+    dao.update(1, &fields("First_writer"), Some(&[1])).await.unwrap();
+
+    let stale = dao.update(1, &fields("Second_writer"), Some(&[1])).await;
+    assert_error(stale, StatusCode::PRECONDITION_FAILED, "User was modified concurrently");
+    assert_eq!(Ok(versioned(1, 2, "First_writer")), timeless_ok(dao.find_by_id(1).await));
+
+    let missing = dao.update(2, &fields("Second_writer"), Some(&[1])).await;
+    assert_error(missing, StatusCode::NOT_FOUND, "User not found");
+
+    // one of several expected versions being current is enough
+    let any = dao.update(1, &fields("Third_writer"), Some(&[1, 2])).await;
+    assert_eq!(Ok(versioned(1, 3, "Third_writer")), timeless_ok(any));
+    let none = dao.update(1, &fields("Fourth_writer"), Some(&[1, 2])).await;
+    assert_error(none, StatusCode::PRECONDITION_FAILED, "User was modified concurrently");
+    assert_error(dao.update(1, &fields("Fourth_writer"), Some(&[])).await, StatusCode::PRECONDITION_FAILED, "User was modified concurrently");
+}
+
+pub async fn delete_version_mismatch<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(1).await;
+    dao.update(1, &fields("Updated"), None).await.unwrap();
+
+    assert_error(dao.delete_by_id(1, Some(&[1])).await, StatusCode::PRECONDITION_FAILED, "User was modified concurrently");
+    assert_eq!(Ok(versioned(1, 2, "Updated")), timeless_ok(dao.find_by_id(1).await));
+
+    assert_eq!(Ok(deleted(versioned(1, 2, "Updated"), 3)), timeless_ok(dao.delete_by_id(1, Some(&[1, 2])).await));
+}
+
+pub async fn profile_fields<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(0).await;
+
+    let profile = UserFields {
+        name: "Suspended".to_string(),
+        email: Some("Someone@Example.com".to_string()),
+        display_name: Some("Someone Else".to_string()),
+        status: UserStatus::Suspended,
+    };
+    let created = dao.create(&profile).await.unwrap();
+    assert_eq!(profile, created.fields);
+    assert_eq!(Ok(created.clone()), dao.find_by_id(created.id).await);
+
+    let cleared = dao.update(created.id, &fields("Active"), None).await.unwrap();
+    assert_eq!(fields("Active"), cleared.fields);
+    assert_eq!(created.created_at, cleared.created_at);
+    assert!(cleared.updated_at >= created.updated_at);
+}
+
+pub async fn email_unique_ignoring_case<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(2).await;
+
+    let same_email = UserFields { email: Some("USER1@example.COM".to_string()), ..fields("Another") };
+    assert_error(dao.create(&same_email).await, StatusCode::CONFLICT, "User exists");
+    assert_error(dao.update(2, &same_email, None).await, StatusCode::CONFLICT, "User exists");
+    assert_eq!(Ok(seeded(2)), timeless_ok(dao.find_by_id(2).await));
+
+    let no_email = dao.create(&fields("Another")).await;
+    assert!(no_email.is_ok());
+    assert!(dao.create(&fields("Third")).await.is_ok());
+}
+
+pub async fn batch_partial_success<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(2).await;
+
+    let ops = vec![
+        BatchOp::Create { fields: fields("Newcomer") },
+        BatchOp::Create { fields: seeded(1).fields },
+        BatchOp::Update { id: 2, version: Some(1), fields: fields("Renamed") },
+        BatchOp::Delete { id: 9, version: None },
+        BatchOp::Delete { id: 1, version: Some(1) },
+    ];
+    let results: Vec<Result<User, UserDAOError>> = dao.batch(&ops).await.unwrap().into_iter().map(timeless_ok).collect();
+
+    assert_eq!(vec![
+        Ok(user(3, "Newcomer")),
+        Err(UserDAOError::AlreadyExists),
+        Ok(versioned(2, 2, "Renamed")),
+        Err(UserDAOError::NotFound),
+        Ok(deleted(seeded(1), 2)),
+    ], results);
+    assert_eq!(
+        Page { items: vec![versioned(2, 2, "Renamed"), user(3, "Newcomer")], total: 2 },
+        timeless_page(dao.list(&UsersQuery::default()).await.unwrap())
+    );
+}
+
+pub async fn batch_atomic_commits<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(1).await;
+
+    // later operations see the users written by earlier ones
+    let ops = vec![
+        BatchOp::Create { fields: fields("Newcomer") },
+        BatchOp::Update { id: 1, version: None, fields: fields("Renamed") },
+        BatchOp::Delete { id: 2, version: Some(1) },
+    ];
+    let applied = dao.batch_atomic(&ops).await.unwrap();
+
+    assert_eq!(
+        vec![user(2, "Newcomer"), versioned(1, 2, "Renamed"), deleted(user(2, "Newcomer"), 2)],
+        applied.into_iter().map(timeless).collect::<Vec<User>>()
+    );
+    assert_eq!(
+        Page { items: vec![versioned(1, 2, "Renamed")], total: 1 },
+        timeless_page(dao.list(&UsersQuery::default()).await.unwrap())
+    );
+}
+
+pub async fn batch_atomic_rolls_back<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(2).await;
+    let unchanged = Page { items: vec![seeded(1), seeded(2)], total: 2 };
+
+    let ops = vec![
+        BatchOp::Create { fields: fields("Newcomer") },
+        BatchOp::Update { id: 1, version: None, fields: fields("Renamed") },
+        BatchOp::Create { fields: seeded(2).fields },
+        BatchOp::Delete { id: 2, version: None },
+    ];
+    let failed = dao.batch_atomic(&ops).await;
+    assert_eq!(Err(BatchError { index: Some(2), error: UserDAOError::AlreadyExists }), failed);
+    assert_eq!(unchanged, timeless_page(dao.list(&UsersQuery::default()).await.unwrap()));
+
+    let ops = vec![
+        BatchOp::Delete { id: 1, version: None },
+        BatchOp::Update { id: 2, version: Some(7), fields: fields("Renamed") },
+    ];
+    let failed = dao.batch_atomic(&ops).await;
+    assert_eq!(Err(BatchError { index: Some(1), error: UserDAOError::VersionMismatch }), failed);
+    assert_eq!(unchanged, timeless_page(dao.list(&UsersQuery::default()).await.unwrap()));
+
+    let failed = dao.batch_atomic(&[BatchOp::Create { fields: fields("") }]).await.unwrap_err();
+    assert_eq!((Some(0), StatusCode::BAD_REQUEST), (failed.index, failed.error.status_code()));
+}
+
+pub async fn health<F, Fut>(factory: F)
+where F: Fn(u16) -> Fut, Fut: Future<Output = Box<dyn UserDAO>> {
+    let dao = factory(0).await;
+    assert_eq!(Ok(()), dao.health().await);
+}
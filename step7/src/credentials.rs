@@ -0,0 +1,687 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use actix_web::{http::StatusCode, web};
+use argon2::{
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Algorithm, Argon2, Params, Version,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use jsonwebtoken::{encode, EncodingKey, Header};
+use rand::RngCore;
+use rbatis::{rbatis::Rbatis, DateTimeUtc};
+use rbson::Bson;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use validator::{Validate, ValidationError};
+
+use crate::configs::{Login, PasswordHashing, Security};
+use crate::model::{UserDAOError, UserStatus};
+use crate::problem::Problem;
+use crate::services::UserDAO;
+
+#[derive(Clone, Debug, Deserialize, Validate)]
+pub struct PasswordChange {
+    #[validate(length(min = 12, max = 128), custom = "password_policy")]
+    pub password: String,
+    // required when users change their own password, admins resetting it for others leave it out
+    #[serde(default)]
+    pub current_password: Option<String>,
+}
+
+// letters alone are easy to guess, control characters can not be typed reliably
+fn password_policy(password: &str) -> Result<(), ValidationError> {
+    let letters = password.chars().any(char::is_alphabetic);
+    let others = password.chars().any(|c| !c.is_alphabetic() && !c.is_control());
+    if password.chars().any(char::is_control) || !letters || !others {
+        let mut err = ValidationError::new("password_policy");
+        err.message = Some("must mix letters with digits or symbols and contain no control characters".into());
+        return Err(err);
+    }
+    Ok(())
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct LoginRequest {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct RefreshRequest {
+    pub refresh_token: String,
+}
+
+// RFC 6749 token response
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TokenResponse {
+    pub access_token: String,
+    pub token_type: String,
+    pub expires_in: u64,
+    pub refresh_token: String,
+    pub refresh_expires_in: u64,
+}
+
+// Refresh tokens are sent as `{id}.{secret}` and used once. Each refresh revokes the token and
+// issues the next one of its family, a revoked token coming back revokes the whole family.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RefreshToken {
+    pub id: String,
+    pub family: String,
+    pub user_id: u64,
+    // sha256 of the secret, the secrets are random and need no slow hash
+    pub hash: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub revoked_at: Option<DateTime<Utc>>,
+}
+
+#[async_trait]
+pub trait CredentialDAO: Sync + Send {
+    async fn set_password(&self, user_id: u64, hash: &str) -> Result<(), UserDAOError>;
+    async fn password_hash(&self, user_id: u64) -> Result<Option<String>, UserDAOError>;
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> Result<(), UserDAOError>;
+    async fn find_refresh_token(&self, id: &str) -> Result<Option<RefreshToken>, UserDAOError>;
+    // false when the token was revoked already, concurrent refreshes with one token get a single winner
+    async fn revoke_refresh_token(&self, id: &str, at: DateTime<Utc>) -> Result<bool, UserDAOError>;
+    // the following return how many tokens were revoked
+    async fn revoke_family(&self, family: &str, at: DateTime<Utc>) -> Result<u64, UserDAOError>;
+    async fn revoke_user(&self, user_id: u64, at: DateTime<Utc>) -> Result<u64, UserDAOError>;
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut buf = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut buf);
+    buf.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn sha256_hex(secret: &str) -> String {
+    Sha256::digest(secret.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn blocking_error(err: impl std::fmt::Display) -> UserDAOError {
+    UserDAOError::Storage(err.to_string().into())
+}
+
+fn invalid_credentials() -> Problem {
+    Problem::new("invalid-credentials", "Invalid credentials", StatusCode::UNAUTHORIZED, "Wrong name or password".to_string())
+}
+
+fn wrong_current_password() -> Problem {
+    Problem::new("wrong-password", "Wrong password", StatusCode::FORBIDDEN, "The current password is missing or wrong".to_string())
+}
+
+fn invalid_refresh_token(reason: &str) -> Problem {
+    Problem::new("invalid-refresh-token", "Invalid refresh token", StatusCode::UNAUTHORIZED, format!("Invalid refresh token: {}", reason))
+}
+
+fn hash_password(params: &Params, password: &str) -> String {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).expect("valid salt");
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone())
+        .hash_password(password.as_bytes(), &salt)
+        .expect("argon2 hash")
+        .to_string()
+}
+
+// parameters are read from the hash, passwords keep working when the configured cost changes
+fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .map(|parsed| Argon2::default().verify_password(password.as_bytes(), &parsed).is_ok())
+        .unwrap_or(false)
+}
+
+// Signs the HS256 access tokens of password logins, they are verified by JwtAuth like any other token
+pub struct PasswordLogin {
+    key: EncodingKey,
+    issuer: Option<String>,
+    audience: Option<String>,
+    cfg: Login,
+    params: Params,
+    // verified against when the name is unknown, so it takes as long as a wrong password
+    dummy_hash: String,
+}
+
+impl PasswordLogin {
+    // None without security.login
+    pub fn from_config(security: &Security) -> std::io::Result<Option<PasswordLogin>> {
+        let cfg = match &security.login {
+            Some(cfg) => cfg.clone(),
+            None => return Ok(None),
+        };
+        let secret = security.hs256_secret.as_ref()
+            .ok_or_else(|| std::io::Error::other("security.login needs hs256_secret to sign access tokens"))?;
+        let PasswordHashing { memory_kib, iterations, parallelism } = cfg.argon2;
+        let params = Params::new(memory_kib, iterations, parallelism, None)
+            .map_err(|err| std::io::Error::other(format!("Invalid security.login.argon2: {}", err)))?;
+
+        let mut login = PasswordLogin {
+            key: EncodingKey::from_secret(secret.as_bytes()),
+            issuer: security.issuer.clone(),
+            audience: security.audience.clone(),
+            cfg,
+            params,
+            dummy_hash: String::new(),
+        };
+        login.dummy_hash = hash_password(&login.params, &random_hex(16));
+        Ok(Some(login))
+    }
+
+    fn access_token(&self, user_id: u64, now: DateTime<Utc>) -> Result<String, UserDAOError> {
+        let mut claims = serde_json::json!({
+            "sub": user_id.to_string(),
+            "roles": self.cfg.roles,
+            "iat": now.timestamp(),
+            "exp": now.timestamp() + self.cfg.access_ttl_secs as i64,
+        });
+        if let Some(issuer) = &self.issuer {
+            claims["iss"] = issuer.as_str().into();
+        }
+        if let Some(audience) = &self.audience {
+            claims["aud"] = audience.as_str().into();
+        }
+        encode(&Header::default(), &claims, &self.key).map_err(blocking_error)
+    }
+
+    async fn issue(&self, store: &dyn CredentialDAO, user_id: u64, family: String) -> Result<TokenResponse, UserDAOError> {
+        let now = Utc::now();
+        let secret = random_hex(32);
+        let refresh = RefreshToken {
+            id: random_hex(8),
+            family,
+            user_id,
+            hash: sha256_hex(&secret),
+            created_at: now,
+            expires_at: now + Duration::seconds(self.cfg.refresh_ttl_secs as i64),
+            revoked_at: None,
+        };
+        store.insert_refresh_token(&refresh).await?;
+
+        Ok(TokenResponse {
+            access_token: self.access_token(user_id, now)?,
+            token_type: "Bearer".to_string(),
+            expires_in: self.cfg.access_ttl_secs,
+            refresh_token: format!("{}.{}", refresh.id, secret),
+            refresh_expires_in: self.cfg.refresh_ttl_secs,
+        })
+    }
+}
+
+// Unknown names, users without password and wrong passwords get the same answer
+pub async fn login(auth: &PasswordLogin, users: &dyn UserDAO, store: &dyn CredentialDAO, request: &LoginRequest) -> Result<TokenResponse, actix_web::Error> {
+    let user = match users.find_by_name(&request.name).await {
+        Ok(user) => Some(user),
+        Err(UserDAOError::NotFound) => None,
+        Err(err) => return Err(err.into()),
+    };
+    let hash = match &user {
+        Some(user) => store.password_hash(user.id).await?,
+        None => None,
+    };
+
+    let known = hash.is_some();
+    let hash = hash.unwrap_or_else(|| auth.dummy_hash.clone());
+    let password = request.password.clone();
+    let verified = web::block(move || verify_password(&password, &hash)).await? && known;
+
+    let user = user.filter(|_| verified).ok_or_else(invalid_credentials)?;
+    if user.fields.status != UserStatus::Active {
+        return Err(Problem::new("inactive-user", "Inactive user", StatusCode::FORBIDDEN, format!("User {} is not active", user.id)).into());
+    }
+    Ok(auth.issue(store, user.id, random_hex(8)).await?)
+}
+
+async fn verified_refresh_token(store: &dyn CredentialDAO, token: &str) -> Result<RefreshToken, actix_web::Error> {
+    let (id, secret) = token.trim().split_once('.').ok_or_else(|| invalid_refresh_token("malformed token"))?;
+    store.find_refresh_token(id).await?
+        .filter(|refresh| refresh.hash == sha256_hex(secret))
+        .ok_or_else(|| invalid_refresh_token("unknown token").into())
+}
+
+pub async fn refresh(auth: &PasswordLogin, users: &dyn UserDAO, store: &dyn CredentialDAO, token: &str) -> Result<TokenResponse, actix_web::Error> {
+    let current = verified_refresh_token(store, token).await?;
+    let now = Utc::now();
+    if current.expires_at <= now {
+        return Err(invalid_refresh_token("token expired").into());
+    }
+    if current.revoked_at.is_some() || !store.revoke_refresh_token(&current.id, now).await? {
+        // the token was used before, by its owner or by whoever copied it
+        let revoked = store.revoke_family(&current.family, now).await?;
+        log::warn!("Refresh token {} of user {} reused, revoked {} tokens of its family", current.id, current.user_id, revoked);
+        return Err(invalid_refresh_token("token reused").into());
+    }
+
+    match users.find_by_id(current.user_id).await {
+        Ok(user) if user.fields.status == UserStatus::Active => Ok(auth.issue(store, user.id, current.family).await?),
+        Ok(_) | Err(UserDAOError::NotFound) => {
+            store.revoke_family(&current.family, now).await?;
+            Err(invalid_refresh_token("user inactive or deleted").into())
+        },
+        Err(err) => Err(err.into()),
+    }
+}
+
+// ends the session of the token, other logins of the user stay valid
+pub async fn logout(store: &dyn CredentialDAO, token: &str) -> Result<(), actix_web::Error> {
+    let current = verified_refresh_token(store, token).await?;
+    store.revoke_family(&current.family, Utc::now()).await?;
+    Ok(())
+}
+
+// Self-service change, a stolen access token alone can not take the account over.
+// Users without a password get their first one from an admin.
+pub async fn change_password(auth: &PasswordLogin, store: &dyn CredentialDAO, user_id: u64, change: &PasswordChange) -> Result<(), actix_web::Error> {
+    change.validate().map_err(UserDAOError::from)?;
+    let hash = store.password_hash(user_id).await?;
+
+    let known = hash.is_some();
+    let hash = hash.unwrap_or_else(|| auth.dummy_hash.clone());
+    let current = change.current_password.clone().unwrap_or_default();
+    let verified = web::block(move || verify_password(&current, &hash)).await? && known;
+    if !verified {
+        return Err(wrong_current_password().into());
+    }
+    Ok(set_password(auth, store, user_id, change).await?)
+}
+
+// sets the password without checking the current one, a new password ends every session of the user
+pub async fn set_password(auth: &PasswordLogin, store: &dyn CredentialDAO, user_id: u64, change: &PasswordChange) -> Result<(), UserDAOError> {
+    change.validate()?;
+    let (params, password) = (auth.params.clone(), change.password.clone());
+    let hash = web::block(move || hash_password(&params, &password)).await.map_err(blocking_error)?;
+
+    store.set_password(user_id, &hash).await?;
+    store.revoke_user(user_id, Utc::now()).await?;
+    Ok(())
+}
+
+#[derive(Default)]
+pub struct CredentialInMemoryDAO {
+    passwords: Mutex<HashMap<u64, String>>,
+    refresh_tokens: Mutex<BTreeMap<String, RefreshToken>>,
+}
+
+impl CredentialInMemoryDAO {
+    fn revoke_where(&self, at: DateTime<Utc>, matches: impl Fn(&RefreshToken) -> bool) -> u64 {
+        let mut tokens = self.refresh_tokens.lock().unwrap();
+        let mut revoked = 0;
+        for token in tokens.values_mut().filter(|token| token.revoked_at.is_none() && matches(token)) {
+            token.revoked_at = Some(at);
+            revoked += 1;
+        }
+        revoked
+    }
+}
+
+#[async_trait]
+impl CredentialDAO for CredentialInMemoryDAO {
+    async fn set_password(&self, user_id: u64, hash: &str) -> Result<(), UserDAOError> {
+        self.passwords.lock().unwrap().insert(user_id, hash.to_string());
+        Ok(())
+    }
+
+    async fn password_hash(&self, user_id: u64) -> Result<Option<String>, UserDAOError> {
+        Ok(self.passwords.lock().unwrap().get(&user_id).cloned())
+    }
+
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> Result<(), UserDAOError> {
+        self.refresh_tokens.lock().unwrap().insert(token.id.clone(), token.clone());
+        Ok(())
+    }
+
+    async fn find_refresh_token(&self, id: &str) -> Result<Option<RefreshToken>, UserDAOError> {
+        Ok(self.refresh_tokens.lock().unwrap().get(id).cloned())
+    }
+
+    async fn revoke_refresh_token(&self, id: &str, at: DateTime<Utc>) -> Result<bool, UserDAOError> {
+        Ok(self.revoke_where(at, |token| token.id == id) == 1)
+    }
+
+    async fn revoke_family(&self, family: &str, at: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        Ok(self.revoke_where(at, |token| token.family == family))
+    }
+
+    async fn revoke_user(&self, user_id: u64, at: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        Ok(self.revoke_where(at, |token| token.user_id == user_id))
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+struct RefreshTokenRow {
+    id: String,
+    family: String,
+    user_id: u64,
+    hash: String,
+    created_at: DateTimeUtc,
+    expires_at: DateTimeUtc,
+    revoked_at: Option<DateTimeUtc>,
+}
+
+impl From<RefreshTokenRow> for RefreshToken {
+    fn from(row: RefreshTokenRow) -> Self {
+        RefreshToken {
+            id: row.id,
+            family: row.family,
+            user_id: row.user_id,
+            hash: row.hash,
+            created_at: row.created_at.inner,
+            expires_at: row.expires_at.inner,
+            revoked_at: row.revoked_at.map(|at| at.inner),
+        }
+    }
+}
+
+fn timestamp(at: DateTime<Utc>) -> Bson {
+    Bson::String(at.to_rfc3339_opts(SecondsFormat::Micros, true))
+}
+
+fn insert_args(token: &RefreshToken) -> Vec<Bson> {
+    vec![
+        Bson::String(token.id.clone()),
+        Bson::String(token.family.clone()),
+        Bson::Int64(token.user_id as i64),
+        Bson::String(token.hash.clone()),
+        timestamp(token.created_at),
+        timestamp(token.expires_at),
+    ]
+}
+
+// Shares the pool of UserDbDAO, the tables are created by migration V8
+pub struct CredentialDbDAO {
+    rb: Arc<Rbatis>,
+}
+
+impl CredentialDbDAO {
+    pub fn new(rb: Arc<Rbatis>) -> CredentialDbDAO {
+        CredentialDbDAO { rb }
+    }
+
+    async fn revoke(&self, condition: &str, arg: Bson, at: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        let sql = format!("update users_schema.refresh_tokens set revoked_at = $2::timestamptz where {} = $1 and revoked_at is null", condition);
+        self.rb.exec(&sql, vec![arg, timestamp(at)])
+            .await
+            .map(|result| result.rows_affected)
+            .map_err(UserDAOError::from)
+    }
+}
+
+#[async_trait]
+impl CredentialDAO for CredentialDbDAO {
+    async fn set_password(&self, user_id: u64, hash: &str) -> Result<(), UserDAOError> {
+        let sql = "insert into users_schema.user_passwords (user_id, hash) values ($1, $2) \
+            on conflict (user_id) do update set hash = excluded.hash, updated_at = now()";
+        self.rb.exec(sql, vec![Bson::Int64(user_id as i64), Bson::String(hash.to_string())])
+            .await
+            .map(|_| ())
+            .map_err(UserDAOError::from)
+    }
+
+    async fn password_hash(&self, user_id: u64) -> Result<Option<String>, UserDAOError> {
+        self.rb.fetch::<Option<String>>("select hash from users_schema.user_passwords where user_id = $1", vec![Bson::Int64(user_id as i64)])
+            .await
+            .map_err(UserDAOError::from)
+    }
+
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> Result<(), UserDAOError> {
+        let sql = "insert into users_schema.refresh_tokens (id, family, user_id, hash, created_at, expires_at) \
+            values ($1, $2, $3, $4, $5::timestamptz, $6::timestamptz)";
+        self.rb.exec(sql, insert_args(token))
+            .await
+            .map(|_| ())
+            .map_err(UserDAOError::from)
+    }
+
+    async fn find_refresh_token(&self, id: &str) -> Result<Option<RefreshToken>, UserDAOError> {
+        self.rb.fetch::<Option<RefreshTokenRow>>("select * from users_schema.refresh_tokens where id = $1", vec![Bson::String(id.to_string())])
+            .await
+            .map(|row| row.map(RefreshToken::from))
+            .map_err(UserDAOError::from)
+    }
+
+    async fn revoke_refresh_token(&self, id: &str, at: DateTime<Utc>) -> Result<bool, UserDAOError> {
+        Ok(self.revoke("id", Bson::String(id.to_string()), at).await? == 1)
+    }
+
+    async fn revoke_family(&self, family: &str, at: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        self.revoke("family", Bson::String(family.to_string()), at).await
+    }
+
+    async fn revoke_user(&self, user_id: u64, at: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        self.revoke("user_id", Bson::Int64(user_id as i64), at).await
+    }
+}
+
+// Shares the pool of UserSqliteDAO
+pub struct CredentialSqliteDAO {
+    rb: Arc<Rbatis>,
+}
+
+impl CredentialSqliteDAO {
+    const CREATE_TABLES: &'static [&'static str] = &[
+        "CREATE TABLE IF NOT EXISTS user_passwords (\
+            user_id INTEGER PRIMARY KEY REFERENCES users (id) ON DELETE CASCADE, \
+            hash TEXT NOT NULL, \
+            updated_at TEXT NOT NULL\
+        )",
+        "CREATE TABLE IF NOT EXISTS refresh_tokens (\
+            id TEXT PRIMARY KEY, \
+            family TEXT NOT NULL, \
+            user_id INTEGER NOT NULL REFERENCES users (id) ON DELETE CASCADE, \
+            hash TEXT NOT NULL, \
+            created_at TEXT NOT NULL, \
+            expires_at TEXT NOT NULL, \
+            revoked_at TEXT\
+        )",
+        "CREATE INDEX IF NOT EXISTS refresh_tokens_family_idx ON refresh_tokens (family)",
+        "CREATE INDEX IF NOT EXISTS refresh_tokens_user_id_idx ON refresh_tokens (user_id)",
+    ];
+    const COLUMNS: &'static str = "id, family, user_id, hash, created_at, expires_at, revoked_at";
+
+    pub async fn new(rb: Arc<Rbatis>) -> CredentialSqliteDAO {
+        for create in CredentialSqliteDAO::CREATE_TABLES {
+            rb.exec(create, vec![]).await.expect("credential tables not created");
+        }
+        CredentialSqliteDAO { rb }
+    }
+
+    async fn revoke(&self, condition: &str, arg: Bson, at: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        let sql = format!("update refresh_tokens set revoked_at = ? where {} = ? and revoked_at is null", condition);
+        self.rb.exec(&sql, vec![timestamp(at), arg])
+            .await
+            .map(|result| result.rows_affected)
+            .map_err(UserDAOError::from)
+    }
+}
+
+#[async_trait]
+impl CredentialDAO for CredentialSqliteDAO {
+    async fn set_password(&self, user_id: u64, hash: &str) -> Result<(), UserDAOError> {
+        let sql = "insert into user_passwords (user_id, hash, updated_at) values (?, ?, ?) \
+            on conflict (user_id) do update set hash = excluded.hash, updated_at = excluded.updated_at";
+        self.rb.exec(sql, vec![Bson::Int64(user_id as i64), Bson::String(hash.to_string()), timestamp(Utc::now())])
+            .await
+            .map(|_| ())
+            .map_err(UserDAOError::from)
+    }
+
+    async fn password_hash(&self, user_id: u64) -> Result<Option<String>, UserDAOError> {
+        self.rb.fetch::<Option<String>>("select hash from user_passwords where user_id = ?", vec![Bson::Int64(user_id as i64)])
+            .await
+            .map_err(UserDAOError::from)
+    }
+
+    async fn insert_refresh_token(&self, token: &RefreshToken) -> Result<(), UserDAOError> {
+        let sql = "insert into refresh_tokens (id, family, user_id, hash, created_at, expires_at) values (?, ?, ?, ?, ?, ?)";
+        self.rb.exec(sql, insert_args(token))
+            .await
+            .map(|_| ())
+            .map_err(UserDAOError::from)
+    }
+
+    async fn find_refresh_token(&self, id: &str) -> Result<Option<RefreshToken>, UserDAOError> {
+        let sql = format!("select {} from refresh_tokens where id = ?", CredentialSqliteDAO::COLUMNS);
+        self.rb.fetch::<Option<RefreshTokenRow>>(&sql, vec![Bson::String(id.to_string())])
+            .await
+            .map(|row| row.map(RefreshToken::from))
+            .map_err(UserDAOError::from)
+    }
+
+    async fn revoke_refresh_token(&self, id: &str, at: DateTime<Utc>) -> Result<bool, UserDAOError> {
+        Ok(self.revoke("id", Bson::String(id.to_string()), at).await? == 1)
+    }
+
+    async fn revoke_family(&self, family: &str, at: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        self.revoke("family", Bson::String(family.to_string()), at).await
+    }
+
+    async fn revoke_user(&self, user_id: u64, at: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        self.revoke("user_id", Bson::Int64(user_id as i64), at).await
+    }
+}
+
+// cheap hashing keeps the tests fast, the cost does not change the flows
+#[cfg(test)]
+pub fn test_login() -> web::Data<PasswordLogin> {
+    let login = Login { argon2: PasswordHashing { memory_kib: 1024, iterations: 1, parallelism: 1 }, ..Login::default() };
+    let security = Security { hs256_secret: Some(crate::auth::TEST_SECRET.to_string()), login: Some(login), ..Security::default() };
+    web::Data::new(PasswordLogin::from_config(&security).unwrap().unwrap())
+}
+
+// Runs the login flows against a store whose users 1 and 2 exist
+#[cfg(test)]
+async fn credential_store_conformance(users: &dyn UserDAO, store: &dyn CredentialDAO) {
+    use crate::auth::test_auth;
+
+    let auth = test_login();
+    let jwt = test_auth();
+    let request = |name: &str, password: &str| LoginRequest { name: name.to_string(), password: password.to_string() };
+    let detail = |err: actix_web::Error| err.as_error::<Problem>().map(|problem| problem.detail.clone());
+    let wrong = Some("Wrong name or password".to_string());
+
+    assert_eq!(wrong, detail(login(&auth, users, store, &request("User1", "correct horse 1")).await.unwrap_err()));
+
+    let change = PasswordChange { password: "correct horse 1".to_string(), current_password: None };
+    let status = |err: actix_web::Error| err.as_response_error().status_code();
+    assert_eq!(StatusCode::FORBIDDEN, status(change_password(&auth, store, 1, &change).await.unwrap_err()));
+    set_password(&auth, store, 1, &change).await.unwrap();
+    assert!(store.password_hash(1).await.unwrap().unwrap().starts_with("$argon2id$v=19$m=1024,t=1,p=1$"));
+
+    assert_eq!(wrong, detail(login(&auth, users, store, &request("User1", "wrong horse 1")).await.unwrap_err()));
+    assert_eq!(wrong, detail(login(&auth, users, store, &request("Nobody", "correct horse 1")).await.unwrap_err()));
+
+    let tokens = login(&auth, users, store, &request("User1", "correct horse 1")).await.unwrap();
+    assert_eq!("Bearer", tokens.token_type);
+    let claims = jwt.verify(&tokens.access_token).unwrap();
+    assert_eq!((Some(1), vec!["writer".to_string()]), (claims.user_id(), claims.roles));
+
+    // rotation, the used token is revoked
+    let rotated = refresh(&auth, users, store, &tokens.refresh_token).await.unwrap();
+    assert_ne!(tokens.refresh_token, rotated.refresh_token);
+    let first_id = tokens.refresh_token.split_once('.').unwrap().0;
+    assert!(store.find_refresh_token(first_id).await.unwrap().unwrap().revoked_at.is_some());
+
+    // reuse of a rotated token revokes its family, the stolen successor included
+    assert_eq!(Some("Invalid refresh token: token reused".to_string()), detail(refresh(&auth, users, store, &tokens.refresh_token).await.unwrap_err()));
+    assert_eq!(Some("Invalid refresh token: token reused".to_string()), detail(refresh(&auth, users, store, &rotated.refresh_token).await.unwrap_err()));
+
+    let forged = format!("{}.{}", first_id, "0".repeat(64));
+    assert_eq!(Some("Invalid refresh token: unknown token".to_string()), detail(refresh(&auth, users, store, &forged).await.unwrap_err()));
+
+    // logout ends one session, a new password all of them
+    let first = login(&auth, users, store, &request("User1", "correct horse 1")).await.unwrap();
+    let second = login(&auth, users, store, &request("User1", "correct horse 1")).await.unwrap();
+    logout(store, &first.refresh_token).await.unwrap();
+    assert!(refresh(&auth, users, store, &first.refresh_token).await.is_err());
+    let second = refresh(&auth, users, store, &second.refresh_token).await.unwrap();
+
+    let wrong_current = PasswordChange { password: "battery staple 2".to_string(), current_password: Some("wrong horse 1".to_string()) };
+    assert_eq!(StatusCode::FORBIDDEN, status(change_password(&auth, store, 1, &wrong_current).await.unwrap_err()));
+    let change = PasswordChange { current_password: Some("correct horse 1".to_string()), ..wrong_current };
+    change_password(&auth, store, 1, &change).await.unwrap();
+    assert!(refresh(&auth, users, store, &second.refresh_token).await.is_err());
+    assert_eq!(wrong, detail(login(&auth, users, store, &request("User1", "correct horse 1")).await.unwrap_err()));
+    let last = login(&auth, users, store, &request("User1", "battery staple 2")).await.unwrap();
+
+    // deleted users lose their sessions
+    users.delete_by_id(1, None).await.unwrap();
+    assert_eq!(Some("Invalid refresh token: user inactive or deleted".to_string()), detail(refresh(&auth, users, store, &last.refresh_token).await.unwrap_err()));
+    assert_eq!(wrong, detail(login(&auth, users, store, &request("User1", "battery staple 2")).await.unwrap_err()));
+}
+
+#[cfg(test)]
+mod tests {
+    use validator::Validate;
+
+    use super::PasswordChange;
+
+    #[actix_web::test]
+    async fn test_password_policy() {
+        let valid = |password: &str| PasswordChange { password: password.to_string(), current_password: None }.validate().is_ok();
+
+        assert!(valid("correct horse 1"));
+        assert!(valid("Tr0ub4dor&3xyz"));
+        assert!(!valid("short1"));
+        assert!(!valid("onlylettersinhere"));
+        assert!(!valid("1234567890123"));
+        assert!(!valid("tab\tin password 1"));
+        assert!(!valid(&"a1".repeat(65)));
+    }
+}
+
+#[cfg(test)]
+mod in_memory_conformance {
+    use crate::configs::InMemory;
+    use crate::services::UserInMemoryDAO;
+    use super::{credential_store_conformance, CredentialInMemoryDAO};
+
+    #[actix_web::test]
+    async fn conformance_credential_store() {
+        let users = UserInMemoryDAO::new(Some(&InMemory { users: 2 }));
+        credential_store_conformance(&users, &CredentialInMemoryDAO::default()).await;
+    }
+}
+
+#[cfg(test)]
+mod sqlite_conformance {
+    use crate::configs::Sqlite;
+    use crate::services::{UserDAO, UserInMemoryDAO, UserSqliteDAO};
+    use super::{credential_store_conformance, CredentialSqliteDAO};
+
+    #[actix_web::test]
+    async fn conformance_credential_store() {
+        let path = std::env::temp_dir().join(format!("credentials-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let users = UserSqliteDAO::new(&Sqlite { path: path.to_string_lossy().to_string() }).await;
+        for i in 1 ..= 2 {
+            users.create(&UserInMemoryDAO::seed_fields(i)).await.unwrap();
+        }
+        credential_store_conformance(&users, &CredentialSqliteDAO::new(users.pool()).await).await;
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+#[cfg(test)]
+mod postgres_conformance {
+    use crate::configs::Configuration;
+    use crate::migrations;
+    use crate::services::{UserDAO, UserDbDAO, UserInMemoryDAO};
+    use super::{credential_store_conformance, CredentialDbDAO};
+
+    #[actix_web::test]
+    #[ignore = "needs a local Postgres"]
+    async fn conformance_credential_store() {
+        let cfg = Configuration::load_from_file("tests/db_full.yaml").unwrap();
+        let db = cfg.store.unwrap().db.unwrap();
+        migrations::migrate(&db).await.unwrap();
+
+        let users = UserDbDAO::new(&db).await;
+        users.pool().exec("TRUNCATE users_schema.users RESTART IDENTITY CASCADE", vec![]).await.unwrap();
+        for i in 1 ..= 2 {
+            users.create(&UserInMemoryDAO::seed_fields(i)).await.unwrap();
+        }
+        credential_store_conformance(&users, &CredentialDbDAO::new(users.pool())).await;
+    }
+}
@@ -0,0 +1,121 @@
+use actix_web::{http::StatusCode, HttpResponse, ResponseError};
+use hmac::{Hmac, Mac};
+use rand::RngCore;
+use sha2::Sha256;
+use std::fmt::Display;
+
+use crate::{configs::ServerConfig, model::{Cursor, Sort, SortField}, problem::Problem};
+
+type HmacSha256 = Hmac<Sha256>;
+
+// Cursors go to clients as `payload.signature`, both base64url encoded.
+// The signature keeps clients from forging sort keys, the payload is not secret.
+pub struct CursorSigner {
+    key: Vec<u8>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct InvalidCursor(pub String);
+
+impl CursorSigner {
+    pub fn new(secret: &[u8]) -> CursorSigner {
+        CursorSigner { key: secret.to_vec() }
+    }
+
+    // tokens signed by a random key are rejected after a restart and by other instances
+    pub fn random() -> CursorSigner {
+        let mut key = vec![0; 32];
+        rand::thread_rng().fill_bytes(&mut key);
+        CursorSigner { key }
+    }
+
+    pub fn from_config(cfg: &ServerConfig) -> CursorSigner {
+        match &cfg.cursor_secret {
+            Some(secret) => CursorSigner::new(secret.as_bytes()),
+            None => {
+                log::warn!("No server.cursor_secret configured, pagination cursors are signed with a random key");
+                CursorSigner::random()
+            },
+        }
+    }
+
+    fn mac(&self, payload: &[u8]) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("HMAC takes keys of any size");
+        mac.update(payload);
+        mac
+    }
+
+    pub fn sign(&self, cursor: &Cursor) -> String {
+        let payload = serde_json::to_vec(cursor).expect("cursor serializes to JSON");
+        let signature = self.mac(&payload).finalize().into_bytes();
+        format!("{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD))
+    }
+
+    // a cursor continues the listing it was issued for, it is rejected for another sort
+    pub fn verify(&self, token: &str, sort: Sort) -> Result<Cursor, InvalidCursor> {
+        let malformed = || InvalidCursor(String::from("Cursor is malformed"));
+
+        let (payload, signature) = token.split_once('.').ok_or_else(malformed)?;
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).map_err(|_| malformed())?;
+        let signature = base64::decode_config(signature, base64::URL_SAFE_NO_PAD).map_err(|_| malformed())?;
+        self.mac(&payload).verify_slice(&signature)
+            .map_err(|_| InvalidCursor(String::from("Cursor signature does not match")))?;
+
+        let cursor: Cursor = serde_json::from_slice(&payload).map_err(|_| malformed())?;
+        if cursor.sort != sort {
+            return Err(InvalidCursor(format!("Cursor was issued for sort '{}'", String::from(cursor.sort))));
+        }
+        if cursor.sort.field == SortField::Name && cursor.name.is_none() {
+            return Err(malformed());
+        }
+        Ok(cursor)
+    }
+}
+
+impl Display for InvalidCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl ResponseError for InvalidCursor {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::BAD_REQUEST
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        Problem::new("invalid-cursor", "Invalid cursor", self.status_code(), self.0.clone()).error_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::model::{Cursor, Sort, SortField, SortOrder};
+    use super::{CursorSigner, InvalidCursor};
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = CursorSigner::new(b"secret");
+        let sort = Sort { field: SortField::Name, order: SortOrder::Desc };
+        let cursor = Cursor { sort, id: 7, name: Some("User7".to_string()) };
+
+        let token = signer.sign(&cursor);
+        assert_eq!(Ok(cursor), signer.verify(&token, sort));
+        assert_eq!(Err(InvalidCursor("Cursor was issued for sort 'name:desc'".to_string())), signer.verify(&token, Sort::default()));
+    }
+
+    #[test]
+    fn test_reject_forged_cursor() {
+        let signer = CursorSigner::new(b"secret");
+        let token = signer.sign(&Cursor { sort: Sort::default(), id: 7, name: None });
+        let (_, signature) = token.split_once('.').unwrap();
+        let forged_payload = base64::encode_config("{\"sort\":\"id\",\"id\":1}", base64::URL_SAFE_NO_PAD);
+
+        let mismatch = Err(InvalidCursor("Cursor signature does not match".to_string()));
+        assert_eq!(mismatch, signer.verify(&format!("{}.{}", forged_payload, signature), Sort::default()));
+        assert_eq!(mismatch, CursorSigner::new(b"other").verify(&token, Sort::default()));
+        assert_eq!(Err(InvalidCursor("Cursor is malformed".to_string())), signer.verify("garbage", Sort::default()));
+    }
+}
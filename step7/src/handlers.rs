@@ -1,31 +1,522 @@
-use actix_web::{Responder, web::{self, Data}, get, post, delete};
+use actix_web::{HttpRequest, HttpResponse, HttpMessage, web::{self, Data}, get, post, put, patch, delete};
+use actix_web::dev::JsonBody;
+use actix_web::http::{StatusCode, header::{self, ETag, EntityTag, IfMatch, IfNoneMatch}};
+use futures::StreamExt;
+use json_patch::PatchErrorKind;
+use validator::Validate;
 
-use crate::{services::{UserDAO}, model::{User, UserFields, UserDAOError}};
+use crate::{services::{UserDAO}, model::{User, UserFields, UserDAOError, UsersQuery, PagedResponse, Cursor, CursorPage, SearchQuery, SearchHit, BatchOp, BatchQuery, BatchItemResult, BatchResponse}, problem::{self, Problem}};
+use crate::auth::{AuthError, Authorized, Permission, UsersAdmin, UsersRead, UsersWrite};
+use crate::api_keys::{self, ApiKeyDAO, NewApiKey};
+use crate::credentials::{self, CredentialDAO, LoginRequest, PasswordChange, PasswordLogin, RefreshRequest};
+use crate::cursor::CursorSigner;
+use crate::health::{self, Readiness};
+use crate::metrics::Metrics;
+use crate::transfer::{self, DataFormat, ExportQuery, ImportQuery, ImportLineError, ImportReport};
 
-pub async fn users_list(dao: Data<Box<dyn UserDAO>>) -> Result<web::Json<Vec<User>>, UserDAOError> {
-    dao.list().await.map(|list| web::Json(list))
+const MERGE_PATCH: &str = "application/merge-patch+json";
+const JSON_PATCH: &str = "application/json-patch+json";
+const MAX_BATCH_SIZE: usize = 1000;
+// MAX_BATCH_SIZE operations of up to 1 KiB each, larger bodies are refused while read
+const MAX_BATCH_BYTES: usize = MAX_BATCH_SIZE * 1024;
+
+fn page_link(req: &HttpRequest, query: &UsersQuery, page: u64) -> String {
+    let page_query = UsersQuery { page, ..query.clone() };
+    format!("{}?{}", req.path(), serde_urlencoded::to_string(&page_query).unwrap_or_default())
+}
+
+// keyset links keep the filters and the limit, page and page_size do not apply
+fn cursor_link(req: &HttpRequest, query: &UsersQuery, limit: u64, cursor: &str) -> String {
+    let mut params = vec![("sort", String::from(query.sort))];
+    if let Some(prefix) = &query.name_prefix {
+        params.push(("name_prefix", prefix.clone()));
+    }
+    if let Some(substring) = &query.name_contains {
+        params.push(("name_contains", substring.clone()));
+    }
+    if query.include_deleted {
+        params.push(("include_deleted", String::from("true")));
+    }
+    params.push(("limit", limit.to_string()));
+    params.push(("cursor", cursor.to_string()));
+    format!("{}?{}", req.path(), serde_urlencoded::to_string(&params).unwrap_or_default())
+}
+
+// cursor or limit switch to keyset pagination, stable under concurrent inserts and cheap on deep pages
+async fn users_after(req: &HttpRequest, query: &UsersQuery, dao: &dyn UserDAO, cursors: &CursorSigner) -> Result<HttpResponse, actix_web::Error> {
+    let after = query.cursor.as_deref()
+        .map(|token| cursors.verify(token, query.sort))
+        .transpose()?;
+    let limit = query.limit.unwrap_or(query.page_size);
+
+    // one more user tells whether there is a next page
+    let mut items = dao.list_after(query, after.as_ref(), limit + 1).await?;
+    let has_next = items.len() as u64 > limit;
+    items.truncate(limit as usize);
+
+    let next_cursor = items.last()
+        .filter(|_| has_next)
+        .map(|last| cursors.sign(&Cursor::after(last, query.sort)));
+    let next = next_cursor.as_deref().map(|cursor| cursor_link(req, query, limit, cursor));
+
+    Ok(HttpResponse::Ok().json(CursorPage { items, limit, next_cursor, next }))
+}
+
+#[tracing::instrument(skip_all)]
+pub async fn users_list(auth: Authorized<UsersRead>, req: HttpRequest, query: web::Query<UsersQuery>, dao: Data<Box<dyn UserDAO>>, cursors: Data<CursorSigner>) -> Result<HttpResponse, actix_web::Error> {
+    let query = query.into_inner();
+    if query.include_deleted {
+        auth.require(Permission::Admin, "include_deleted")?;
+    }
+    if query.keyset() {
+        return users_after(&req, &query, dao.as_ref().as_ref(), &cursors).await;
+    }
+
+    let page = dao.list(&query).await?;
+
+    let has_next = query.offset() + query.page_size < page.total;
+    let next = has_next.then(|| page_link(&req, &query, query.page + 1));
+    let prev = (query.page > 1).then(|| page_link(&req, &query, query.page - 1));
+
+    Ok(HttpResponse::Ok().json(PagedResponse {
+        items: page.items,
+        page: query.page,
+        page_size: query.page_size,
+        total: page.total,
+        next,
+        prev,
+    }))
+}
+
+fn etag(user: &User) -> ETag {
+    ETag(EntityTag::new_strong(user.version.to_string()))
+}
+
+fn user_response(user: &User) -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header(etag(user))
+        .json(user)
+}
+
+// If-Match takes "*" or strong ETags, the precondition passes when any of them is the current version.
+// Weak and malformed tags never match, a header without a strong tag always fails.
+fn expected_versions(req: &HttpRequest) -> Result<Option<Vec<u64>>, UserDAOError> {
+    if !req.headers().contains_key(header::IF_MATCH) {
+        return Ok(None);
+    }
+    match req.get_header::<IfMatch>() {
+        Some(IfMatch::Any) => Ok(None),
+        Some(IfMatch::Items(tags)) => {
+            let versions: Vec<u64> = tags.iter()
+                .filter(|tag| !tag.weak)
+                .filter_map(|tag| tag.tag().parse().ok())
+                .collect();
+            if versions.is_empty() {
+                return Err(UserDAOError::VersionMismatch);
+            }
+            Ok(Some(versions))
+        },
+        None => Err(UserDAOError::VersionMismatch),
+    }
 }
 
 #[get("users/{id}")]
-pub async fn get_user_by_id(uid: web::Path<u64>, dao: Data<Box<dyn UserDAO>>) -> Result<web::Json<User>, UserDAOError> {
-    dao.find_by_id(uid.into_inner()).await.map(|user| web::Json(user))
+#[tracing::instrument(skip_all)]
+pub async fn get_user_by_id(_: Authorized<UsersRead>, req: HttpRequest, uid: web::Path<u64>, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, UserDAOError> {
+    let user = dao.find_by_id(uid.into_inner()).await?;
+    let etag = etag(&user);
+
+    let not_modified = match req.get_header::<IfNoneMatch>() {
+        Some(IfNoneMatch::Any) => true,
+        Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+        None => false,
+    };
+
+    if not_modified {
+        Ok(HttpResponse::NotModified().insert_header(etag).finish())
+    } else {
+        Ok(user_response(&user))
+    }
 }
 
 #[post("users")]
-pub async fn create_user(fields: web::Json<UserFields>, dao: Data<Box<dyn UserDAO>>) -> Result<web::Json<User>, UserDAOError> {
-    dao.create(&fields).await.map(|user| web::Json(user))
+#[tracing::instrument(skip_all)]
+pub async fn create_user(_: Authorized<UsersWrite>, req: HttpRequest, fields: web::Json<UserFields>, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, UserDAOError> {
+    let user = dao.create(&fields).await?;
+    let location = format!("{}/{}", req.path().trim_end_matches('/'), user.id);
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, location))
+        .insert_header(etag(&user))
+        .json(user))
+}
+
+#[put("users/{id}")]
+#[tracing::instrument(skip_all)]
+pub async fn replace_user(auth: Authorized<UsersWrite>, req: HttpRequest, uid: web::Path<u64>, fields: web::Json<UserFields>, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    let id = uid.into_inner();
+    auth.require_self_or_admin(id)?;
+    let user = matched_user(&req, dao.as_ref().as_ref(), id).await?;
+    require_status_kept(&auth, &user, &fields)?;
+    // the status was checked against this version, a concurrent change of it must not be overwritten
+    let user = dao.update(id, &fields, Some(&[user.version])).await?;
+    Ok(user_response(&user))
+}
+
+// the stored user, once If-Match allows writing over its version
+async fn matched_user(req: &HttpRequest, dao: &dyn UserDAO, id: u64) -> Result<User, UserDAOError> {
+    let expected = expected_versions(req)?;
+    let user = dao.find_by_id(id).await?;
+    if expected.is_some_and(|versions| !versions.contains(&user.version)) {
+        return Err(UserDAOError::VersionMismatch);
+    }
+    Ok(user)
+}
+
+// users edit their own record but only admins its status, a suspended user must not reactivate itself
+fn require_status_kept(auth: &Authorized<UsersWrite>, user: &User, fields: &UserFields) -> Result<(), AuthError> {
+    if fields.status == user.fields.status {
+        Ok(())
+    } else {
+        auth.require(Permission::Admin, "Changing the status")
+    }
+}
+
+fn apply_patch(fields: &UserFields, content_type: &str, body: &[u8]) -> Result<UserFields, actix_web::Error> {
+    let mut doc = serde_json::to_value(fields)
+        .map_err(|err| Problem::new("storage-error", "Storage error", StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))?;
+
+    match content_type {
+        MERGE_PATCH => {
+            let merge_patch: serde_json::Value = serde_json::from_slice(body)
+                .map_err(|err| Problem::malformed_request(err.to_string()))?;
+            json_patch::merge(&mut doc, &merge_patch);
+        },
+        JSON_PATCH => {
+            let patch: json_patch::Patch = serde_json::from_slice(body)
+                .map_err(|err| Problem::malformed_request(err.to_string()))?;
+            json_patch::patch(&mut doc, &patch.0).map_err(|err| match err.kind {
+                PatchErrorKind::TestFailed => 
+                    Problem::new("patch-test-failed", "Patch test failed", StatusCode::CONFLICT, err.to_string()),
+                _ => 
+                    Problem::new("unprocessable-patch", "Patch can not be applied", StatusCode::UNPROCESSABLE_ENTITY, err.to_string()),
+            })?;
+        },
+        _ => {
+            let detail = format!("Expected {} or {} content type", MERGE_PATCH, JSON_PATCH);
+            return Err(Problem::new("unsupported-media-type", "Unsupported media type", StatusCode::UNSUPPORTED_MEDIA_TYPE, detail).into());
+        },
+    }
+
+    let patched = serde_json::from_value(doc)
+        .map_err(|err| Problem::new("unprocessable-patch", "Patch can not be applied", StatusCode::UNPROCESSABLE_ENTITY, err.to_string()))?;
+    Ok(patched)
+}
+
+#[patch("users/{id}")]
+#[tracing::instrument(skip_all)]
+pub async fn patch_user(auth: Authorized<UsersWrite>, req: HttpRequest, uid: web::Path<u64>, body: web::Bytes, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    let id = uid.into_inner();
+    auth.require_self_or_admin(id)?;
+    let user = matched_user(&req, dao.as_ref().as_ref(), id).await?;
+
+    let fields = apply_patch(&user.fields, req.content_type(), &body)?;
+    require_status_kept(&auth, &user, &fields)?;
+    // the patch was computed from this version, a concurrent update must not be overwritten
+    let patched = dao.update(id, &fields, Some(&[user.version])).await?;
+    Ok(user_response(&patched))
+}
+
+#[post("users/{id}/restore")]
+#[tracing::instrument(skip_all)]
+pub async fn restore_user(_: Authorized<UsersAdmin>, req: HttpRequest, uid: web::Path<u64>, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, UserDAOError> {
+    let user = dao.restore(uid.into_inner(), expected_versions(&req)?.as_deref()).await?;
+    Ok(user_response(&user))
 }
 
+// Legacy update route for old clients, enabled with server.legacy_update_route
 #[post("users/{id}")]
-pub async fn update_user(uid: web::Path<u64>, fields: web::Json<UserFields>, dao: Data<Box<dyn UserDAO>>) -> impl Responder {
-    let users_fields = fields.into_inner();
-    let user = User {id: uid.into_inner(), fields: users_fields};
-    dao.update(&user).await.map(|user| web::Json(user))
+#[tracing::instrument(skip_all)]
+pub async fn update_user(auth: Authorized<UsersWrite>, uid: web::Path<u64>, fields: web::Json<UserFields>, dao: Data<Box<dyn UserDAO>>) -> Result<web::Json<User>, actix_web::Error> {
+    let id = uid.into_inner();
+    auth.require_self_or_admin(id)?;
+    let user = dao.find_by_id(id).await?;
+    require_status_kept(&auth, &user, &fields)?;
+    Ok(web::Json(dao.update(id, &fields, Some(&[user.version])).await?))
 }
 
+// Answers 204 without body when the client sends Prefer: return=minimal
 #[delete("/users/{id}")]
-pub async fn delete_user(uid: web::Path<u64>, dao: Data<Box<dyn UserDAO>>) -> Result<web::Json<User>, UserDAOError> {
-    dao.delete_by_id(uid.into_inner()).await.map(|user| web::Json(user))
+#[tracing::instrument(skip_all)]
+pub async fn delete_user(auth: Authorized<UsersWrite>, req: HttpRequest, uid: web::Path<u64>, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    let id = uid.into_inner();
+    auth.require_self_or_admin(id)?;
+    let user = dao.delete_by_id(id, expected_versions(&req)?.as_deref()).await?;
+
+    let minimal = req.headers().get_all("Prefer")
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|pref| pref.trim() == "return=minimal");
+
+    if minimal {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Ok(HttpResponse::Ok().json(user))
+    }
+}
+
+fn batch_item(index: usize, op: &BatchOp, result: Result<User, UserDAOError>) -> BatchItemResult {
+    match result {
+        Ok(user) => {
+            let status = match op {
+                BatchOp::Create { .. } => StatusCode::CREATED,
+                _ => StatusCode::OK,
+            };
+            BatchItemResult { index, status: status.as_u16(), user: Some(user), error: None }
+        },
+        Err(err) => {
+            let problem = Problem::from(&err);
+            BatchItemResult { index, status: problem.status, user: None, error: Some(problem) }
+        },
+    }
+}
+
+// Partial success answers 207 with the outcome of every operation,
+// a failed atomic batch answers with the problem of the failed operation.
+#[post("/users:batch")]
+#[tracing::instrument(skip_all)]
+pub async fn batch_users(_: Authorized<UsersAdmin>, req: HttpRequest, query: web::Query<BatchQuery>, payload: web::Payload, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    let ops: Vec<BatchOp> = JsonBody::new(&req, &mut payload.into_inner(), None, true)
+        .limit(MAX_BATCH_BYTES)
+        .await
+        .map_err(problem::json_error)?;
+    if ops.len() > MAX_BATCH_SIZE {
+        let detail = format!("A batch takes at most {} operations, got {}", MAX_BATCH_SIZE, ops.len());
+        return Err(Problem::new("batch-too-large", "Batch too large", StatusCode::PAYLOAD_TOO_LARGE, detail).into());
+    }
+
+    let results: Vec<BatchItemResult> = if query.atomic {
+        let users = dao.batch_atomic(&ops).await.map_err(|err| Problem::from(&err))?;
+        ops.iter().zip(users).enumerate()
+            .map(|(index, (op, user))| batch_item(index, op, Ok(user)))
+            .collect()
+    } else {
+        dao.batch(&ops).await?.into_iter().zip(ops.iter()).enumerate()
+            .map(|(index, (result, op))| batch_item(index, op, result))
+            .collect()
+    };
+
+    let status = if results.iter().all(|r| r.error.is_none()) { StatusCode::OK } else { StatusCode::MULTI_STATUS };
+    Ok(HttpResponse::build(status).json(BatchResponse { results }))
+}
+
+#[get("/users/export")]
+#[tracing::instrument(skip_all)]
+pub async fn export_users(auth: Authorized<UsersRead>, query: web::Query<ExportQuery>, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    if query.include_deleted {
+        auth.require(Permission::Admin, "include_deleted")?;
+    }
+
+    let format = query.format;
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .insert_header((header::CONTENT_DISPOSITION, format!("attachment; filename=\"users.{}\"", format.extension())))
+        .streaming(transfer::export(dao.as_ref().as_ref(), &query)))
+}
+
+fn search_link(req: &HttpRequest, query: &SearchQuery, page: u64) -> String {
+    let page_query = SearchQuery { page, ..query.clone() };
+    format!("{}?{}", req.path(), serde_urlencoded::to_string(&page_query).unwrap_or_default())
+}
+
+// Fuzzy lookup by name or display name, ranked by score
+#[get("/users/search")]
+#[tracing::instrument(skip_all)]
+pub async fn search_users(_: Authorized<UsersRead>, req: HttpRequest, query: web::Query<SearchQuery>, dao: Data<Box<dyn UserDAO>>) -> Result<web::Json<PagedResponse<SearchHit>>, UserDAOError> {
+    let query = query.into_inner();
+    let page = dao.search(&query).await?;
+
+    let has_next = query.offset() + query.page_size < page.total;
+    let next = has_next.then(|| search_link(&req, &query, query.page + 1));
+    let prev = (query.page > 1).then(|| search_link(&req, &query, query.page - 1));
+
+    Ok(web::Json(PagedResponse {
+        items: page.items,
+        page: query.page,
+        page_size: query.page_size,
+        total: page.total,
+        next,
+        prev,
+    }))
+}
+
+// All matching users in one response without paging, as NDJSON when accepted and as a JSON array otherwise
+#[get("/users/stream")]
+#[tracing::instrument(skip_all)]
+pub async fn users_stream(auth: Authorized<UsersRead>, req: HttpRequest, query: web::Query<UsersQuery>, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    // the status is sent before the first user, an invalid query must fail here
+    query.validate().map_err(UserDAOError::from)?;
+    if query.include_deleted {
+        auth.require(Permission::Admin, "include_deleted")?;
+    }
+
+    let ndjson = req.headers().get_all(header::ACCEPT)
+        .filter_map(|v| v.to_str().ok())
+        .any(|accept| accept.contains(DataFormat::Ndjson.content_type()));
+    let format = if ndjson { DataFormat::Ndjson } else { DataFormat::Json };
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .streaming(transfer::encode_stream(dao.list_stream(&query), format)))
+}
+
+async fn read_body(mut payload: web::Payload, limit: usize) -> Result<web::BytesMut, actix_web::Error> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > limit {
+            let detail = format!("The body must not be larger than {} bytes", limit);
+            return Err(Problem::new("payload-too-large", "Payload too large", StatusCode::PAYLOAD_TOO_LARGE, detail).into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+// The format follows the content type, invalid rows are reported by line and do not stop the others.
+// A bulk write like the batch, it needs the same admin permission.
+#[post("/users/import")]
+#[tracing::instrument(skip_all)]
+pub async fn import_users(_: Authorized<UsersAdmin>, req: HttpRequest, query: web::Query<ImportQuery>, payload: web::Payload, dao: Data<Box<dyn UserDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    let format = DataFormat::from_content_type(req.content_type()).ok_or_else(|| {
+        let detail = "Expected text/csv, application/x-ndjson or application/json".to_string();
+        Problem::new("unsupported-media-type", "Unsupported media type", StatusCode::UNSUPPORTED_MEDIA_TYPE, detail)
+    })?;
+    let body = read_body(payload, transfer::MAX_IMPORT_BYTES).await?;
+    let rows = transfer::check(transfer::parse(format, &body)?);
+
+    let total = rows.len();
+    let mut errors = vec![];
+    let mut lines = vec![];
+    let mut ops = vec![];
+    for row in rows {
+        match row.fields {
+            Ok(fields) => {
+                lines.push(row.line);
+                ops.push(BatchOp::Create { fields });
+            },
+            Err(problem) => errors.push(ImportLineError { line: row.line, problem }),
+        }
+    }
+
+    if !query.dry_run {
+        let results = dao.batch(&ops).await?;
+        for (line, result) in lines.into_iter().zip(results) {
+            if let Err(err) = result {
+                errors.push(ImportLineError { line, problem: Problem::from(&err) });
+            }
+        }
+        errors.sort_by_key(|e| e.line);
+    }
+
+    Ok(HttpResponse::Ok().json(ImportReport {
+        dry_run: query.dry_run,
+        total,
+        imported: total - errors.len(),
+        failed: errors.len(),
+        errors,
+    }))
+}
+
+// Service account keys, the secret is part of the create response only
+#[post("/api-keys")]
+#[tracing::instrument(skip_all)]
+pub async fn create_api_key(_: Authorized<UsersAdmin>, req: HttpRequest, new_key: web::Json<NewApiKey>, keys: Data<Box<dyn ApiKeyDAO>>) -> Result<HttpResponse, UserDAOError> {
+    let created = api_keys::issue(keys.as_ref().as_ref(), &new_key).await?;
+    let location = format!("{}/{}", req.path().trim_end_matches('/'), created.key.id);
+    Ok(HttpResponse::Created()
+        .insert_header((header::LOCATION, location))
+        .json(created))
+}
+
+#[get("/api-keys")]
+#[tracing::instrument(skip_all)]
+pub async fn list_api_keys(_: Authorized<UsersAdmin>, keys: Data<Box<dyn ApiKeyDAO>>) -> Result<HttpResponse, UserDAOError> {
+    Ok(HttpResponse::Ok().json(keys.list().await?))
+}
+
+#[delete("/api-keys/{id}")]
+#[tracing::instrument(skip_all)]
+pub async fn delete_api_key(_: Authorized<UsersAdmin>, id: web::Path<String>, keys: Data<Box<dyn ApiKeyDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    if keys.delete(&id).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        let detail = format!("API key {} not found", id);
+        Err(Problem::new("not-found", "API key not found", StatusCode::NOT_FOUND, detail).into())
+    }
+}
+
+// Liveness probe, answers while the process runs without touching the store
+#[get("/health/live")]
+#[tracing::instrument(skip_all)]
+pub async fn health_live() -> HttpResponse {
+    HttpResponse::Ok().json(serde_json::json!({ "status": health::Status::Up }))
+}
+
+// 503 while a component is down or once shutdown started
+#[get("/health/ready")]
+#[tracing::instrument(skip_all)]
+pub async fn health_ready(readiness: Data<Readiness>, dao: Data<Box<dyn UserDAO>>) -> HttpResponse {
+    let report = health::readiness(&readiness, dao.as_ref().as_ref()).await;
+    let status = if report.is_ready() { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    HttpResponse::build(status)
+        .insert_header(header::CacheControl(vec![header::CacheDirective::NoStore]))
+        .json(report)
+}
+
+// Prometheus text format, left out of authentication like the login routes
+#[get("/metrics")]
+#[tracing::instrument(skip_all)]
+pub async fn metrics(metrics: Data<Metrics>) -> Result<HttpResponse, UserDAOError> {
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics.render()?))
+}
+
+// Password login, tokens are not cached by intermediaries
+#[post("/auth/login")]
+#[tracing::instrument(skip_all)]
+pub async fn login(request: web::Json<LoginRequest>, users: Data<Box<dyn UserDAO>>, store: Data<Box<dyn CredentialDAO>>, auth: Data<PasswordLogin>) -> Result<HttpResponse, actix_web::Error> {
+    let tokens = credentials::login(&auth, users.as_ref().as_ref(), store.as_ref().as_ref(), &request).await?;
+    Ok(HttpResponse::Ok().insert_header(header::CacheControl(vec![header::CacheDirective::NoStore])).json(tokens))
+}
+
+#[post("/auth/refresh")]
+#[tracing::instrument(skip_all)]
+pub async fn refresh_token(request: web::Json<RefreshRequest>, users: Data<Box<dyn UserDAO>>, store: Data<Box<dyn CredentialDAO>>, auth: Data<PasswordLogin>) -> Result<HttpResponse, actix_web::Error> {
+    let tokens = credentials::refresh(&auth, users.as_ref().as_ref(), store.as_ref().as_ref(), &request.refresh_token).await?;
+    Ok(HttpResponse::Ok().insert_header(header::CacheControl(vec![header::CacheDirective::NoStore])).json(tokens))
+}
+
+#[post("/auth/logout")]
+#[tracing::instrument(skip_all)]
+pub async fn logout(request: web::Json<RefreshRequest>, store: Data<Box<dyn CredentialDAO>>) -> Result<HttpResponse, actix_web::Error> {
+    credentials::logout(store.as_ref().as_ref(), &request.refresh_token).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[put("/users/{id}/password")]
+#[tracing::instrument(skip_all)]
+pub async fn set_password(auth: Authorized<UsersWrite>, uid: web::Path<u64>, change: web::Json<PasswordChange>, users: Data<Box<dyn UserDAO>>, store: Data<Box<dyn CredentialDAO>>, password_login: Data<PasswordLogin>) -> Result<HttpResponse, actix_web::Error> {
+    let id = uid.into_inner();
+    auth.require_self_or_admin(id)?;
+    users.find_by_id(id).await?;
+    if auth.is_self(id) {
+        credentials::change_password(&password_login, store.as_ref().as_ref(), id, &change).await?;
+    } else {
+        // admins reset the password of others without knowing it
+        credentials::set_password(&password_login, store.as_ref().as_ref(), id, &change).await?;
+    }
+    Ok(HttpResponse::NoContent().finish())
 }
 
 #[cfg(test)]
@@ -34,13 +525,79 @@ mod tests {
     use super::*;
     use actix_web::{test, App, web::Data};
 
-    use crate::configs::{InMemory};
-    use crate::services::UserInMemoryDAO;
+    use actix_web::dev::Service;
+    use futures::TryFutureExt;
+
+    use crate::api_keys::{ApiKeyInMemoryDAO, CreatedApiKey};
+    use crate::auth::{self, test_auth, test_token};
+    use crate::credentials::{test_login, CredentialInMemoryDAO, TokenResponse};
+    use crate::configs::{InMemory, Sqlite};
+    use crate::model::UserStatus;
+    use crate::problem::{self, FieldProblem, Problem};
+    use crate::services::{UserInMemoryDAO, UserSqliteDAO};
+    use crate::conformance::{deleted, fields, seeded, timeless, user, versioned};
 
     fn create_dao(inmemory: Option<&InMemory>) -> Box<dyn UserDAO + 'static> {
         Box::new(UserInMemoryDAO::new(inmemory)) 
     }
 
+    #[test]
+    async fn test_health() {
+        let readiness = Data::new(Readiness::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(create_dao(None)))
+                .app_data(readiness.clone())
+                .app_data(test_auth())
+                .wrap(auth::Authentication)
+                .service(health_live)
+                .service(health_ready),
+        ).await;
+
+        // probes need no token
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/health/live").to_request()).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(serde_json::json!({ "status": "up" }), test::read_body_json::<serde_json::Value, _>(resp).await);
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/health/ready").to_request()).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("no-store", resp.headers().get("cache-control").unwrap());
+        let report: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!("up", report["status"]);
+        assert_eq!("up", report["components"]["store"]["status"]);
+        assert!(report["components"]["store"]["latency_ms"].is_number());
+
+        readiness.shut_down();
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/health/ready").to_request()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        assert_eq!(serde_json::json!({ "status": "shutting_down", "components": {} }), test::read_body_json::<serde_json::Value, _>(resp).await);
+        // still alive while draining
+        assert_eq!(StatusCode::OK, test::call_service(&app, test::TestRequest::get().uri("/health/live").to_request()).await.status());
+    }
+
+    #[test]
+    async fn test_health_store_down() {
+        let path = std::env::temp_dir().join(format!("health-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sqlite = UserSqliteDAO::new(&Sqlite { path: path.to_string_lossy().to_string() }).await;
+        sqlite.pool().get_pool().unwrap().close().await;
+        let dao: Box<dyn UserDAO> = Box::new(sqlite);
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(dao))
+                .app_data(Data::new(Readiness::default()))
+                .service(health_ready),
+        ).await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/health/ready").to_request()).await;
+        assert_eq!(StatusCode::SERVICE_UNAVAILABLE, resp.status());
+        let report: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!("down", report["status"]);
+        assert_eq!("down", report["components"]["store"]["status"]);
+        assert!(report["components"]["store"]["error"].is_string(), "{}", report);
+        let _ = std::fs::remove_file(&path);
+    }
+
     #[test]
     async fn test_list() {
         let dao = create_dao(Some(&InMemory {users: 1}));
@@ -49,6 +606,7 @@ mod tests {
         let app = test::init_service(
             App::new()
                 .app_data(user_data)
+                .app_data(Data::new(CursorSigner::new(b"test")))
                 .route("/users", web::get().to(users_list)),
         ).await;
 
@@ -56,8 +614,341 @@ mod tests {
             .uri("/users")
             .to_request();
 
-        let users: Vec<User> = test::call_and_read_body_json(&app, req).await;
-        assert_eq!(vec![User{id: 1, fields: UserFields{ name: "User1".to_string()}}], users);
+        let users: PagedResponse<User> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![seeded(1)], users.items.into_iter().map(timeless).collect::<Vec<User>>());
+        assert_eq!(1, users.total);
+        assert_eq!(None, users.next);
+        assert_eq!(None, users.prev);
+    }
+
+    #[actix_web::test]
+    async fn test_list_page_links() {
+        let dao = create_dao(Some(&InMemory {users: 5}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .route("/users", web::get().to(users_list)),
+        ).await;
+
+        let req = test::TestRequest::get()
+            .uri("/users?page=2&page_size=2&sort=name:desc&name_prefix=User")
+            .to_request();
+
+        let users: PagedResponse<User> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![3, 2], users.items.iter().map(|u| u.id).collect::<Vec<u64>>());
+        assert_eq!(5, users.total);
+        assert_eq!(Some("/users?page=3&page_size=2&sort=name%3Adesc&name_prefix=User".to_string()), users.next);
+        assert_eq!(Some("/users?page=1&page_size=2&sort=name%3Adesc&name_prefix=User".to_string()), users.prev);
+    }
+
+    #[actix_web::test]
+    async fn test_api_keys() {
+        let keys: Box<dyn ApiKeyDAO> = Box::new(ApiKeyInMemoryDAO::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(create_dao(Some(&InMemory {users: 3}))))
+                .app_data(Data::new(keys))
+                .app_data(test_auth())
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .wrap_fn(|req, srv| srv.call(req).map_ok(problem::with_instance))
+                .wrap(auth::Authentication)
+                .route("/users", web::get().to(users_list))
+                .service(create_user)
+                .service(create_api_key)
+                .service(list_api_keys)
+                .service(delete_api_key),
+        ).await;
+        let bearer = |roles: &[&str]| ("Authorization", format!("Bearer {}", test_token(1, roles)));
+        let new_key = serde_json::json!({ "owner": "billing", "scopes": ["users:read"] });
+
+        let req = test::TestRequest::post().uri("/api-keys").insert_header(bearer(&["writer"])).set_json(&new_key).to_request();
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::post().uri("/api-keys").insert_header(bearer(&["admin"])).set_json(&new_key).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+        let created: CreatedApiKey = test::read_body_json(resp).await;
+        assert_eq!("billing", created.key.owner);
+        assert_eq!(vec![Permission::Read], created.key.scopes);
+
+        let req = test::TestRequest::get().uri("/users").insert_header(("X-API-Key", created.token.clone())).to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::post().uri("/users").insert_header(("X-API-Key", created.token.clone())).set_json(fields("Newcomer")).to_request();
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, req).await.status());
+
+        // the secret is never listed
+        let req = test::TestRequest::get().uri("/api-keys").insert_header(bearer(&["admin"])).to_request();
+        let listed: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(created.key.id, listed[0]["id"]);
+        assert!(listed[0]["last_used_at"].is_string());
+        assert!(listed[0].get("token").is_none() && listed[0].get("hash").is_none());
+
+        let uri = format!("/api-keys/{}", created.key.id);
+        let req = test::TestRequest::delete().uri(&uri).insert_header(bearer(&["admin"])).to_request();
+        assert_eq!(StatusCode::NO_CONTENT, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/users").insert_header(("X-API-Key", created.token.clone())).to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::delete().uri(&uri).insert_header(bearer(&["admin"])).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_FOUND, resp.status());
+        let problem: Problem = test::read_body_json(resp).await;
+        assert_eq!(format!("API key {} not found", created.key.id), problem.detail);
+        assert_eq!(Some(uri), problem.instance);
+    }
+
+    #[actix_web::test]
+    async fn test_password_login() {
+        let credentials: Box<dyn CredentialDAO> = Box::new(CredentialInMemoryDAO::default());
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(create_dao(Some(&InMemory {users: 3}))))
+                .app_data(Data::new(credentials))
+                .app_data(test_auth())
+                .app_data(test_login())
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .wrap_fn(|req, srv| srv.call(req).map_ok(problem::with_instance))
+                .wrap(auth::Authentication)
+                .route("/users", web::get().to(users_list))
+                .service(login)
+                .service(refresh_token)
+                .service(logout)
+                .service(set_password),
+        ).await;
+        let bearer = |user_id| ("Authorization", format!("Bearer {}", test_token(user_id, &["writer"])));
+        let credentials = serde_json::json!({ "name": "User2", "password": "correct horse 2" });
+
+        let req = test::TestRequest::put().uri("/users/2/password").insert_header(bearer(1)).set_json(serde_json::json!({ "password": "correct horse 2" })).to_request();
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, req).await.status());
+
+        // the first password is set by an admin
+        let admin = ("Authorization", format!("Bearer {}", test_token(1, &["admin"])));
+        let req = test::TestRequest::put().uri("/users/2/password").insert_header(admin).set_json(serde_json::json!({ "password": "initial horse 2" })).to_request();
+        assert_eq!(StatusCode::NO_CONTENT, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::put().uri("/users/2/password").insert_header(bearer(2)).set_json(serde_json::json!({ "password": "horses" })).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        let problem: Problem = test::read_body_json(resp).await;
+        assert_eq!(vec!["length", "password_policy"], problem.errors.iter().map(|e| e.code.as_str()).collect::<Vec<_>>());
+        assert!(!serde_json::to_string(&problem).unwrap().contains("horses"));
+
+        // changing the own password needs the current one
+        for current in [None, Some("wrong horse 2")] {
+            let req = test::TestRequest::put().uri("/users/2/password").insert_header(bearer(2))
+                .set_json(serde_json::json!({ "password": "correct horse 2", "current_password": current })).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(StatusCode::FORBIDDEN, resp.status());
+            assert_eq!("/problems/wrong-password", test::read_body_json::<Problem, _>(resp).await.problem_type);
+        }
+
+        let req = test::TestRequest::put().uri("/users/2/password").insert_header(bearer(2))
+            .set_json(serde_json::json!({ "password": "correct horse 2", "current_password": "initial horse 2" })).to_request();
+        assert_eq!(StatusCode::NO_CONTENT, test::call_service(&app, req).await.status());
+
+        // the login routes need no token
+        let req = test::TestRequest::post().uri("/auth/login").set_json(serde_json::json!({ "name": "User2", "password": "wrong" })).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::UNAUTHORIZED, resp.status());
+        assert_eq!(Some("/auth/login".to_string()), test::read_body_json::<Problem, _>(resp).await.instance);
+
+        let req = test::TestRequest::post().uri("/auth/login").set_json(&credentials).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("no-store", resp.headers().get("cache-control").unwrap());
+        let tokens: TokenResponse = test::read_body_json(resp).await;
+        assert_eq!(900, tokens.expires_in);
+
+        let req = test::TestRequest::get().uri("/users").insert_header(("Authorization", format!("Bearer {}", tokens.access_token))).to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let refresh = serde_json::json!({ "refresh_token": tokens.refresh_token });
+        let req = test::TestRequest::post().uri("/auth/refresh").set_json(&refresh).to_request();
+        let rotated: TokenResponse = test::call_and_read_body_json(&app, req).await;
+
+        let req = test::TestRequest::post().uri("/auth/refresh").set_json(&refresh).to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        // revoked with its family by the reuse, logging out again changes nothing
+        let req = test::TestRequest::post().uri("/auth/logout").set_json(serde_json::json!({ "refresh_token": rotated.refresh_token })).to_request();
+        assert_eq!(StatusCode::NO_CONTENT, test::call_service(&app, req).await.status());
+        let req = test::TestRequest::post().uri("/auth/logout").set_json(serde_json::json!({ "refresh_token": "unknown.token" })).to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::post().uri("/auth/login").set_json(&credentials).to_request();
+        let tokens: TokenResponse = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::post().uri("/auth/logout").set_json(serde_json::json!({ "refresh_token": tokens.refresh_token })).to_request();
+        assert_eq!(StatusCode::NO_CONTENT, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn test_permissions() {
+        let dao = create_dao(Some(&InMemory {users: 3}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(test_auth())
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .wrap(auth::Authentication)
+                .route("/users", web::get().to(users_list))
+                .service(create_user)
+                .service(replace_user)
+                .service(delete_user),
+        ).await;
+        let bearer = |user_id, roles: &[&str]| ("Authorization", format!("Bearer {}", test_token(user_id, roles)));
+        let detail = |resp| async move { test::read_body_json::<Problem, _>(resp).await.detail };
+
+        let req = test::TestRequest::get().uri("/users").to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/users").insert_header(bearer(1, &["reader"])).to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/users?include_deleted=true").insert_header(bearer(1, &["reader"])).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        assert_eq!("include_deleted requires permission users:admin", detail(resp).await);
+
+        let req = test::TestRequest::post().uri("/users").insert_header(bearer(1, &["reader"])).set_json(fields("Newcomer")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        assert_eq!("This route requires permission users:write", detail(resp).await);
+
+        // writers change their own record only
+        let req = test::TestRequest::put().uri("/users/2").insert_header(bearer(2, &["writer"])).set_json(fields("Renamed")).to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::put().uri("/users/1").insert_header(bearer(2, &["writer"])).set_json(fields("Renamed")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::FORBIDDEN, resp.status());
+        assert_eq!("Changing another user requires permission users:admin", detail(resp).await);
+
+        let req = test::TestRequest::delete().uri("/users/1").insert_header(bearer(2, &["writer"])).to_request();
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::put().uri("/users/1").insert_header(bearer(99, &["admin"])).set_json(fields("Changed")).to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::delete().uri("/users/3").insert_header(bearer(99, &["admin"])).to_request();
+        assert_eq!(StatusCode::OK, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn test_status_changed_by_admins_only() {
+        let dao = create_dao(Some(&InMemory {users: 2}));
+        let suspended = UserFields { status: UserStatus::Suspended, ..seeded(2).fields };
+        dao.update(2, &suspended, None).await.unwrap();
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(test_auth())
+                .wrap(auth::Authentication)
+                .service(replace_user)
+                .service(patch_user)
+                .service(update_user),
+        ).await;
+        let bearer = |user_id, roles: &[&str]| ("Authorization", format!("Bearer {}", test_token(user_id, roles)));
+        let active = UserFields { status: UserStatus::Active, ..suspended.clone() };
+
+        // a suspended user can not reactivate itself by any write
+        let requests = [
+            test::TestRequest::put().uri("/users/2").set_json(&active),
+            test::TestRequest::patch().uri("/users/2").insert_header(("Content-type", MERGE_PATCH)).set_payload(r#"{"status": "active"}"#),
+            test::TestRequest::post().uri("/users/2").set_json(&active),
+        ];
+        for req in requests {
+            let resp = test::call_service(&app, req.insert_header(bearer(2, &["writer"])).to_request()).await;
+            assert_eq!(StatusCode::FORBIDDEN, resp.status());
+            assert_eq!("Changing the status requires permission users:admin", test::read_body_json::<Problem, _>(resp).await.detail);
+        }
+
+        // other fields stay its own to change
+        let renamed = UserFields { display_name: Some("Still suspended".to_string()), ..suspended.clone() };
+        let req = test::TestRequest::put().uri("/users/2").insert_header(bearer(2, &["writer"])).set_json(&renamed).to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(UserStatus::Suspended, user.fields.status);
+
+        let req = test::TestRequest::put().uri("/users/2").insert_header(bearer(1, &["admin"])).set_json(&active).to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(UserStatus::Active, user.fields.status);
+    }
+
+    #[actix_web::test]
+    async fn test_list_cursor_pages() {
+        let dao = create_dao(Some(&InMemory {users: 5}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .route("/users", web::get().to(users_list))
+                .service(create_user),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users?limit=2&sort=name:desc&name_prefix=User").to_request();
+        let page: CursorPage<User> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![5, 4], page.items.iter().map(|u| u.id).collect::<Vec<u64>>());
+        assert_eq!(2, page.limit);
+        let cursor = page.next_cursor.unwrap();
+        let next = page.next.unwrap();
+        assert!(next.starts_with("/users?sort=name%3Adesc&name_prefix=User&limit=2&cursor="), "{}", next);
+
+        // a user sorted into the pages already read does not shift the next ones
+        let req = test::TestRequest::post().uri("/users").set_json(fields("User9")).to_request();
+        assert_eq!(StatusCode::CREATED, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri(&next).to_request();
+        let page: CursorPage<User> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![3, 2], page.items.iter().map(|u| u.id).collect::<Vec<u64>>());
+
+        let req = test::TestRequest::get().uri(&page.next.unwrap()).to_request();
+        let page: CursorPage<User> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![1], page.items.iter().map(|u| u.id).collect::<Vec<u64>>());
+        assert_eq!(None, page.next_cursor);
+        assert_eq!(None, page.next);
+
+        let req = test::TestRequest::get().uri(&format!("/users?cursor={}", cursor)).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+        let problem: Problem = test::read_body_json(resp).await;
+        assert_eq!("/problems/invalid-cursor", problem.problem_type);
+        assert_eq!("Cursor was issued for sort 'name:desc'", problem.detail);
+
+        let forged = format!("{}x", cursor);
+        let req = test::TestRequest::get().uri(&format!("/users?sort=name:desc&cursor={}", forged)).to_request();
+        assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn test_list_invalid_page() {
+        let dao = create_dao(None);
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .route("/users", web::get().to(users_list)),
+        ).await;
+
+        for page in [0, u64::MAX] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/users?page={}", page))
+                .to_request();
+
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(actix_web::http::StatusCode::BAD_REQUEST, resp.status());
+        }
     }
 
     #[actix_web::test]
@@ -78,7 +969,7 @@ mod tests {
             .to_request();
         let user: User = test::call_and_read_body_json(&app, req).await;
 
-        assert_eq!(User { id: 1, fields: UserFields { name: "User1".to_string() }}, user);
+        assert_eq!(seeded(1), timeless(user));
     }
 
     #[actix_web::test]
@@ -97,8 +988,20 @@ mod tests {
         let req = test::TestRequest::get()
             .uri("/users/2")
             .to_request();
-        let resp: UserDAOError = test::call_and_read_body_json(&app, req).await;
-        assert_eq!("User not found", resp.message);
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(actix_web::http::StatusCode::NOT_FOUND, resp.status());
+        assert_eq!("application/problem+json", resp.headers().get("content-type").unwrap());
+
+        let problem: Problem = test::read_body_json(resp).await;
+        assert_eq!(Problem {
+            problem_type: "/problems/not-found".to_string(),
+            title: "User not found".to_string(),
+            status: 404,
+            detail: "User not found".to_string(),
+            instance: None,
+            errors: vec![],
+            index: None,
+        }, problem);
     }
 
     #[actix_web::test]
@@ -119,9 +1022,12 @@ mod tests {
             .insert_header(("Content-type", "application/json"))
             .set_payload("{ \"name\": \"User1\" }")
             .to_request();
-        let user: User = test::call_and_read_body_json(&app, req).await;
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::CREATED, resp.status());
+        assert_eq!("/users/1", resp.headers().get("location").unwrap());
 
-        assert_eq!(User {id: 1, fields: UserFields { name: "User1".to_string() }}, user);
+        let created: User = test::read_body_json(resp).await;
+        assert_eq!(user(1, "User1"), timeless(created));
     }
 
     #[actix_web::test]
@@ -144,28 +1050,729 @@ mod tests {
             .to_request();
         let user: User = test::call_and_read_body_json(&app, req).await;
 
-        assert_eq!(User {id: 1, fields: UserFields{ name: "User2".to_string() }}, user);
+        assert_eq!(versioned(1, 2, "User2"), timeless(user));
     }
 
     #[actix_web::test]
-    async fn test_delete_user() {
-        let inmemory = InMemory {users: 1};
-
-        let dao = create_dao(Some(&inmemory));
-        let user_data = Data::new(dao); 
+    async fn test_replace_user() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
 
         let app = test::init_service(
             App::new()
                 .app_data(user_data)
-                .service(delete_user),
+                .service(replace_user),
         ).await;
 
-        let req = test::TestRequest::delete()
+        let req = test::TestRequest::put()
             .uri("/users/1")
+            .insert_header(("Content-type", "application/json"))
+            .set_payload("{\"name\": \"User2\"}")
             .to_request();
         let user: User = test::call_and_read_body_json(&app, req).await;
 
-        assert_eq!(User {id: 1, fields: UserFields{ name: "User1".to_string() }}, user);
+        assert_eq!(versioned(1, 2, "User2"), timeless(user));
     }
-}
 
+    #[actix_web::test]
+    async fn test_merge_patch_user() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(patch_user),
+        ).await;
+
+        let req = test::TestRequest::patch()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/merge-patch+json"))
+            .set_payload("{\"name\": \"Patched\"}")
+            .to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(User { fields: UserFields { name: "Patched".to_string(), ..seeded(1).fields }, ..versioned(1, 2, "") }, timeless(user));
+    }
+
+    #[actix_web::test]
+    async fn test_json_patch_user() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(patch_user),
+        ).await;
+
+        let req = test::TestRequest::patch()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json-patch+json"))
+            .set_payload(r#"[
+                {"op": "test", "path": "/name", "value": "User1"},
+                {"op": "replace", "path": "/name", "value": "Patched"}
+            ]"#)
+            .to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(User { fields: UserFields { name: "Patched".to_string(), ..seeded(1).fields }, ..versioned(1, 2, "") }, timeless(user));
+    }
+
+    #[actix_web::test]
+    async fn test_json_patch_failed_test_problem() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data.clone())
+                .service(patch_user)
+                .service(get_user_by_id),
+        ).await;
+
+        let req = test::TestRequest::patch()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json-patch+json"))
+            .set_payload(r#"[
+                {"op": "test", "path": "/name", "value": "Someone"},
+                {"op": "replace", "path": "/name", "value": "Patched"}
+            ]"#)
+            .to_request();
+        let problem: Problem = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("/problems/patch-test-failed", problem.problem_type);
+        assert_eq!(409, problem.status);
+
+        let req = test::TestRequest::get().uri("/users/1").to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("User1", user.fields.name);
+    }
+
+    #[actix_web::test]
+    async fn test_json_patch_invalid_result_problem() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(patch_user),
+        ).await;
+
+        let req = test::TestRequest::patch()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json-patch+json"))
+            .set_payload(r#"[{"op": "remove", "path": "/name"}]"#)
+            .to_request();
+        let problem: Problem = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("/problems/unprocessable-patch", problem.problem_type);
+        assert_eq!(422, problem.status);
+    }
+
+    #[actix_web::test]
+    async fn test_patch_unsupported_media_type_problem() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(patch_user),
+        ).await;
+
+        let req = test::TestRequest::patch()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json"))
+            .set_payload("{\"name\": \"Patched\"}")
+            .to_request();
+        let problem: Problem = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("/problems/unsupported-media-type", problem.problem_type);
+        assert_eq!(415, problem.status);
+    }
+
+    #[actix_web::test]
+    async fn test_delete_user_return_minimal() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(delete_user),
+        ).await;
+
+        let req = test::TestRequest::delete()
+            .uri("/users/1")
+            .insert_header(("Prefer", "return=minimal"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NO_CONTENT, resp.status());
+        assert!(test::read_body(resp).await.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_get_user_etag_and_not_modified() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(get_user_by_id),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users/1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("\"1\"", resp.headers().get("etag").unwrap());
+
+        let req = test::TestRequest::get()
+            .uri("/users/1")
+            .insert_header(("If-None-Match", "\"1\""))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::NOT_MODIFIED, resp.status());
+        assert_eq!("\"1\"", resp.headers().get("etag").unwrap());
+        assert!(test::read_body(resp).await.is_empty());
+
+        let req = test::TestRequest::get()
+            .uri("/users/1")
+            .insert_header(("If-None-Match", "\"0\""))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn test_replace_user_if_match() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(replace_user),
+        ).await;
+
+        let req = test::TestRequest::put()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json"))
+            .insert_header(("If-Match", "\"1\""))
+            .set_payload("{\"name\": \"User2\"}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("\"2\"", resp.headers().get("etag").unwrap());
+
+        let req = test::TestRequest::put()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json"))
+            .insert_header(("If-Match", "\"1\""))
+            .set_payload("{\"name\": \"User3\"}")
+            .to_request();
+        let problem: Problem = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("/problems/precondition-failed", problem.problem_type);
+        assert_eq!(412, problem.status);
+
+        let req = test::TestRequest::put()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json"))
+            .insert_header(("If-Match", "W/\"2\""))
+            .set_payload("{\"name\": \"User3\"}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::PRECONDITION_FAILED, resp.status());
+
+        // any listed strong tag may match, weak and malformed ones are skipped
+        let req = test::TestRequest::put()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json"))
+            .insert_header(("If-Match", "\"1\", W/\"3\", \"x\", \"2\""))
+            .set_payload("{\"name\": \"User3\"}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("\"3\"", resp.headers().get("etag").unwrap());
+
+        let req = test::TestRequest::put()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/json"))
+            .insert_header(("If-Match", "\"1\", \"2\""))
+            .set_payload("{\"name\": \"User4\"}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::PRECONDITION_FAILED, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn test_patch_user_if_match_mismatch() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(patch_user),
+        ).await;
+
+        let req = test::TestRequest::patch()
+            .uri("/users/1")
+            .insert_header(("Content-type", "application/merge-patch+json"))
+            .insert_header(("If-Match", "\"7\""))
+            .set_payload("{\"name\": \"Patched\"}")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::PRECONDITION_FAILED, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn test_delete_user_if_match() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(delete_user),
+        ).await;
+
+        let req = test::TestRequest::delete()
+            .uri("/users/1")
+            .insert_header(("If-Match", "\"2\""))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::PRECONDITION_FAILED, resp.status());
+
+        let req = test::TestRequest::delete()
+            .uri("/users/1")
+            .insert_header(("If-Match", "*"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+    }
+
+    #[actix_web::test]
+    async fn test_delete_user() {
+        let inmemory = InMemory {users: 1};
+
+        let dao = create_dao(Some(&inmemory));
+        let user_data = Data::new(dao); 
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(delete_user),
+        ).await;
+
+        let req = test::TestRequest::delete()
+            .uri("/users/1")
+            .to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!(deleted(seeded(1), 2), timeless(user));
+    }
+
+    #[actix_web::test]
+    async fn test_restore_user() {
+        let dao = create_dao(Some(&InMemory {users: 2}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .route("/users", web::get().to(users_list))
+                .service(get_user_by_id)
+                .service(delete_user)
+                .service(restore_user),
+        ).await;
+
+        let req = test::TestRequest::delete().uri("/users/1").to_request();
+        test::call_service(&app, req).await;
+
+        let req = test::TestRequest::get().uri("/users/1").to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::get().uri("/users?page_size=1&include_deleted=true").to_request();
+        let users: PagedResponse<User> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(vec![deleted(seeded(1), 2)], users.items.into_iter().map(timeless).collect::<Vec<User>>());
+        assert_eq!(Some("/users?page=2&page_size=1&sort=id&include_deleted=true".to_string()), users.next);
+
+        let req = test::TestRequest::post()
+            .uri("/users/1/restore")
+            .insert_header(("If-Match", "\"1\""))
+            .to_request();
+        assert_eq!(StatusCode::PRECONDITION_FAILED, test::call_service(&app, req).await.status());
+
+        let req = test::TestRequest::post()
+            .uri("/users/1/restore")
+            .insert_header(("If-Match", "\"2\""))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("\"3\"", resp.headers().get("etag").unwrap());
+
+        let req = test::TestRequest::get().uri("/users/1").to_request();
+        let user: User = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(User { version: 3, ..seeded(1) }, timeless(user));
+    }
+
+    #[actix_web::test]
+    async fn test_create_user_validation_problem() {
+        let dao = create_dao(None);
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .wrap_fn(|req, srv| srv.call(req).map_ok(problem::with_instance))
+                .service(create_user),
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .insert_header(("Content-type", "application/json"))
+            .set_payload("{ \"name\": \"Ab\" }")
+            .to_request();
+        let problem: Problem = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!("/problems/validation-error", problem.problem_type);
+        assert_eq!(400, problem.status);
+        assert_eq!(Some("/users".to_string()), problem.instance);
+        assert_eq!(vec![FieldProblem {
+            field: "name".to_string(),
+            code: "length".to_string(),
+            params: serde_json::from_str("{\"min\": 4, \"max\": 255}").unwrap(),
+            message: "length must be between 4 and 255 characters".to_string(),
+        }], problem.errors);
+    }
+
+    #[actix_web::test]
+    async fn test_create_user_malformed_body_problem() {
+        let dao = create_dao(None);
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(problem::json_config())
+                .wrap_fn(|req, srv| srv.call(req).map_ok(problem::with_instance))
+                .service(create_user),
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users")
+            .insert_header(("Content-type", "application/json"))
+            .set_payload("{ \"nam\": \"User1\" }")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!("application/problem+json", resp.headers().get("content-type").unwrap());
+
+        let problem: Problem = test::read_body_json(resp).await;
+        assert_eq!("/problems/malformed-request", problem.problem_type);
+        assert_eq!(400, problem.status);
+        assert_eq!(Some("/users".to_string()), problem.instance);
+        assert!(problem.detail.contains("missing field `name`"), "{}", problem.detail);
+    }
+
+    #[actix_web::test]
+    async fn test_list_invalid_sort_problem() {
+        let dao = create_dao(None);
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(problem::query_config())
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .route("/users", web::get().to(users_list)),
+        ).await;
+
+        let req = test::TestRequest::get()
+            .uri("/users?sort=email")
+            .to_request();
+        let problem: Problem = test::call_and_read_body_json(&app, req).await;
+
+        assert_eq!("/problems/malformed-request", problem.problem_type);
+        assert!(problem.detail.contains("unknown sort field 'email'"), "{}", problem.detail);
+    }
+
+    #[actix_web::test]
+    async fn test_batch_users_partial_success() {
+        let dao = create_dao(Some(&InMemory {users: 2}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(batch_users),
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users:batch")
+            .set_json(serde_json::json!([
+                { "op": "create", "name": "Newcomer" },
+                { "op": "update", "id": 1, "version": 3, "name": "Renamed" },
+                { "op": "delete", "id": 2 },
+            ]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::MULTI_STATUS, resp.status());
+
+        let batch: BatchResponse = test::read_body_json(resp).await;
+        let statuses: Vec<(usize, u16)> = batch.results.iter().map(|r| (r.index, r.status)).collect();
+        assert_eq!(vec![(0, 201), (1, 412), (2, 200)], statuses);
+        assert_eq!(Some(user(3, "Newcomer")), batch.results[0].user.clone().map(timeless));
+        assert_eq!(Some("/problems/precondition-failed".to_string()), batch.results[1].error.as_ref().map(|p| p.problem_type.clone()));
+        assert_eq!(Some(deleted(seeded(2), 2)), batch.results[2].user.clone().map(timeless));
+    }
+
+    #[actix_web::test]
+    async fn test_batch_users_atomic() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .wrap_fn(|req, srv| srv.call(req).map_ok(problem::with_instance))
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .route("/users", web::get().to(users_list))
+                .service(batch_users),
+        ).await;
+
+        let req = test::TestRequest::post()
+            .uri("/users:batch?atomic=true")
+            .set_json(serde_json::json!([
+                { "op": "create", "name": "Newcomer" },
+                { "op": "create", "name": "User1" },
+            ]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::CONFLICT, resp.status());
+
+        let problem: Problem = test::read_body_json(resp).await;
+        assert_eq!("/problems/already-exists", problem.problem_type);
+        assert_eq!(Some(1), problem.index);
+        assert_eq!(Some("/users:batch".to_string()), problem.instance);
+        assert_eq!("Operation 1 failed, no operation was applied: User exists", problem.detail);
+
+        let req = test::TestRequest::get().uri("/users").to_request();
+        let users: PagedResponse<User> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(1, users.total);
+
+        let req = test::TestRequest::post()
+            .uri("/users:batch?atomic=true")
+            .set_json(serde_json::json!([{ "op": "create", "name": "Newcomer" }]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+
+        let batch: BatchResponse = test::read_body_json(resp).await;
+        assert_eq!(201, batch.results[0].status);
+        assert_eq!(Some(user(2, "Newcomer")), batch.results[0].user.clone().map(timeless));
+    }
+
+    #[actix_web::test]
+    async fn test_batch_users_too_large() {
+        let dao = create_dao(None);
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(batch_users),
+        ).await;
+
+        let ops: Vec<BatchOp> = (0..=MAX_BATCH_SIZE).map(|id| BatchOp::Delete { id: id as u64, version: None }).collect();
+        let req = test::TestRequest::post()
+            .uri("/users:batch")
+            .set_json(ops)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::PAYLOAD_TOO_LARGE, resp.status());
+
+        // refused by size before it is parsed, this body is not even valid JSON
+        let req = test::TestRequest::post()
+            .uri("/users:batch")
+            .insert_header(("Content-type", "application/json"))
+            .set_payload(format!("[{}", " ".repeat(MAX_BATCH_BYTES)))
+            .to_request();
+        let problem: Problem = test::call_and_read_body_json(&app, req).await;
+        assert_eq!("/problems/payload-too-large", problem.problem_type);
+        assert_eq!(413, problem.status);
+    }
+
+    #[actix_web::test]
+    async fn test_search_users() {
+        let dao = create_dao(Some(&InMemory {users: 2}));
+        dao.create(&UserFields { display_name: Some("Alex Smith".to_string()), ..fields("Alexander") }).await.unwrap();
+        dao.create(&fields("Alexandra")).await.unwrap();
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(problem::query_config())
+                .service(search_users)
+                .service(get_user_by_id),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users/search?q=alex&page_size=1").to_request();
+        let hits: PagedResponse<SearchHit> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(2, hits.total);
+        assert_eq!(vec![("Alexander".to_string(), (5.0f32 / 11.0) as f64)],
+            hits.items.into_iter().map(|hit| (hit.user.fields.name, hit.score)).collect::<Vec<_>>());
+        assert_eq!(Some("/users/search?q=alex&page=2&page_size=1".to_string()), hits.next);
+
+        let req = test::TestRequest::get().uri("/users/search?q=").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::BAD_REQUEST, resp.status());
+
+        let req = test::TestRequest::get().uri("/users/search").to_request();
+        assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&app, req).await.status());
+
+        // the offset of the page would overflow
+        let req = test::TestRequest::get().uri(&format!("/users/search?q=alex&page={}", u64::MAX)).to_request();
+        assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn test_users_stream() {
+        let dao = create_dao(Some(&InMemory {users: 3}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(users_stream)
+                .service(get_user_by_id),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users/stream?sort=id:desc&page_size=1").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("application/json", resp.headers().get("content-type").unwrap());
+        let users: Vec<User> = test::read_body_json(resp).await;
+        assert_eq!(vec![3, 2, 1], users.iter().map(|u| u.id).collect::<Vec<_>>());
+
+        let req = test::TestRequest::get()
+            .uri("/users/stream?name_prefix=User2")
+            .insert_header(("Accept", "application/x-ndjson"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!("application/x-ndjson", resp.headers().get("content-type").unwrap());
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(1, body.lines().count());
+        assert_eq!(2, serde_json::from_str::<User>(body.trim_end()).unwrap().id);
+
+        let req = test::TestRequest::get().uri("/users/stream?page=0").to_request();
+        assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn test_export_users_csv() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .service(export_users)
+                .service(get_user_by_id),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/users/export?format=csv").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("text/csv; charset=utf-8", resp.headers().get("content-type").unwrap());
+        assert_eq!("attachment; filename=\"users.csv\"", resp.headers().get("content-disposition").unwrap());
+
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        assert_eq!(2, body.lines().count());
+        assert!(body.contains("\n1,1,User1,user1@example.com,User 1,active,"), "{}", body);
+
+        let req = test::TestRequest::get().uri("/users/export?format=xml").to_request();
+        assert_eq!(StatusCode::BAD_REQUEST, test::call_service(&app, req).await.status());
+    }
+
+    #[actix_web::test]
+    async fn test_bulk_writes_need_admin() {
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(create_dao(None)))
+                .app_data(test_auth())
+                .wrap(auth::Authentication)
+                .service(import_users)
+                .service(batch_users),
+        ).await;
+        let bearer = |roles: &[&str]| ("Authorization", format!("Bearer {}", test_token(1, roles)));
+        let import = |roles: &[&str]| test::TestRequest::post()
+            .uri("/users/import")
+            .insert_header(bearer(roles))
+            .insert_header(("Content-type", "application/x-ndjson"))
+            .set_payload("{\"name\": \"Newcomer\"}\n")
+            .to_request();
+        let batch = |roles: &[&str]| test::TestRequest::post()
+            .uri("/users:batch")
+            .insert_header(bearer(roles))
+            .set_json(serde_json::json!([]))
+            .to_request();
+
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, import(&["writer"])).await.status());
+        assert_eq!(StatusCode::FORBIDDEN, test::call_service(&app, batch(&["writer"])).await.status());
+        assert_eq!(StatusCode::OK, test::call_service(&app, import(&["admin"])).await.status());
+    }
+
+    #[actix_web::test]
+    async fn test_import_users() {
+        let dao = create_dao(Some(&InMemory {users: 1}));
+        let user_data = Data::new(dao);
+
+        let app = test::init_service(
+            App::new()
+                .app_data(user_data)
+                .app_data(Data::new(CursorSigner::new(b"test")))
+                .route("/users", web::get().to(users_list))
+                .service(import_users),
+        ).await;
+
+        let ndjson = "{\"name\": \"Newcomer\"}\n{\"name\": \"User1\"}\n{\"name\": \"ab\"}\n{\"name\": \"Another\"}\n";
+
+        let req = test::TestRequest::post()
+            .uri("/users/import?dry_run=true")
+            .insert_header(("Content-type", "application/x-ndjson"))
+            .set_payload(ndjson)
+            .to_request();
+        let report: ImportReport = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((true, 4, 3, 1), (report.dry_run, report.total, report.imported, report.failed));
+        assert_eq!(3, report.errors[0].line);
+        assert_eq!("/problems/validation-error", report.errors[0].problem.problem_type);
+
+        let req = test::TestRequest::get().uri("/users").to_request();
+        let users: PagedResponse<User> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(1, users.total);
+
+        let req = test::TestRequest::post()
+            .uri("/users/import")
+            .insert_header(("Content-type", "application/x-ndjson"))
+            .set_payload(ndjson)
+            .to_request();
+        let report: ImportReport = test::call_and_read_body_json(&app, req).await;
+        assert_eq!((false, 4, 2, 2), (report.dry_run, report.total, report.imported, report.failed));
+        let failed: Vec<(u64, u16)> = report.errors.iter().map(|e| (e.line, e.problem.status)).collect();
+        assert_eq!(vec![(2, 409), (3, 400)], failed);
+
+        let req = test::TestRequest::get().uri("/users?sort=name").to_request();
+        let users: PagedResponse<User> = test::call_and_read_body_json(&app, req).await;
+        let names: Vec<String> = users.items.into_iter().map(|u| u.fields.name).collect();
+        assert_eq!(vec!["Another", "Newcomer", "User1"], names);
+
+        let req = test::TestRequest::post()
+            .uri("/users/import")
+            .insert_header(("Content-type", "text/plain"))
+            .set_payload(ndjson)
+            .to_request();
+        assert_eq!(StatusCode::UNSUPPORTED_MEDIA_TYPE, test::call_service(&app, req).await.status());
+    }
+}
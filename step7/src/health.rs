@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+use actix_web::rt::time::timeout;
+use serde::Serialize;
+
+use crate::services::UserDAO;
+
+// a hung database must not hang the probe, a check taking longer counts as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+// Flipped once shutdown starts, readiness fails from then on so no new traffic is routed here
+#[derive(Debug, Default)]
+pub struct Readiness {
+    shutting_down: AtomicBool,
+}
+
+impl Readiness {
+    pub fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Status {
+    Up,
+    Down,
+    ShuttingDown,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ComponentHealth {
+    pub status: Status,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HealthReport {
+    pub status: Status,
+    pub components: BTreeMap<&'static str, ComponentHealth>,
+}
+
+impl HealthReport {
+    pub fn is_ready(&self) -> bool {
+        self.status == Status::Up
+    }
+}
+
+pub async fn check_store(dao: &dyn UserDAO) -> ComponentHealth {
+    let start = Instant::now();
+    let error = match timeout(CHECK_TIMEOUT, dao.health()).await {
+        Ok(Ok(())) => None,
+        Ok(Err(err)) => Some(err.to_string()),
+        Err(_) => Some(format!("No answer within {} ms", CHECK_TIMEOUT.as_millis())),
+    };
+    ComponentHealth {
+        status: if error.is_none() { Status::Up } else { Status::Down },
+        latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+        error,
+    }
+}
+
+// ready when every component is up, the components are not checked any more once shutdown started
+pub async fn readiness(readiness: &Readiness, dao: &dyn UserDAO) -> HealthReport {
+    if readiness.is_shutting_down() {
+        return HealthReport { status: Status::ShuttingDown, components: BTreeMap::new() };
+    }
+    let components = BTreeMap::from([("store", check_store(dao).await)]);
+    let status = if components.values().all(|component| component.status == Status::Up) { Status::Up } else { Status::Down };
+    HealthReport { status, components }
+}
@@ -0,0 +1,276 @@
+use std::future::{ready, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::header::{HeaderName, HeaderValue},
+    web::Bytes,
+    Error, HttpMessage,
+};
+use futures::{future::LocalBoxFuture, FutureExt};
+use rand::RngCore;
+use tracing::{field::Empty, Instrument, Span};
+use opentelemetry_sdk::trace::SdkTracer;
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+use crate::auth::Claims;
+use crate::configs::{LogFormat, Logging};
+use crate::telemetry;
+
+pub const REQUEST_ID: &str = "x-request-id";
+
+// log records of the log crate, rbatis SQL statements among them, are forwarded to the subscriber.
+// Spans go to the tracer as well when tracing is configured.
+pub fn init(cfg: &Logging, tracer: Option<SdkTracer>) -> std::io::Result<()> {
+    let filter = EnvFilter::try_new(&cfg.level)
+        .map_err(|err| std::io::Error::other(format!("Invalid logging level {}: {}", cfg.level, err)))?;
+    let fmt = match cfg.format {
+        LogFormat::Pretty => tracing_subscriber::fmt::layer().boxed(),
+        // fields of the enclosing spans go with every event, the request span with its id comes first
+        LogFormat::Json => tracing_subscriber::fmt::layer().json().with_current_span(false).with_span_list(true).boxed(),
+    };
+    tracing_subscriber::registry()
+        .with(fmt)
+        .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        .with(filter)
+        .try_init()
+        .map_err(|err| std::io::Error::other(format!("Logging setup failed: {}", err)))
+}
+
+// Correlation id of the request, as sent by the client in X-Request-Id or generated
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(pub String);
+
+impl RequestId {
+    // ids of clients are kept when short and printable, they end up in every log line
+    fn from_request(req: &ServiceRequest) -> RequestId {
+        let sent = req.headers().get(REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic()));
+        match sent {
+            Some(id) => RequestId(id.to_string()),
+            None => {
+                let mut bytes = [0u8; 16];
+                rand::thread_rng().fill_bytes(&mut bytes);
+                RequestId(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+            },
+        }
+    }
+}
+
+// Middleware running every request in a span with its id, the DAO and SQL logs of the request carry it.
+// Writes an access log line per request and echoes the id in X-Request-Id.
+// The span continues the trace of the caller given in traceparent.
+#[derive(Clone, Default)]
+pub struct RequestLogging;
+
+impl<S, B> Transform<S, ServiceRequest> for RequestLogging
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<InstrumentedBody<B>>;
+    type Error = Error;
+    type Transform = RequestLoggingMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<RequestLoggingMiddleware<S>, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestLoggingMiddleware { service: Rc::new(service) }))
+    }
+}
+
+pub struct RequestLoggingMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestLoggingMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<InstrumentedBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<ServiceResponse<InstrumentedBody<B>>, Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let request_id = RequestId::from_request(&req);
+        req.extensions_mut().insert(request_id.clone());
+        let method = req.method().to_string();
+        let path = req.path().to_string();
+        let route = req.match_pattern();
+        let span = tracing::info_span!("request", request_id = %request_id.0, method = %method, path = %path,
+            otel.name = %format!("{} {}", method, route.as_deref().unwrap_or("unmatched")), otel.kind = "server",
+            http.route = route, http.response.status_code = Empty, otel.status_code = Empty);
+        // fails only without a tracer, the span is a root then
+        let _ = span.set_parent(telemetry::remote_context(req.headers()));
+
+        async move {
+            let start = Instant::now();
+            let res = service.call(req).instrument(span.clone()).await;
+            let latency_ms = start.elapsed().as_secs_f64() * 1000.0;
+            match res {
+                Ok(mut res) => {
+                    let status = res.status().as_u16();
+                    record_status(&span, status);
+                    let subject = res.request().extensions().get::<Claims>().map(|claims| claims.sub.clone());
+                    span.in_scope(|| tracing::info!(target: "access", status, latency_ms, subject = subject.as_deref().unwrap_or("-"), "{} {} {}", method, path, status));
+                    if let Ok(value) = HeaderValue::from_str(&request_id.0) {
+                        res.headers_mut().insert(HeaderName::from_static(REQUEST_ID), value);
+                    }
+                    Ok(res.map_body(|_, body| InstrumentedBody { body: Box::pin(body), span }))
+                },
+                Err(err) => {
+                    let status = err.as_response_error().status_code().as_u16();
+                    record_status(&span, status);
+                    span.in_scope(|| tracing::info!(target: "access", status, latency_ms, subject = "-", "{} {} {}", method, path, status));
+                    Err(err)
+                },
+            }
+        }.boxed_local()
+    }
+}
+
+fn record_status(span: &Span, status: u16) {
+    span.record("http.response.status_code", status);
+    if status >= 500 {
+        span.record("otel.status_code", "ERROR");
+    }
+}
+
+// Body polled in the request span, streamed responses read the store after the handler returned
+pub struct InstrumentedBody<B> {
+    body: Pin<Box<B>>,
+    span: Span,
+}
+
+impl<B: MessageBody> MessageBody for InstrumentedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let this = self.get_mut();
+        let _entered = this.span.enter();
+        this.body.as_mut().poll_next(cx)
+    }
+}
+
+// log or span lines written by tests, one JSON object per line
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct Captured(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+#[cfg(test)]
+impl std::io::Write for Captured {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl Captured {
+    pub fn lines(&self) -> Vec<serde_json::Value> {
+        let buf = self.0.lock().unwrap();
+        String::from_utf8_lossy(&buf).lines().map(|line| serde_json::from_str(line).unwrap()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web::{self, Data}, App};
+    use serde_json::Value;
+
+    use crate::auth::{test_auth, test_token, Authentication};
+    use crate::configs::Sqlite;
+    use crate::handlers;
+    use crate::services::{UserDAO, UserSqliteDAO};
+    use super::{Captured, RequestLogging, REQUEST_ID};
+
+    #[actix_web::test]
+    async fn test_request_id() {
+        let app = test::init_service(
+            App::new()
+                .wrap(RequestLogging)
+                .route("/", web::get().to(|| async { "ok" })),
+        ).await;
+
+        let req = test::TestRequest::get().uri("/").insert_header((REQUEST_ID, "req-42")).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("req-42", resp.headers().get(REQUEST_ID).unwrap());
+
+        // generated when missing or unfit for logs
+        for sent in [None, Some("has spaces"), Some(&"x".repeat(129)[..])] {
+            let mut req = test::TestRequest::get().uri("/");
+            if let Some(sent) = sent {
+                req = req.insert_header((REQUEST_ID, sent));
+            }
+            let resp = test::call_service(&app, req.to_request()).await;
+            let id = resp.headers().get(REQUEST_ID).unwrap().to_str().unwrap();
+            assert_eq!(32, id.len());
+            assert!(id.bytes().all(|b| b.is_ascii_hexdigit()));
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_sql_and_access_logs_carry_request_id() {
+        let _ = tracing_log::LogTracer::init();
+        let captured = Captured::default();
+        let writer = captured.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_current_span(false)
+            .with_span_list(true)
+            .with_writer(move || writer.clone())
+            .with_env_filter("info")
+            .finish();
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let path = std::env::temp_dir().join(format!("logging-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let dao: Box<dyn UserDAO> = Box::new(UserSqliteDAO::new(&Sqlite { path: path.to_string_lossy().to_string() }).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(dao))
+                .app_data(test_auth())
+                .wrap(Authentication)
+                .wrap(RequestLogging)
+                .service(handlers::get_user_by_id),
+        ).await;
+
+        let req = test::TestRequest::get()
+            .uri("/users/1")
+            .insert_header(("Authorization", format!("Bearer {}", test_token(7, &["reader"]))))
+            .insert_header((REQUEST_ID, "req-sql"))
+            .to_request();
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, req).await.status());
+
+        let lines = captured.lines();
+        let of_request: Vec<&Value> = lines.iter().filter(|line| line["spans"][0]["request_id"] == "req-sql").collect();
+        assert!(of_request.iter().any(|line| line["fields"]["message"].as_str().unwrap_or("").contains("[rbatis]")), "{:#?}", lines);
+        // the bound arguments are left out at the default level
+        assert!(lines.iter().all(|line| !line["fields"]["message"].as_str().unwrap_or("").contains("Args")), "{:#?}", lines);
+        let access = of_request.iter().find(|line| line["target"] == "access").unwrap();
+        assert_eq!(404, access["fields"]["status"]);
+        assert_eq!("7", access["fields"]["subject"]);
+        assert_eq!("GET", access["spans"][0]["method"]);
+        assert!(access["fields"]["latency_ms"].is_number());
+        let _ = std::fs::remove_file(&path);
+    }
+}
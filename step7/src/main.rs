@@ -1,23 +1,87 @@
-use actix_web::{App, HttpServer, web::{Data, self}};
+use std::sync::Arc;
+
+use actix_web::{App, HttpServer, dev::Service, web::{Data, self}};
+use futures::TryFutureExt;
+use opentelemetry::trace::TracerProvider;
 use configs::{Configuration, Store};
-use services::{UserInMemoryDAO, UserDAO, UserDbDAO};
+use rbatis::rbatis::Rbatis;
+use services::{UserInMemoryDAO, UserDAO, UserDbDAO, UserSqliteDAO};
+use api_keys::{ApiKeyDAO, ApiKeyDbDAO, ApiKeyInMemoryDAO, ApiKeySqliteDAO};
+use credentials::{CredentialDAO, CredentialDbDAO, CredentialInMemoryDAO, CredentialSqliteDAO};
 
 
 mod model;
+mod api_keys;
 mod handlers;
+mod health;
 mod services;
+mod auth;
 mod configs;
+mod credentials;
+mod cursor;
+mod metrics;
+mod logging;
+mod migrations;
+mod problem;
+mod purge;
+mod rate_limit;
+mod search;
+mod shutdown;
+mod telemetry;
+mod transfer;
+#[cfg(test)]
+mod conformance;
+
+// API keys and credentials are kept next to the users, SQL stores share one pool
+struct Stores {
+    users: Box<dyn UserDAO + 'static>,
+    api_keys: Box<dyn ApiKeyDAO + 'static>,
+    credentials: Box<dyn CredentialDAO + 'static>,
+    // backend label of the DAO metrics
+    backend: &'static str,
+    pool: Option<Arc<Rbatis>>,
+}
 
-async fn create_dao(store: &Store) -> std::io::Result<Box<dyn UserDAO + 'static>> {
-    match &store {
-        &Store { inmemory: Some(im), db: None } => 
-            Ok(Box::new(UserInMemoryDAO::new(Some(&im)))),
-        &Store { inmemory: None, db: Some(dbcfg) } => 
-            Ok(Box::new(UserDbDAO::new(&dbcfg).await)), 
-        &Store { inmemory: None, db: None } => 
-            Ok(Box::new(UserInMemoryDAO::new(store.inmemory.as_ref()))),
-        &Store { inmemory: Some(_), db: Some(_) } => 
-            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Both inmemory and db config present")) 
+fn in_memory_stores(cfg: Option<&configs::InMemory>) -> Stores {
+    Stores {
+        users: Box::new(UserInMemoryDAO::new(cfg)),
+        api_keys: Box::new(ApiKeyInMemoryDAO::default()),
+        credentials: Box::new(CredentialInMemoryDAO::default()),
+        backend: "inmemory",
+        pool: None,
+    }
+}
+
+async fn create_dao(store: &Store) -> std::io::Result<Stores> {
+    match store {
+        Store { inmemory: Some(im), db: None, sqlite: None } => 
+            Ok(in_memory_stores(Some(im))),
+        Store { inmemory: None, db: Some(dbcfg), sqlite: None } => {
+            migrations::migrate(dbcfg).await
+                .map_err(|err| std::io::Error::other(format!("Migration error: {}", err)))?;
+            let dao = UserDbDAO::new(dbcfg).await;
+            Ok(Stores {
+                api_keys: Box::new(ApiKeyDbDAO::new(dao.pool())),
+                credentials: Box::new(CredentialDbDAO::new(dao.pool())),
+                backend: "postgres",
+                pool: Some(dao.pool()),
+                users: Box::new(dao),
+            })
+        },
+        Store { inmemory: None, db: None, sqlite: Some(sqlite) } => {
+            let dao = UserSqliteDAO::new(sqlite).await;
+            Ok(Stores {
+                api_keys: Box::new(ApiKeySqliteDAO::new(dao.pool()).await),
+                credentials: Box::new(CredentialSqliteDAO::new(dao.pool()).await),
+                backend: "sqlite",
+                pool: Some(dao.pool()),
+                users: Box::new(dao),
+            })
+        },
+        Store { inmemory: None, db: None, sqlite: None } => 
+            Ok(in_memory_stores(store.inmemory.as_ref())),
+        _ => 
+            Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Only one of inmemory, db and sqlite configs allowed")) 
     }
 }
 
@@ -27,27 +91,138 @@ async fn main() -> std::io::Result<()> {
     let cfg_result = &Configuration::load_from_file("./application.yaml");
     match cfg_result {
         Err(load_err) => {
-            println!("Load config error {:#?}", &load_err);
+            logging::init(&configs::Logging::default(), None)?;
+            log::error!("Load config error {:#?}", &load_err);
             Ok(())
         },
         Ok(cfg) => {
-            let store = cfg.store.as_ref().unwrap_or(&Store {inmemory: None, db: None});
-            let dao = create_dao(store).await?; 
-            
-            let user_data = Data::new(dao);
+            let tracer_provider = cfg.tracing.as_ref().map(telemetry::tracer_provider).transpose()?;
+            logging::init(&cfg.logging.clone().unwrap_or_default(), tracer_provider.as_ref().map(|provider| provider.tracer("users")))?;
+            let store = cfg.store.as_ref().unwrap_or(&Store {inmemory: None, db: None, sqlite: None});
+            let stores = create_dao(store).await?; 
+
+            let metrics = match &cfg.metrics {
+                Some(metrics_cfg) => Some(Arc::new(metrics::Metrics::new(metrics_cfg, stores.pool.clone())
+                    .map_err(|err| std::io::Error::other(format!("Metrics error: {}", err)))?)),
+                None => None,
+            };
+            let mut users: Box<dyn UserDAO> = match &metrics {
+                Some(metrics) => Box::new(metrics::MeteredUserDAO::new(stores.users, stores.backend, metrics.clone())),
+                None => stores.users,
+            };
+            if tracer_provider.is_some() {
+                users = Box::new(telemetry::TracedUserDAO::new(users, stores.backend));
+            }
+            let user_data = Data::new(users);
+            let key_data = Data::new(stores.api_keys);
+            let credential_data = Data::new(stores.credentials);
+            let mut tasks = shutdown::Tasks::default();
+            if let Some(purge_cfg) = &cfg.purge {
+                tasks.add(purge::spawn(user_data.clone(), purge_cfg, tasks.cancellation()));
+            }
+            if let (Some(metrics), Some(metrics_cfg)) = (&metrics, &cfg.metrics) {
+                tasks.add(metrics::spawn_user_count(metrics.clone(), user_data.clone(), metrics_cfg, tasks.cancellation()));
+            }
+            let readiness = Data::new(health::Readiness::default());
+            let legacy_update_route = cfg.server.legacy_update_route;
+            let cursors = Data::new(cursor::CursorSigner::from_config(&cfg.server));
+            let jwt_auth = match &cfg.security {
+                Some(security) => Some(Data::new(auth::JwtAuth::from_config(security)?)),
+                None => {
+                    // keys would be taken for credentials while nothing checks them
+                    let keys = key_data.list().await
+                        .map_err(|err| std::io::Error::other(format!("API keys not read: {}", err)))?;
+                    if !keys.is_empty() {
+                        return Err(std::io::Error::other("API keys are stored but no security section is configured, add one to check them"));
+                    }
+                    log::warn!("No security section configured, the API is open to anyone");
+                    None
+                },
+            };
+            let rate_limit_store = Arc::new(rate_limit::InProcessStore::default());
+            let rate_limiter = rate_limit::RateLimiter::from_config(cfg.rate_limit.as_ref(), rate_limit_store.clone())?;
+            let address_limiter = rate_limit::RateLimiter::per_address(cfg.rate_limit.as_ref(), rate_limit_store)?;
+            let password_login = match &cfg.security {
+                Some(security) => credentials::PasswordLogin::from_config(security)?.map(Data::new),
+                None => None,
+            };
 
-            HttpServer::new(move || {
+            let app_readiness = readiness.clone();
+            let in_flight = shutdown::InFlight::default();
+            let app_in_flight = in_flight.clone();
+            let server = HttpServer::new(move || {
                 App::new()
                     .app_data(user_data.clone())
+                    .app_data(app_readiness.clone())
+                    .app_data(key_data.clone())
+                    .app_data(credential_data.clone())
+                    .app_data(cursors.clone())
+                    .app_data(problem::json_config())
+                    .app_data(problem::query_config())
+                    .app_data(problem::path_config())
+                    .wrap_fn(|req, srv| srv.call(req).map_ok(problem::with_instance))
+                    // inside authentication, the buckets of signed in clients are keyed by token subject
+                    .wrap(rate_limiter.clone())
+                    .wrap(auth::Authentication)
+                    // outside authentication, guessing tokens and API keys is limited too
+                    .wrap(address_limiter.clone())
+                    // outside authentication and the limiters, rejected and unauthenticated requests are counted too
+                    .wrap(metrics::RequestMetrics::new(metrics.clone()))
+                    // around everything but the in-flight count, every log line of a request carries its id
+                    .wrap(logging::RequestLogging)
+                    // outermost, shutdown waits for the requests counted here
+                    .wrap(app_in_flight.clone())
+                    .configure(|c| if let Some(jwt_auth) = &jwt_auth {
+                        c.app_data(jwt_auth.clone());
+                    })
+                    .service(handlers::health_live)
+                    .service(handlers::health_ready)
                     .route("/users", web::get().to(handlers::users_list))
+                    // ahead of users/{id}, which would take search, stream, export and import as ids
+                    .service(handlers::search_users)
+                    .service(handlers::users_stream)
+                    .service(handlers::export_users)
+                    .service(handlers::import_users)
                     .service(handlers::get_user_by_id)
                     .service(handlers::create_user)
-                    .service(handlers::update_user)
+                    .service(handlers::replace_user)
+                    .service(handlers::patch_user)
                     .service(handlers::delete_user)
+                    .service(handlers::restore_user)
+                    .service(handlers::batch_users)
+                    .service(handlers::create_api_key)
+                    .service(handlers::list_api_keys)
+                    .service(handlers::delete_api_key)
+                    .configure(|c| if let Some(metrics) = &metrics {
+                        c.app_data(Data::from(metrics.clone()))
+                            .service(handlers::metrics);
+                    })
+                    .configure(|c| if let Some(password_login) = &password_login {
+                        c.app_data(password_login.clone())
+                            .service(handlers::login)
+                            .service(handlers::refresh_token)
+                            .service(handlers::logout)
+                            .service(handlers::set_password);
+                    })
+                    .configure(|c| if legacy_update_route {
+                        c.service(handlers::update_user);
+                    })
             })
             .bind((cfg.server.host.clone().as_str(), cfg.server.port))?
-            .run()
-            .await
+            .disable_signals()
+            .run();
+
+            shutdown::Shutdown::from_config(&cfg.server)
+                .serve(server, shutdown::signal()?, readiness, in_flight, tasks, stores.pool)
+                .await?;
+
+            // the spans still batched leave before exit
+            if let Some(provider) = tracer_provider {
+                if let Err(err) = provider.shutdown() {
+                    log::warn!("Tracer shutdown failed: {}", err);
+                }
+            }
+            Ok(())
         }
     }
- }
\ No newline at end of file
+ }
@@ -0,0 +1,379 @@
+use std::future::{ready, Future, Ready};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    rt,
+    web::Data,
+    Error,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{future::{select, Either, LocalBoxFuture}, stream::BoxStream, FutureExt, StreamExt};
+use prometheus::{Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use rbatis::core::db::DBPool;
+use rbatis::rbatis::Rbatis;
+
+use crate::configs;
+use crate::model::{BatchError, BatchOp, Cursor, Page, SearchHit, SearchQuery, User, UserDAOError, UserFields, UsersQuery};
+use crate::services::UserDAO;
+use crate::shutdown::Cancellation;
+
+// route label of requests no pattern matched, raw paths would make a series per probed url
+const UNMATCHED: &str = "unmatched";
+
+// Metrics of the service in their own registry, rendered in Prometheus text format by GET /metrics
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    dao_operations: IntCounterVec,
+    dao_duration: HistogramVec,
+    users: IntGauge,
+    pool_connections: IntGauge,
+    pool_idle: IntGauge,
+    // pool of the SQL stores, none for the in-memory store
+    pool: Option<Arc<Rbatis>>,
+}
+
+impl Metrics {
+    pub fn new(cfg: &configs::Metrics, pool: Option<Arc<Rbatis>>) -> Result<Metrics, prometheus::Error> {
+        let buckets = cfg.latency_buckets.clone();
+        let metrics = Metrics {
+            registry: Registry::new(),
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "HTTP requests by method, route pattern and status"),
+                &["method", "route", "status"])?,
+            request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP request latency by method and route pattern").buckets(buckets.clone()),
+                &["method", "route"])?,
+            dao_operations: IntCounterVec::new(
+                Opts::new("dao_operations_total", "UserDAO calls by backend, operation and outcome"),
+                &["backend", "operation", "outcome"])?,
+            dao_duration: HistogramVec::new(
+                HistogramOpts::new("dao_operation_duration_seconds", "UserDAO call latency by backend and operation").buckets(buckets),
+                &["backend", "operation"])?,
+            users: IntGauge::new("users", "Users that are not soft deleted")?,
+            pool_connections: IntGauge::new("db_pool_connections", "Open connections of the database pool")?,
+            pool_idle: IntGauge::new("db_pool_idle_connections", "Idle connections of the database pool")?,
+            pool,
+        };
+        metrics.registry.register(Box::new(metrics.requests.clone()))?;
+        metrics.registry.register(Box::new(metrics.request_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.dao_operations.clone()))?;
+        metrics.registry.register(Box::new(metrics.dao_duration.clone()))?;
+        metrics.registry.register(Box::new(metrics.users.clone()))?;
+        if metrics.pool.is_some() {
+            metrics.registry.register(Box::new(metrics.pool_connections.clone()))?;
+            metrics.registry.register(Box::new(metrics.pool_idle.clone()))?;
+        }
+        Ok(metrics)
+    }
+
+    pub fn observe_request(&self, method: &str, route: &str, status: u16, elapsed: Duration) {
+        self.requests.with_label_values(&[method, route, &status.to_string()]).inc();
+        self.request_duration.with_label_values(&[method, route]).observe(elapsed.as_secs_f64());
+    }
+
+    pub fn observe_dao(&self, backend: &str, operation: &str, ok: bool, elapsed: Duration) {
+        let outcome = if ok { "ok" } else { "error" };
+        self.dao_operations.with_label_values(&[backend, operation, outcome]).inc();
+        self.dao_duration.with_label_values(&[backend, operation]).observe(elapsed.as_secs_f64());
+    }
+
+    // the user count is a list call like any other, counted by spawn_user_count and not per scrape
+    pub async fn count_users(&self, dao: &dyn UserDAO) -> Result<(), UserDAOError> {
+        let page = dao.list(&UsersQuery { page_size: 1, ..UsersQuery::default() }).await?;
+        self.users.set(page.total as i64);
+        Ok(())
+    }
+
+    // scrapes need no token, rendering reads nothing from the store
+    pub fn render(&self) -> Result<String, UserDAOError> {
+        if let Some((size, idle)) = self.pool.as_deref().and_then(pool_stats) {
+            self.pool_connections.set(i64::from(size));
+            self.pool_idle.set(idle as i64);
+        }
+
+        let mut buffer = vec![];
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)
+            .map_err(|err| UserDAOError::Storage(Box::new(err)))?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+}
+
+// counts the users right away and then every interval, until cancelled
+pub fn spawn_user_count(metrics: Arc<Metrics>, dao: Data<Box<dyn UserDAO>>, cfg: &configs::Metrics, mut cancellation: Cancellation) -> rt::task::JoinHandle<()> {
+    let period = Duration::from_secs(cfg.users_interval_secs.max(1));
+
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(period);
+        loop {
+            let tick = Box::pin(interval.tick());
+            let cancelled = Box::pin(cancellation.cancelled());
+            if let Either::Right(_) = select(tick, cancelled).await {
+                return;
+            }
+            if let Err(err) = metrics.count_users(dao.as_ref().as_ref()).await {
+                log::warn!("Users not counted for metrics: {}", err);
+            }
+        }
+    })
+}
+
+fn pool_stats(rb: &Rbatis) -> Option<(u32, usize)> {
+    match rb.get_pool().ok()? {
+        DBPool::Postgres(pool, _) => Some((pool.size(), pool.num_idle())),
+        DBPool::Sqlite(pool, _) => Some((pool.size(), pool.num_idle())),
+        _ => None,
+    }
+}
+
+// Middleware counting and timing requests by their route pattern, passes everything when metrics are off
+#[derive(Clone)]
+pub struct RequestMetrics {
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl RequestMetrics {
+    pub fn new(metrics: Option<Arc<Metrics>>) -> RequestMetrics {
+        RequestMetrics { metrics }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RequestMetrics
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Transform = RequestMetricsMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<RequestMetricsMiddleware<S>, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RequestMetricsMiddleware { service: Rc::new(service), metrics: self.metrics.clone() }))
+    }
+}
+
+pub struct RequestMetricsMiddleware<S> {
+    service: Rc<S>,
+    metrics: Option<Arc<Metrics>>,
+}
+
+impl<S, B> Service<ServiceRequest> for RequestMetricsMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<ServiceResponse<B>, Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let metrics = match &self.metrics {
+            Some(metrics) => metrics.clone(),
+            None => return async move { service.call(req).await }.boxed_local(),
+        };
+        async move {
+            let method = req.method().to_string();
+            let route = req.match_pattern().unwrap_or_else(|| UNMATCHED.to_string());
+            let start = Instant::now();
+            let res = service.call(req).await;
+            let status = match &res {
+                Ok(res) => res.status(),
+                Err(err) => err.as_response_error().status_code(),
+            };
+            metrics.observe_request(&method, &route, status.as_u16(), start.elapsed());
+            res
+        }.boxed_local()
+    }
+}
+
+// UserDAO recording every call of the wrapped store, backend tells the stores apart
+pub struct MeteredUserDAO {
+    inner: Box<dyn UserDAO>,
+    backend: &'static str,
+    metrics: Arc<Metrics>,
+}
+
+impl MeteredUserDAO {
+    pub fn new(inner: Box<dyn UserDAO>, backend: &'static str, metrics: Arc<Metrics>) -> MeteredUserDAO {
+        MeteredUserDAO { inner, backend, metrics }
+    }
+
+    async fn observe<T, E>(&self, operation: &str, call: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+        let start = Instant::now();
+        let result = call.await;
+        self.metrics.observe_dao(self.backend, operation, result.is_ok(), start.elapsed());
+        result
+    }
+}
+
+#[async_trait]
+impl UserDAO for MeteredUserDAO {
+    async fn list(&self, query: &UsersQuery) -> Result<Page<User>, UserDAOError> {
+        self.observe("list", self.inner.list(query)).await
+    }
+
+    // recorded once the stream ends, streams dropped by gone clients are not
+    fn list_stream(&self, query: &UsersQuery) -> BoxStream<'static, Result<User, UserDAOError>> {
+        let mut users = self.inner.list_stream(query);
+        let metrics = self.metrics.clone();
+        let backend = self.backend;
+        let start = Instant::now();
+        async_stream::stream! {
+            let mut ok = true;
+            while let Some(user) = users.next().await {
+                ok &= user.is_ok();
+                yield user;
+            }
+            metrics.observe_dao(backend, "list_stream", ok, start.elapsed());
+        }.boxed()
+    }
+
+    async fn list_after(&self, query: &UsersQuery, after: Option<&Cursor>, limit: u64) -> Result<Vec<User>, UserDAOError> {
+        self.observe("list_after", self.inner.list_after(query, after, limit)).await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Page<SearchHit>, UserDAOError> {
+        self.observe("search", self.inner.search(query)).await
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<User, UserDAOError> {
+        self.observe("find_by_id", self.inner.find_by_id(id)).await
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<User, UserDAOError> {
+        self.observe("find_by_name", self.inner.find_by_name(name)).await
+    }
+
+    async fn create(&self, fields: &UserFields) -> Result<User, UserDAOError> {
+        self.observe("create", self.inner.create(fields)).await
+    }
+
+    async fn update(&self, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        self.observe("update", self.inner.update(id, fields, expected_versions)).await
+    }
+
+    async fn delete_by_id(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        self.observe("delete_by_id", self.inner.delete_by_id(id, expected_versions)).await
+    }
+
+    async fn restore(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        self.observe("restore", self.inner.restore(id, expected_versions)).await
+    }
+
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        self.observe("purge_deleted", self.inner.purge_deleted(before)).await
+    }
+
+    async fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Result<User, UserDAOError>>, UserDAOError> {
+        self.observe("batch", self.inner.batch(ops)).await
+    }
+
+    async fn batch_atomic(&self, ops: &[BatchOp]) -> Result<Vec<User>, BatchError> {
+        self.observe("batch_atomic", self.inner.batch_atomic(ops)).await
+    }
+
+    async fn health(&self) -> Result<(), UserDAOError> {
+        self.observe("health", self.inner.health()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use actix_web::{http::StatusCode, rt, test, web::Data, App};
+    use futures::StreamExt;
+
+    use crate::auth::{test_auth, test_token, Authentication};
+    use crate::configs::{self, InMemory, Sqlite};
+    use crate::handlers;
+    use crate::model::UsersQuery;
+    use crate::services::{UserDAO, UserInMemoryDAO, UserSqliteDAO};
+    use crate::shutdown::Tasks;
+    use super::{spawn_user_count, MeteredUserDAO, Metrics, RequestMetrics};
+
+    #[actix_web::test]
+    async fn test_metrics_endpoint() {
+        let metrics = Arc::new(Metrics::new(&configs::Metrics::default(), None).unwrap());
+        let dao: Box<dyn UserDAO> = Box::new(MeteredUserDAO::new(Box::new(UserInMemoryDAO::new(Some(&InMemory { users: 3 }))), "inmemory", metrics.clone()));
+        let dao = Data::new(dao);
+        let tasks = Tasks::default();
+        let counting = spawn_user_count(metrics.clone(), dao.clone(), &configs::Metrics::default(), tasks.cancellation());
+        let app = test::init_service(
+            App::new()
+                .app_data(dao)
+                .app_data(Data::from(metrics.clone()))
+                .app_data(test_auth())
+                .wrap(Authentication)
+                .wrap(RequestMetrics::new(Some(metrics)))
+                .service(handlers::get_user_by_id)
+                .service(handlers::metrics),
+        ).await;
+        let get = |uri: &str| test::TestRequest::get()
+            .uri(uri)
+            .insert_header(("Authorization", format!("Bearer {}", test_token(1, &["reader"]))))
+            .to_request();
+
+        assert_eq!(StatusCode::OK, test::call_service(&app, get("/users/1")).await.status());
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, get("/users/7")).await.status());
+        assert_eq!(StatusCode::NOT_FOUND, test::call_service(&app, get("/nothing/here")).await.status());
+        let anonymous = test::TestRequest::get().uri("/users/1").to_request();
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, anonymous).await.status());
+
+        // scraped without a token, the users were counted once by the task however often it is scraped
+        rt::time::sleep(Duration::from_millis(50)).await;
+        test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/metrics").to_request()).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!(prometheus::TEXT_FORMAT, resp.headers().get("content-type").unwrap());
+        let body = String::from_utf8(test::read_body(resp).await.to_vec()).unwrap();
+        for line in [
+            r#"http_requests_total{method="GET",route="/users/{id}",status="200"} 1"#,
+            r#"http_requests_total{method="GET",route="/users/{id}",status="404"} 1"#,
+            r#"http_requests_total{method="GET",route="/users/{id}",status="401"} 1"#,
+            r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#,
+            r#"http_request_duration_seconds_count{method="GET",route="/users/{id}"} 3"#,
+            r#"dao_operations_total{backend="inmemory",operation="find_by_id",outcome="ok"} 1"#,
+            r#"dao_operations_total{backend="inmemory",operation="find_by_id",outcome="error"} 1"#,
+            r#"dao_operation_duration_seconds_count{backend="inmemory",operation="find_by_id"} 2"#,
+            r#"dao_operations_total{backend="inmemory",operation="list",outcome="ok"} 1"#,
+            "users 3",
+        ] {
+            assert!(body.lines().any(|l| l == line), "{} missing in\n{}", line, body);
+        }
+        assert!(!body.contains("db_pool_connections"));
+        counting.abort();
+    }
+
+    #[actix_web::test]
+    async fn test_list_stream_and_pool() {
+        let path = std::env::temp_dir().join(format!("metrics-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sqlite = UserSqliteDAO::new(&Sqlite { path: path.to_string_lossy().to_string() }).await;
+        let metrics = Arc::new(Metrics::new(&configs::Metrics::default(), Some(sqlite.pool())).unwrap());
+        let dao = MeteredUserDAO::new(Box::new(sqlite), "sqlite", metrics.clone());
+
+        // recorded once the stream is drained
+        let users: Vec<_> = dao.list_stream(&UsersQuery::default()).collect().await;
+        assert!(users.iter().all(Result::is_ok));
+
+        metrics.count_users(&dao).await.unwrap();
+        let body = metrics.render().unwrap();
+        assert!(body.contains(r#"dao_operations_total{backend="sqlite",operation="list_stream",outcome="ok"} 1"#), "{}", body);
+        assert!(body.contains(r#"dao_operations_total{backend="sqlite",operation="list",outcome="ok"} 1"#), "{}", body);
+        assert!(body.lines().any(|l| l.starts_with("db_pool_connections ") && l != "db_pool_connections 0"), "{}", body);
+        assert!(body.contains("db_pool_idle_connections "), "{}", body);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,101 @@
+use std::collections::HashSet;
+
+use tokio_postgres::{Config, NoTls};
+
+use crate::configs::{Db, MigrateMode};
+
+type Error = Box<dyn std::error::Error + Send + Sync + 'static>;
+
+mod embedded {
+    use refinery::embed_migrations;
+    embed_migrations!("migrations");
+}
+
+// set field by field, spaces and quotes in a password need no escaping
+fn connection_config(cfg: &Db) -> Config {
+    let mut config = Config::new();
+    config.host(&cfg.host)
+        .port(cfg.port)
+        .dbname(&cfg.db_name)
+        .user(&cfg.user)
+        .password(&cfg.password);
+    config
+}
+
+pub async fn migrate(cfg: &Db) -> Result<(), Error> {
+    if cfg.migrate == MigrateMode::Off {
+        log::info!("Schema migrations are disabled");
+        return Ok(());
+    }
+
+    let (mut client, con) = connection_config(cfg).connect(NoTls).await?;
+
+    tokio::spawn(async move {
+        if let Err(e) = con.await {
+            log::error!("Migration connection error: {}", e);
+        }
+    });
+
+    let runner = embedded::migrations::runner();
+
+    match cfg.migrate {
+        MigrateMode::Auto => {
+            let migration_report = runner.run_async(&mut client).await?;
+
+            for migration in migration_report.applied_migrations() {
+                log::info!(
+                    "Migration Applied -  Name: {}, Version: {}",
+                    migration.name(),
+                    migration.version()
+                );
+            }
+        },
+        MigrateMode::Check => {
+            let applied: HashSet<_> = runner.get_applied_migrations_async(&mut client)
+                .await?
+                .iter()
+                .map(|m| m.version())
+                .collect();
+
+            let pending: Vec<String> = runner.get_migrations()
+                .iter()
+                .filter(|m| !applied.contains(&m.version()))
+                .map(|m| m.to_string())
+                .collect();
+
+            if !pending.is_empty() {
+                return Err(format!("Database schema is behind, pending migrations: {}", pending.join(", ")).into());
+            }
+            log::info!("Database schema is up to date");
+        },
+        MigrateMode::Off => {},
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio_postgres::config::Host;
+
+    use crate::configs::{Db, MigrateMode};
+    use super::connection_config;
+
+    #[test]
+    fn test_connection_config() {
+        let cfg = Db {
+            host: "localhost".to_string(),
+            port: 5433,
+            db_name: "users".to_string(),
+            user: "rw_user".to_string(),
+            password: "it's a pass word=1".to_string(),
+            migrate: MigrateMode::Auto,
+        };
+        let config = connection_config(&cfg);
+        assert_eq!(&[Host::Tcp("localhost".to_string())], config.get_hosts());
+        assert_eq!(&[5433], config.get_ports());
+        assert_eq!(Some("users"), config.get_dbname());
+        assert_eq!(Some("rw_user"), config.get_user());
+        assert_eq!(Some("it's a pass word=1".as_bytes()), config.get_password());
+    }
+}
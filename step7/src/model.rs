@@ -1,11 +1,14 @@
 use actix_web::{ResponseError, HttpResponse, http::StatusCode};
 use lazy_static::lazy_static;
-use rbatis::crud_table;
+use chrono::{DateTime, Utc};
+use rbatis::{crud_table, DateTimeUtc};
 use regex::Regex;
 use serde::{Serialize, Deserialize};
 use validator::{Validate, ValidationErrors, ValidationErrorsKind, ValidationError};
 use std::{error::Error, fmt::Display};
 
+use crate::problem::Problem;
+
 lazy_static! {
   static ref STARTS_WITH_UPPER_LETTER: Regex = Regex::new(r"^[A-Z][a-zA-Z\d_]+$").unwrap();
 }
@@ -15,33 +18,412 @@ lazy_static! {
 pub struct DbUser {
   pub id: u64,
   pub name: String,
+  pub version: u64,
+  pub email: Option<String>,
+  pub display_name: Option<String>,
+  pub status: UserStatus,
+  pub created_at: DateTimeUtc,
+  pub updated_at: DateTimeUtc,
+  pub deleted_at: Option<DateTimeUtc>,
+}
+
+#[crud_table(table_name: "users")]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SqliteUser {
+  pub id: u64,
+  pub name: String,
+  pub version: u64,
+  pub email: Option<String>,
+  pub display_name: Option<String>,
+  pub status: UserStatus,
+  pub created_at: DateTimeUtc,
+  pub updated_at: DateTimeUtc,
+  pub deleted_at: Option<DateTimeUtc>,
 }
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct User {
   pub id: u64,
+
+  // bumped on every update, exposed as ETag for optimistic concurrency
+  pub version: u64,
+
+  // maintained by the storage, never taken from requests
+  pub created_at: DateTime<Utc>,
+  pub updated_at: DateTime<Utc>,
+
+  // set by soft delete, the user can be restored until purged
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub deleted_at: Option<DateTime<Utc>>,
   
   #[serde(flatten)]
   pub fields: UserFields,
 }
 
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Validate)]
+impl From<DbUser> for User {
+  fn from(u: DbUser) -> Self {
+    User {
+      id: u.id,
+      version: u.version,
+      created_at: u.created_at.inner,
+      updated_at: u.updated_at.inner,
+      deleted_at: u.deleted_at.map(|at| at.inner),
+      fields: UserFields { name: u.name, email: u.email, display_name: u.display_name, status: u.status },
+    }
+  }
+}
+
+impl From<SqliteUser> for User {
+  fn from(u: SqliteUser) -> Self {
+    User {
+      id: u.id,
+      version: u.version,
+      created_at: u.created_at.inner,
+      updated_at: u.updated_at.inner,
+      deleted_at: u.deleted_at.map(|at| at.inner),
+      fields: UserFields { name: u.name, email: u.email, display_name: u.display_name, status: u.status },
+    }
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+  #[default]
+  Active,
+  Suspended,
+  // exactly while deleted_at is set, soft delete and restore change both
+  Deleted,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Validate, Default)]
 pub struct UserFields {
   #[validate(length(min = 4, max = 255), non_control_character, regex = "STARTS_WITH_UPPER_LETTER")]
-  pub name: String
+  pub name: String,
+
+  // unique ignoring case
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[validate(email, length(max = 255))]
+  pub email: Option<String>,
+
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  #[validate(length(min = 1, max = 255), non_control_character)]
+  pub display_name: Option<String>,
+
+  #[serde(default)]
+  #[validate(custom = "settable_status")]
+  pub status: UserStatus,
+}
+
+// deleted is set by soft delete along with deleted_at, clients delete with DELETE /users/{id}
+fn settable_status(status: &UserStatus) -> Result<(), ValidationError> {
+  if *status == UserStatus::Deleted {
+    let mut err = ValidationError::new("settable_status");
+    err.message = Some("must be active or suspended, users are deleted with DELETE".into());
+    return Err(err);
+  }
+  Ok(())
+}
+
+impl UserFields {
+  // names are unique as is, emails regardless of case
+  pub fn conflicts_with(&self, other: &UserFields) -> bool {
+    let same_email = match (&self.email, &other.email) {
+      (Some(a), Some(b)) => a.to_lowercase() == b.to_lowercase(),
+      _ => false,
+    };
+    self.name == other.name || same_email
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortField {
+  Id,
+  Name,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SortOrder {
+  Asc,
+  Desc,
+}
+
+// Parsed from query strings like `name` or `name:desc`
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Sort {
+  pub field: SortField,
+  pub order: SortOrder,
+}
+
+impl Default for Sort {
+  fn default() -> Self {
+    Sort { field: SortField::Id, order: SortOrder::Asc }
+  }
 }
 
+impl TryFrom<String> for Sort {
+  type Error = String;
+
+  fn try_from(value: String) -> Result<Self, Self::Error> {
+    let (field, order) = value.split_once(':').unwrap_or((&value, "asc"));
+
+    let field = match field {
+      "id" => SortField::Id,
+      "name" => SortField::Name,
+      _ => return Err(format!("unknown sort field '{}', expected 'id' or 'name'", field)),
+    };
+    let order = match order {
+      "asc" => SortOrder::Asc,
+      "desc" => SortOrder::Desc,
+      _ => return Err(format!("unknown sort order '{}', expected 'asc' or 'desc'", order)),
+    };
+
+    Ok(Sort { field, order })
+  }
+}
 
+impl From<Sort> for String {
+  fn from(sort: Sort) -> Self {
+    let field = match sort.field {
+      SortField::Id => "id",
+      SortField::Name => "name",
+    };
+    match sort.order {
+      SortOrder::Asc => field.to_string(),
+      SortOrder::Desc => format!("{}:desc", field),
+    }
+  }
+}
+
+fn default_page() -> u64 { 1 }
+fn default_page_size() -> u64 { 10 }
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Validate)]
+pub struct UsersQuery {
+  // bounded so that the offset of the page fits, deeper pages are read by cursor
+  #[serde(default = "default_page")]
+  #[validate(range(min = 1, max = 1_000_000))]
+  pub page: u64,
+
+  #[serde(default = "default_page_size")]
+  #[validate(range(min = 1, max = 100))]
+  pub page_size: u64,
+
+  #[serde(default)]
+  pub sort: Sort,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name_prefix: Option<String>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub name_contains: Option<String>,
+
+  // admin view, soft deleted users are hidden otherwise
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub include_deleted: bool,
+
+  // keyset pagination, the signed cursor of the previous page replaces page and page_size
+  #[serde(skip_serializing_if = "Option::is_none")]
+  pub cursor: Option<String>,
+
+  #[serde(skip_serializing_if = "Option::is_none")]
+  #[validate(range(min = 1, max = 100))]
+  pub limit: Option<u64>,
+}
+
+impl UsersQuery {
+  pub fn offset(&self) -> u64 {
+    (self.page - 1) * self.page_size
+  }
+
+  pub fn keyset(&self) -> bool {
+    self.cursor.is_some() || self.limit.is_some()
+  }
+
+  pub fn matches(&self, user: &User) -> bool {
+    let name = &user.fields.name;
+    (self.include_deleted || user.deleted_at.is_none())
+      && self.name_prefix.as_ref().is_none_or(|p| name.starts_with(p.as_str()))
+      && self.name_contains.as_ref().is_none_or(|c| name.contains(c.as_str()))
+  }
+}
+
+impl Default for UsersQuery {
+  fn default() -> Self {
+    UsersQuery {
+      page: default_page(),
+      page_size: default_page_size(),
+      sort: Sort::default(),
+      name_prefix: None,
+      name_contains: None,
+      include_deleted: false,
+      cursor: None,
+      limit: None,
+    }
+  }
+}
+
+// Sort key of the last user of a keyset page, the next page starts after it
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
-pub struct UserDAOError {
-  #[serde(rename(serialize = "error", deserialize = "error"))]
-  pub message: String,
-  #[serde(skip)]
-  pub status: u16
+pub struct Cursor {
+  pub sort: Sort,
+  pub id: u64,
+  // set for name sorts only
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub name: Option<String>,
+}
+
+impl Cursor {
+  pub fn after(user: &User, sort: Sort) -> Cursor {
+    let name = (sort.field == SortField::Name).then(|| user.fields.name.clone());
+    Cursor { sort, id: user.id, name }
+  }
+
+  // whether the user follows the cursor in its sort order
+  pub fn precedes(&self, user: &User) -> bool {
+    let ordering = match (self.sort.field, &self.name) {
+      (SortField::Name, Some(name)) => user.fields.name.as_str().cmp(name).then(user.id.cmp(&self.id)),
+      _ => user.id.cmp(&self.id),
+    };
+    match self.sort.order {
+      SortOrder::Asc => ordering.is_gt(),
+      SortOrder::Desc => ordering.is_lt(),
+    }
+  }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Page<T> {
+  pub items: Vec<T>,
+  pub total: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PagedResponse<T> {
+  pub items: Vec<T>,
+  pub page: u64,
+  pub page_size: u64,
+  pub total: u64,
+  pub next: Option<String>,
+  pub prev: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Validate)]
+pub struct SearchQuery {
+  // matched against name and display name, by similarity or as a substring ignoring case
+  #[validate(length(min = 1, max = 255))]
+  pub q: String,
+
+  // bounded like the page of UsersQuery
+  #[serde(default = "default_page")]
+  #[validate(range(min = 1, max = 1_000_000))]
+  pub page: u64,
+
+  #[serde(default = "default_page_size")]
+  #[validate(range(min = 1, max = 100))]
+  pub page_size: u64,
+}
+
+impl SearchQuery {
+  pub fn offset(&self) -> u64 {
+    (self.page - 1) * self.page_size
+  }
+}
+
+// score is the trigram similarity of the better matching of name and display name, from 0 to 1
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SearchHit {
+  pub score: f64,
+  pub user: User,
+}
+
+// Keyset page, there is no total as counting would scan what the cursor skips
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CursorPage<T> {
+  pub items: Vec<T>,
+  pub limit: u64,
+  // absent on the last page
+  pub next_cursor: Option<String>,
+  pub next: Option<String>,
+}
+
+// One operation of a batch request, tagged by "op"
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOp {
+  Create {
+    #[serde(flatten)]
+    fields: UserFields,
+  },
+  Update {
+    id: u64,
+    // same as If-Match of a single update
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+    #[serde(flatten)]
+    fields: UserFields,
+  },
+  Delete {
+    id: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<u64>,
+  },
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+pub struct BatchQuery {
+  // all operations in one transaction, nothing is applied if one fails
+  #[serde(default)]
+  pub atomic: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatchItemResult {
+  pub index: usize,
+  pub status: u16,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub user: Option<User>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error: Option<Problem>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct BatchResponse {
+  pub results: Vec<BatchItemResult>,
+}
+
+// Failure of an atomic batch, index is None when the transaction itself failed
+#[derive(Debug, PartialEq)]
+pub struct BatchError {
+  pub index: Option<usize>,
+  pub error: UserDAOError,
+}
+
+impl From<rbatis::Error> for BatchError {
+  fn from(err: rbatis::Error) -> Self {
+    BatchError { index: None, error: UserDAOError::from(err) }
+  }
+}
+
+
+type BoxError = Box<dyn Error + Send + Sync + 'static>;
+
+#[derive(Debug)]
+pub enum UserDAOError {
+  NotFound,
+  AlreadyExists,
+  VersionMismatch,
+  Validation(ValidationErrors),
+  Conflict(String),
+  Storage(BoxError),
+  Unavailable(BoxError),
 }
 
 impl UserDAOError {
-  pub fn from_validation_errors(err: &ValidationErrors) -> UserDAOError {
+  fn validation_message(err: &ValidationErrors) -> String {
     
     fn element_type(err: &ValidationErrorsKind) -> String {
       match err {
@@ -59,7 +441,7 @@ impl UserDAOError {
         }
     }
 
-    fn field_errors_str(f: &Vec<ValidationError>) -> String {
+    fn field_errors_str(f: &[ValidationError]) -> String {
       let errs_txt: Vec<&str> = f.iter()
         .map(|e| e.code.as_ref())
         .collect();
@@ -67,81 +449,273 @@ impl UserDAOError {
       errs_txt.join(", ")
     }
 
-    let error_vals: String = err.errors()
+    let mut fields: Vec<(&&str, &ValidationErrorsKind)> = err.errors().iter().collect();
+    fields.sort_by_key(|(f, _)| **f);
+
+    let error_vals: String = fields
       .iter()
       .map(|(f, err)|  format!("{}: '{}' errors: '{}'", element_type(err), f, str(err)))
       .collect::<Vec<String>>()
       .join("# ");
       
-    UserDAOError {message: format!("Validation failed for: {}", error_vals), status: StatusCode::BAD_REQUEST.as_u16() }
+    format!("Validation failed for: {}", error_vals)
+  }
+}
+
+impl From<ValidationErrors> for UserDAOError {
+  fn from(err: ValidationErrors) -> Self {
+    UserDAOError::Validation(err)
+  }
+}
+
+impl From<rbatis::Error> for UserDAOError {
+  fn from(err: rbatis::Error) -> Self {
+    let message = err.to_string();
+    if message.contains("duplicate key value violates unique constraint") || message.contains("UNIQUE constraint failed") {
+      UserDAOError::AlreadyExists
+    } else if message.contains("could not serialize access") || message.contains("deadlock detected") {
+      UserDAOError::Conflict(message)
+    } else if message.contains("pool timed out") || message.contains("error communicating with database") {
+      UserDAOError::Unavailable(Box::new(err))
+    } else {
+      UserDAOError::Storage(Box::new(err))
+    }
   }
 }
 
 impl Display for UserDAOError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "UserDAO error {}", self.message)
+        match self {
+            UserDAOError::NotFound => write!(f, "User not found"),
+            UserDAOError::AlreadyExists => write!(f, "User exists"),
+            UserDAOError::VersionMismatch => write!(f, "User was modified concurrently"),
+            UserDAOError::Validation(e) => write!(f, "{}", UserDAOError::validation_message(e)),
+            UserDAOError::Conflict(s) => write!(f, "{}", s),
+            UserDAOError::Storage(e) => write!(f, "Storage error: {}", e),
+            UserDAOError::Unavailable(e) => write!(f, "Storage unavailable: {}", e),
+        }
+    }
+}
+
+impl Error for UserDAOError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UserDAOError::Validation(e) => Some(e),
+            UserDAOError::Storage(e) => Some(e.as_ref()),
+            UserDAOError::Unavailable(e) => Some(e.as_ref()),
+            UserDAOError::NotFound | UserDAOError::AlreadyExists | UserDAOError::VersionMismatch | UserDAOError::Conflict(_) => None,
+        }
     }
 }
 
-impl Error for UserDAOError {}
+// Storage errors carry driver errors without PartialEq, they are compared by message
+impl PartialEq for UserDAOError {
+  fn eq(&self, other: &Self) -> bool {
+    match (self, other) {
+      (UserDAOError::NotFound, UserDAOError::NotFound) => true,
+      (UserDAOError::AlreadyExists, UserDAOError::AlreadyExists) => true,
+      (UserDAOError::VersionMismatch, UserDAOError::VersionMismatch) => true,
+      (UserDAOError::Validation(a), UserDAOError::Validation(b)) => a == b,
+      (UserDAOError::Conflict(a), UserDAOError::Conflict(b)) => a == b,
+      (UserDAOError::Storage(a), UserDAOError::Storage(b)) => a.to_string() == b.to_string(),
+      (UserDAOError::Unavailable(a), UserDAOError::Unavailable(b)) => a.to_string() == b.to_string(),
+      _ => false,
+    }
+  }
+}
 
 impl ResponseError for UserDAOError {
+
+  fn status_code(&self) -> StatusCode {
+    match self {
+      UserDAOError::NotFound => StatusCode::NOT_FOUND,
+      UserDAOError::AlreadyExists => StatusCode::CONFLICT,
+      UserDAOError::VersionMismatch => StatusCode::PRECONDITION_FAILED,
+      UserDAOError::Validation(_) => StatusCode::BAD_REQUEST,
+      UserDAOError::Conflict(_) => StatusCode::CONFLICT,
+      UserDAOError::Storage(_) => StatusCode::INTERNAL_SERVER_ERROR,
+      UserDAOError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+    }
+  }
   
   fn error_response(&self) -> HttpResponse {
-    let err_json = serde_json::json!({ "error": self.message });
-    HttpResponse::build(StatusCode::from_u16(self.status).unwrap()).json(err_json)
+    Problem::from(self).error_response()
   }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use crate::model::UserFields;
+    use std::error::Error;
+
+    use actix_web::{ResponseError, http::StatusCode};
+
+    use crate::model::{BatchOp, Cursor, UserFields, UserStatus, Sort, SortField, SortOrder, UsersQuery, UserDAOError};
 
     use super::User;
 
 
+  fn user(id: u64, name: &str) -> User {
+    let at = "2022-07-01T10:00:00Z".parse().unwrap();
+    User { id, version: 1, created_at: at, updated_at: at, deleted_at: None, fields: UserFields { name: name.to_string(), ..UserFields::default() } }
+  }
+
   #[test]
   fn test_serialize_user() {
-    let json = serde_json::to_string(&User {
-      id: 1, 
-      fields: UserFields { name: "user".to_string()}
-    }).unwrap();
-    assert_eq!("{\"id\":1,\"name\":\"user\"}", json);
+    let json = serde_json::to_string(&user(1, "user")).unwrap();
+    assert_eq!("{\"id\":1,\"version\":1,\"created_at\":\"2022-07-01T10:00:00Z\",\"updated_at\":\"2022-07-01T10:00:00Z\",\"name\":\"user\",\"status\":\"active\"}", json);
+  }
+
+  #[test]
+  fn test_serialize_user_profile() {
+    let profile = User {
+      fields: UserFields {
+        name: "user".to_string(),
+        email: Some("user@example.com".to_string()),
+        display_name: Some("User".to_string()),
+        status: UserStatus::Suspended,
+      },
+      ..user(1, "user")
+    };
+    let json = serde_json::to_value(&profile).unwrap();
+    assert_eq!(serde_json::json!({
+      "id": 1,
+      "version": 1,
+      "created_at": "2022-07-01T10:00:00Z",
+      "updated_at": "2022-07-01T10:00:00Z",
+      "name": "user",
+      "email": "user@example.com",
+      "display_name": "User",
+      "status": "suspended",
+    }), json);
   }
 
   #[test]
   fn test_serizalize_users_list() {
-    let users = vec![
-      User{id: 1, fields: UserFields { name: "user 1".to_string()}},
-      User{id: 2, fields: UserFields { name: "user 2".to_string()}},
-    ];
+    let users = vec![user(1, "user 1"), user(2, "user 2")];
 
     let json = serde_json::to_string(&users).unwrap();
-    assert_eq!("[{\"id\":1,\"name\":\"user 1\"},{\"id\":2,\"name\":\"user 2\"}]", json);
+    assert_eq!("[{\"id\":1,\"version\":1,\"created_at\":\"2022-07-01T10:00:00Z\",\"updated_at\":\"2022-07-01T10:00:00Z\",\"name\":\"user 1\",\"status\":\"active\"},\
+{\"id\":2,\"version\":1,\"created_at\":\"2022-07-01T10:00:00Z\",\"updated_at\":\"2022-07-01T10:00:00Z\",\"name\":\"user 2\",\"status\":\"active\"}]", json);
   }
 
   #[test]
   fn test_deserialize_user() {
-    let json = "{\"id\":1,\"name\":\"user\"}";
-    let user = serde_json::from_str::<User>(json).unwrap();
-    let expected_user = User {
-      id: 1, 
-      fields: UserFields { name: "user".to_string()}};
+    let json = "{\"id\":1,\"version\":1,\"created_at\":\"2022-07-01T10:00:00Z\",\"updated_at\":\"2022-07-01T10:00:00Z\",\"name\":\"user\"}";
+    let user_from_json = serde_json::from_str::<User>(json).unwrap();
 
-    assert_eq!(expected_user, user);
+    assert_eq!(user(1, "user"), user_from_json);
   }
 
   #[test]
   fn test_deserialize_users_list() {
-    let json ="[{\"id\":1,\"name\":\"user 1\"},{\"id\":2,\"name\":\"user 2\"}]";
+    let json ="[{\"id\":1,\"version\":1,\"created_at\":\"2022-07-01T10:00:00Z\",\"updated_at\":\"2022-07-01T10:00:00Z\",\"name\":\"user 1\"},\
+{\"id\":2,\"version\":1,\"created_at\":\"2022-07-01T10:00:00Z\",\"updated_at\":\"2022-07-01T10:00:00Z\",\"name\":\"user 2\",\"status\":\"active\"}]";
     let users = serde_json::from_str::<Vec<User>>(json).unwrap();
+    assert_eq!(vec![user(1, "user 1"), user(2, "user 2")], users);
+  }
+
+  #[test]
+  fn test_fields_conflict() {
+    let first = UserFields { name: "First".to_string(), email: Some("Mail@Example.com".to_string()), ..UserFields::default() };
+    let same_email = UserFields { name: "Second".to_string(), email: Some("mail@example.COM".to_string()), ..UserFields::default() };
+    let no_email = UserFields { name: "Third".to_string(), ..UserFields::default() };
+
+    assert!(first.conflicts_with(&same_email));
+    assert!(first.conflicts_with(&UserFields { name: "First".to_string(), ..UserFields::default() }));
+    assert!(!first.conflicts_with(&no_email));
+    assert!(!no_email.conflicts_with(&UserFields { name: "Fourth".to_string(), ..UserFields::default() }));
+  }
+
+  #[test]
+  fn test_deserialize_batch_ops() {
+    let json = serde_json::json!([
+      { "op": "create", "name": "Newcomer", "email": "new@example.com" },
+      { "op": "update", "id": 2, "version": 3, "name": "Renamed" },
+      { "op": "delete", "id": 4 },
+    ]);
+    let ops = serde_json::from_value::<Vec<BatchOp>>(json).unwrap();
+
     assert_eq!(vec![
-        User {id: 1, fields: UserFields { name: "user 1".to_string() }},
-        User {id: 2, fields: UserFields { name: "user 2".to_string() }},
-      ], 
-      users
+      BatchOp::Create { fields: UserFields { name: "Newcomer".to_string(), email: Some("new@example.com".to_string()), ..UserFields::default() } },
+      BatchOp::Update { id: 2, version: Some(3), fields: UserFields { name: "Renamed".to_string(), ..UserFields::default() } },
+      BatchOp::Delete { id: 4, version: None },
+    ], ops);
+    assert!(serde_json::from_str::<BatchOp>("{\"op\": \"upsert\", \"id\": 1}").is_err());
+  }
+
+  #[test]
+  fn test_parse_sort() {
+    assert_eq!(Ok(Sort { field: SortField::Name, order: SortOrder::Asc }), Sort::try_from("name".to_string()));
+    assert_eq!(Ok(Sort { field: SortField::Id, order: SortOrder::Desc }), Sort::try_from("id:desc".to_string()));
+    assert!(Sort::try_from("email".to_string()).is_err());
+    assert!(Sort::try_from("id:up".to_string()).is_err());
+  }
+
+  #[test]
+  fn test_cursor_precedes() {
+    let by_name_desc = Sort { field: SortField::Name, order: SortOrder::Desc };
+    let cursor = Cursor::after(&user(5, "Mike"), by_name_desc);
+    assert_eq!(Some("Mike".to_string()), cursor.name);
+
+    assert!(cursor.precedes(&user(9, "Lima")));
+    assert!(cursor.precedes(&user(4, "Mike")));
+    assert!(!cursor.precedes(&user(5, "Mike")));
+    assert!(!cursor.precedes(&user(1, "Oscar")));
+
+    let by_id = Cursor::after(&user(5, "Mike"), Sort::default());
+    assert_eq!(None, by_id.name);
+    assert!(by_id.precedes(&user(6, "Alpha")));
+    assert!(!by_id.precedes(&user(4, "Zulu")));
+  }
+
+  #[test]
+  fn test_deserialize_users_query() {
+    let query = serde_urlencoded::from_str::<UsersQuery>("page=3&sort=name:desc&name_contains=x").unwrap();
+    assert_eq!(UsersQuery {
+        page: 3,
+        sort: Sort { field: SortField::Name, order: SortOrder::Desc },
+        name_contains: Some("x".to_string()),
+        ..UsersQuery::default()
+      },
+      query
     );
+
+    let admin = serde_urlencoded::from_str::<UsersQuery>("include_deleted=true").unwrap();
+    assert!(admin.include_deleted);
+    assert_eq!("page=1&page_size=10&sort=id&include_deleted=true", serde_urlencoded::to_string(&admin).unwrap());
+    assert_eq!("page=1&page_size=10&sort=id", serde_urlencoded::to_string(UsersQuery::default()).unwrap());
   }
 
+  #[test]
+  fn test_error_statuses() {
+    assert_eq!(StatusCode::NOT_FOUND, UserDAOError::NotFound.status_code());
+    assert_eq!(StatusCode::CONFLICT, UserDAOError::AlreadyExists.status_code());
+    assert_eq!(StatusCode::PRECONDITION_FAILED, UserDAOError::VersionMismatch.status_code());
+    assert_eq!(StatusCode::CONFLICT, UserDAOError::Conflict("retry".to_string()).status_code());
+    assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, UserDAOError::Storage("broken".into()).status_code());
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, UserDAOError::Unavailable("down".into()).status_code());
+  }
+
+  #[test]
+  fn test_error_from_rbatis() {
+    let duplicate = rbatis::Error::Database("duplicate key value violates unique constraint \"users_name_key\"".to_string());
+    assert_eq!(UserDAOError::AlreadyExists, UserDAOError::from(duplicate));
+
+    let timeout = UserDAOError::from(rbatis::Error::Database("pool timed out while waiting for an open connection".to_string()));
+    assert_eq!(StatusCode::SERVICE_UNAVAILABLE, timeout.status_code());
+    assert_eq!("pool timed out while waiting for an open connection", timeout.source().unwrap().to_string());
+
+    let other = UserDAOError::from(rbatis::Error::E("syntax error".to_string()));
+    assert_eq!("Storage error: syntax error", other.to_string());
+    assert_eq!("syntax error", other.source().unwrap().to_string());
+  }
+
+  #[test]
+  fn test_validation_error_source() {
+    let fields = UserFields { name: "".to_string(), ..UserFields::default() };
+    let err = UserDAOError::from(validator::Validate::validate(&fields).unwrap_err());
+    assert_eq!(StatusCode::BAD_REQUEST, err.status_code());
+    assert!(err.source().is_some());
+  }
 }
@@ -0,0 +1,221 @@
+use std::fmt::Display;
+
+use actix_web::{
+    body::{EitherBody, MessageBody},
+    dev::ServiceResponse,
+    error::{JsonPayloadError, PathError, QueryPayloadError},
+    http::{header::ContentType, StatusCode},
+    web, HttpRequest, HttpResponse, ResponseError,
+};
+use serde::{Serialize, Deserialize};
+use serde_json::{Map, Value};
+use validator::{ValidationErrors, ValidationErrorsKind};
+
+use crate::model::{BatchError, UserDAOError};
+
+pub const CONTENT_TYPE: &str = "application/problem+json";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FieldProblem {
+    pub field: String,
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub params: Map<String, Value>,
+    pub message: String,
+}
+
+// RFC 7807 problem details, the body of every error response
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    pub title: String,
+    pub status: u16,
+    pub detail: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<FieldProblem>,
+    // failed operation of an atomic batch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub index: Option<usize>,
+}
+
+impl Problem {
+    pub fn new(problem_type: &str, title: &str, status: StatusCode, detail: String) -> Problem {
+        Problem {
+            problem_type: format!("/problems/{}", problem_type),
+            title: title.to_string(),
+            status: status.as_u16(),
+            detail,
+            instance: None,
+            errors: vec![],
+            index: None,
+        }
+    }
+
+    pub fn malformed_request(detail: String) -> Problem {
+        Problem::new("malformed-request", "Malformed request", StatusCode::BAD_REQUEST, detail)
+    }
+
+    pub fn validation(err: &ValidationErrors) -> Problem {
+        let mut errors = vec![];
+        collect_field_problems("", err, &mut errors);
+
+        Problem {
+            errors,
+            ..Problem::new("validation-error", "Validation failed", StatusCode::BAD_REQUEST, validation_detail(err))
+        }
+    }
+
+    pub fn with_instance(self, instance: &str) -> Problem {
+        Problem { instance: Some(instance.to_string()), ..self }
+    }
+}
+
+impl Display for Problem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.title, self.detail)
+    }
+}
+
+impl ResponseError for Problem {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::build(self.status_code())
+            .insert_header(ContentType(CONTENT_TYPE.parse().unwrap()))
+            .body(serde_json::to_string(self).unwrap())
+    }
+}
+
+impl From<&UserDAOError> for Problem {
+    fn from(err: &UserDAOError) -> Self {
+        let status = err.status_code();
+        match err {
+            UserDAOError::NotFound => Problem::new("not-found", "User not found", status, err.to_string()),
+            UserDAOError::AlreadyExists => Problem::new("already-exists", "User already exists", status, err.to_string()),
+            UserDAOError::VersionMismatch => Problem::new("precondition-failed", "Precondition failed", status, err.to_string()),
+            UserDAOError::Validation(e) => Problem::validation(e),
+            UserDAOError::Conflict(_) => Problem::new("conflict", "Conflicting update", status, err.to_string()),
+            UserDAOError::Storage(_) => Problem::new("storage-error", "Storage error", status, err.to_string()),
+            UserDAOError::Unavailable(_) => Problem::new("unavailable", "Storage unavailable", status, err.to_string()),
+        }
+    }
+}
+
+impl From<&BatchError> for Problem {
+    fn from(err: &BatchError) -> Self {
+        let problem = Problem::from(&err.error);
+        match err.index {
+            Some(index) => Problem {
+                detail: format!("Operation {} failed, no operation was applied: {}", index, problem.detail),
+                index: Some(index),
+                ..problem
+            },
+            None => problem,
+        }
+    }
+}
+
+fn validation_detail(err: &ValidationErrors) -> String {
+    let mut fields: Vec<&str> = err.errors().keys().copied().collect();
+    fields.sort();
+    format!("Invalid value of: {}", fields.join(", "))
+}
+
+fn collect_field_problems(prefix: &str, err: &ValidationErrors, out: &mut Vec<FieldProblem>) {
+    let mut fields: Vec<(&&str, &ValidationErrorsKind)> = err.errors().iter().collect();
+    fields.sort_by_key(|(f, _)| **f);
+
+    for (field, kind) in fields {
+        let path = if prefix.is_empty() { field.to_string() } else { format!("{}.{}", prefix, field) };
+        match kind {
+            ValidationErrorsKind::Field(errs) => {
+                for e in errs {
+                    // value is left out on purpose, it may be a secret
+                    let params: Map<String, Value> = e.params.iter()
+                        .filter(|(k, _)| *k != "value")
+                        .map(|(k, v)| (k.to_string(), v.clone()))
+                        .collect();
+                    let message = e.message.as_ref()
+                        .map(|m| m.to_string())
+                        .unwrap_or_else(|| default_message(&e.code, &params));
+
+                    out.push(FieldProblem { field: path.clone(), code: e.code.to_string(), params, message });
+                }
+            },
+            ValidationErrorsKind::Struct(nested) => collect_field_problems(&path, nested, out),
+            ValidationErrorsKind::List(items) => {
+                for (idx, nested) in items {
+                    collect_field_problems(&format!("{}[{}]", path, idx), nested, out);
+                }
+            },
+        }
+    }
+}
+
+fn default_message(code: &str, params: &Map<String, Value>) -> String {
+    let bounds = || match (params.get("min"), params.get("max")) {
+        (Some(min), Some(max)) => format!("between {} and {}", min, max),
+        (Some(min), None) => format!("at least {}", min),
+        (None, Some(max)) => format!("at most {}", max),
+        (None, None) => String::from("within bounds"),
+    };
+
+    match code {
+        "length" => format!("length must be {} characters", bounds()),
+        "range" => format!("must be {}", bounds()),
+        "regex" => String::from("has invalid format"),
+        "non_control_character" => String::from("must not contain control characters"),
+        "email" => String::from("must be a valid email address"),
+        "required" => String::from("is required"),
+        _ => String::from("is invalid"),
+    }
+}
+
+pub fn json_error(err: JsonPayloadError) -> Problem {
+    match &err {
+        JsonPayloadError::ContentType =>
+            Problem::new("unsupported-media-type", "Unsupported media type", StatusCode::UNSUPPORTED_MEDIA_TYPE, err.to_string()),
+        JsonPayloadError::Overflow { .. } | JsonPayloadError::OverflowKnownLength { .. } =>
+            Problem::new("payload-too-large", "Payload too large", StatusCode::PAYLOAD_TOO_LARGE, err.to_string()),
+        _ => Problem::malformed_request(err.to_string()),
+    }
+}
+
+pub fn json_config() -> web::JsonConfig {
+    web::JsonConfig::default().error_handler(|err: JsonPayloadError, _req: &HttpRequest| json_error(err).into())
+}
+
+pub fn query_config() -> web::QueryConfig {
+    web::QueryConfig::default().error_handler(|err: QueryPayloadError, _req: &HttpRequest| {
+        Problem::malformed_request(err.to_string()).into()
+    })
+}
+
+pub fn path_config() -> web::PathConfig {
+    web::PathConfig::default().error_handler(|err: PathError, _req: &HttpRequest| {
+        Problem::malformed_request(err.to_string()).into()
+    })
+}
+
+// Re-renders error responses with the request path as problem instance,
+// ResponseError::error_response has no access to the request.
+pub fn with_instance<B: MessageBody + 'static>(res: ServiceResponse<B>) -> ServiceResponse<EitherBody<B>> {
+    let problem = res.response().error().and_then(|err| {
+        err.as_error::<UserDAOError>().map(Problem::from)
+            .or_else(|| err.as_error::<Problem>().cloned())
+    });
+
+    match problem {
+        Some(problem) => {
+            let instance = res.request().path().to_string();
+            let response = problem.with_instance(&instance).error_response();
+            res.into_response(response).map_into_right_body()
+        },
+        None => res.map_into_left_body(),
+    }
+}
@@ -0,0 +1,56 @@
+use std::time::Duration;
+
+use actix_web::{rt, web::Data};
+use chrono::Utc;
+use futures::future::{select, Either};
+
+use crate::{configs::Purge, model::UserDAOError, services::UserDAO, shutdown::Cancellation};
+
+// Hard-deletes users soft deleted more than `retention` ago
+pub async fn purge_once(dao: &dyn UserDAO, retention: chrono::Duration) -> Result<u64, UserDAOError> {
+    let purged = dao.purge_deleted(Utc::now() - retention).await?;
+    if purged > 0 {
+        log::info!("Purged {} deleted users", purged);
+    }
+    Ok(purged)
+}
+
+// a purge under way finishes on cancellation, the next one does not start
+pub fn spawn(dao: Data<Box<dyn UserDAO>>, cfg: &Purge, mut cancellation: Cancellation) -> rt::task::JoinHandle<()> {
+    let retention = chrono::Duration::days(cfg.retention_days.into());
+    let period = Duration::from_secs(cfg.interval_secs.max(1));
+
+    rt::spawn(async move {
+        let mut interval = rt::time::interval(period);
+        loop {
+            let tick = Box::pin(interval.tick());
+            let cancelled = Box::pin(cancellation.cancelled());
+            if let Either::Right(_) = select(tick, cancelled).await {
+                log::info!("Purge of deleted users stopped");
+                return;
+            }
+            if let Err(err) = purge_once(dao.as_ref().as_ref(), retention).await {
+                log::error!("Purge of deleted users failed: {}", err);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Duration;
+
+    use crate::{configs::InMemory, services::{UserDAO, UserInMemoryDAO}};
+    use super::purge_once;
+
+    #[actix_web::test]
+    async fn test_purge_once_keeps_recently_deleted() {
+        let dao = UserInMemoryDAO::new(Some(&InMemory { users: 2 }));
+        dao.delete_by_id(1, None).await.unwrap();
+
+        assert_eq!(Ok(0), purge_once(&dao, Duration::days(30)).await);
+        assert_eq!(Ok(1), purge_once(&dao, Duration::zero()).await);
+        assert_eq!(Ok(0), purge_once(&dao, Duration::zero()).await);
+        assert_eq!(1, dao.list(&Default::default()).await.unwrap().total);
+    }
+}
@@ -0,0 +1,437 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::future::{ready, Ready};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    http::{header::{self, HeaderMap, HeaderName, HeaderValue}, StatusCode},
+    Error, HttpMessage, ResponseError,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{future::LocalBoxFuture, FutureExt};
+
+use crate::auth::Claims;
+use crate::configs::{Limit, RateLimit};
+use crate::problem::Problem;
+
+// buckets kept by InProcessStore at most
+const MAX_BUCKETS: usize = 10_000;
+// left after pruning, the pass over all buckets runs once per MAX_BUCKETS / 10 new keys at most
+const PRUNED_BUCKETS: usize = MAX_BUCKETS / 10 * 9;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    // seconds until the bucket is full again
+    pub reset_secs: u64,
+    // seconds until the next request may pass, 0 when allowed
+    pub retry_after_secs: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Bucket {
+    pub tokens: f64,
+    pub updated: DateTime<Utc>,
+}
+
+impl Bucket {
+    pub fn full(limit: &Limit, now: DateTime<Utc>) -> Bucket {
+        Bucket { tokens: f64::from(limit.capacity), updated: now }
+    }
+
+    fn refill(&mut self, limit: &Limit, now: DateTime<Utc>) {
+        let elapsed = (now - self.updated).num_microseconds().unwrap_or(i64::MAX).max(0) as f64 / 1e6;
+        self.tokens = (self.tokens + elapsed * limit.refill_per_sec).min(f64::from(limit.capacity));
+        self.updated = self.updated.max(now);
+    }
+
+    // takes a token if there is one, external stores run the same arithmetic on their state
+    pub fn take(&mut self, limit: &Limit, now: DateTime<Utc>) -> Decision {
+        self.refill(limit, now);
+        let allowed = self.tokens >= 1.0;
+        if allowed {
+            self.tokens -= 1.0;
+        }
+        let seconds = |tokens: f64| (tokens.max(0.0) / limit.refill_per_sec).ceil() as u64;
+        Decision {
+            allowed,
+            limit: limit.capacity,
+            remaining: self.tokens.floor() as u32,
+            reset_secs: seconds(f64::from(limit.capacity) - self.tokens),
+            retry_after_secs: if allowed { 0 } else { seconds(1.0 - self.tokens).max(1) },
+        }
+    }
+}
+
+// Where the buckets live, in the process by default. A shared store makes the limits hold across instances.
+#[async_trait]
+pub trait RateLimitStore: Send + Sync {
+    async fn acquire(&self, key: &str, limit: &Limit, now: DateTime<Utc>) -> Result<Decision, Box<dyn StdError + Send + Sync>>;
+}
+
+#[derive(Default)]
+pub struct InProcessStore {
+    // with the limit they were filled with, pruning needs the capacity and rate of every bucket
+    buckets: Mutex<HashMap<String, (Bucket, Limit)>>,
+}
+
+impl InProcessStore {
+    // Full buckets are the same as missing ones and go first. Clients cycling through addresses keep
+    // theirs from filling up, the least recently used go then and their clients start over with a full bucket.
+    fn prune(buckets: &mut HashMap<String, (Bucket, Limit)>, now: DateTime<Utc>) {
+        buckets.retain(|_, (bucket, limit)| {
+            let mut refilled = *bucket;
+            refilled.refill(limit, now);
+            refilled.tokens < f64::from(limit.capacity)
+        });
+        if buckets.len() > PRUNED_BUCKETS {
+            let mut updated: Vec<DateTime<Utc>> = buckets.values().map(|(bucket, _)| bucket.updated).collect();
+            let (_, cutoff, _) = updated.select_nth_unstable(buckets.len() - PRUNED_BUCKETS - 1);
+            let cutoff = *cutoff;
+            buckets.retain(|_, (bucket, _)| bucket.updated > cutoff);
+        }
+    }
+}
+
+#[async_trait]
+impl RateLimitStore for InProcessStore {
+    async fn acquire(&self, key: &str, limit: &Limit, now: DateTime<Utc>) -> Result<Decision, Box<dyn StdError + Send + Sync>> {
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            InProcessStore::prune(&mut buckets, now);
+        }
+        let (bucket, _) = buckets.entry(key.to_string()).or_insert_with(|| (Bucket::full(limit, now), *limit));
+        Ok(bucket.take(limit, now))
+    }
+}
+
+fn check(what: &str, limit: Limit) -> std::io::Result<Limit> {
+    if limit.capacity == 0 || !limit.refill_per_sec.is_finite() || limit.refill_per_sec <= 0.0 {
+        Err(std::io::Error::other(format!("Invalid rate limit of {}: capacity and refill_per_sec must be positive", what)))
+    } else {
+        Ok(limit)
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    method: Option<String>,
+    path: String,
+    limit: Limit,
+}
+
+// Middleware limiting the requests of every client per route, see configs::RateLimit.
+// Clients are told apart by the subject of their token or API key, anonymous ones by peer address.
+// X-Forwarded-For is not trusted, it would let clients pick their bucket.
+#[derive(Clone)]
+pub struct RateLimiter {
+    rules: Arc<Vec<Rule>>,
+    default: Option<Limit>,
+    store: Arc<dyn RateLimitStore>,
+    // one bucket per peer address for all routes, see per_address
+    per_address: bool,
+}
+
+impl RateLimiter {
+    // passes every request without config
+    pub fn from_config(cfg: Option<&RateLimit>, store: Arc<dyn RateLimitStore>) -> std::io::Result<RateLimiter> {
+        let mut rules = vec![];
+        let mut default = None;
+        if let Some(cfg) = cfg {
+            for route in &cfg.routes {
+                rules.push(Rule {
+                    method: route.method.as_ref().map(|method| method.to_uppercase()),
+                    path: route.path.clone(),
+                    limit: check(&route.path, route.limit())?,
+                });
+            }
+            default = cfg.default.map(|limit| check("default", limit)).transpose()?;
+        }
+        Ok(RateLimiter { rules: Arc::new(rules), default, store, per_address: false })
+    }
+
+    // Limiter of rate_limit.per_address, wrapped outside authentication. Unauthenticated requests and
+    // ones with invalid credentials are limited before their tokens and API keys are checked.
+    pub fn per_address(cfg: Option<&RateLimit>, store: Arc<dyn RateLimitStore>) -> std::io::Result<RateLimiter> {
+        let default = cfg.and_then(|cfg| cfg.per_address).map(|limit| check("per_address", limit)).transpose()?;
+        Ok(RateLimiter { rules: Arc::new(vec![]), default, store, per_address: true })
+    }
+
+    // key and limit of the bucket the request draws from, routes without a rule share the default bucket
+    fn bucket(&self, req: &ServiceRequest) -> Option<(String, Limit)> {
+        let address = || format!("ip:{}", req.peer_addr().map(|addr| addr.ip().to_string()).unwrap_or_default());
+        if self.per_address {
+            return Some((format!("address|{}", address()), self.default?));
+        }
+        let pattern = req.match_pattern().unwrap_or_else(|| req.path().to_string());
+        let method = req.method().as_str();
+        let rule = self.rules.iter()
+            .find(|rule| rule.path == pattern && rule.method.as_deref().is_none_or(|m| m == method));
+        let (scope, limit) = match rule {
+            Some(rule) => (format!("{} {}", rule.method.as_deref().unwrap_or("*"), rule.path), rule.limit),
+            None => ("*".to_string(), self.default?),
+        };
+
+        let client = match req.extensions().get::<Claims>() {
+            Some(claims) => format!("sub:{}", claims.sub),
+            None => address(),
+        };
+        Some((format!("{}|{}", scope, client), limit))
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimiter
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimiterMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<RateLimiterMiddleware<S>, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimiterMiddleware { service: Rc::new(service), limiter: self.clone() }))
+    }
+}
+
+pub struct RateLimiterMiddleware<S> {
+    service: Rc<S>,
+    limiter: RateLimiter,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimiterMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<ServiceResponse<EitherBody<B>>, Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let limiter = self.limiter.clone();
+        async move {
+            let decision = match limiter.bucket(&req) {
+                // a failing store lets requests pass, the limiter must not take the service down
+                Some((key, limit)) => match limiter.store.acquire(&key, &limit, Utc::now()).await {
+                    Ok(decision) => Some(decision),
+                    Err(err) => {
+                        log::warn!("Rate limit store failed, {} passes unchecked: {}", key, err);
+                        None
+                    },
+                },
+                None => None,
+            };
+
+            match decision {
+                Some(decision) if !decision.allowed => {
+                    log::debug!("Rate limit of {} {} exceeded", req.method(), req.path());
+                    Ok(req.error_response(TooManyRequests(decision)).map_into_right_body())
+                },
+                // the headers tell about the bucket of the route, not the one of the address
+                Some(decision) if !limiter.per_address => {
+                    let mut res = service.call(req).await?;
+                    rate_limit_headers(res.headers_mut(), &decision);
+                    Ok(res.map_into_left_body())
+                },
+                Some(_) => service.call(req).await.map(ServiceResponse::map_into_left_body),
+                None => service.call(req).await.map(ServiceResponse::map_into_left_body),
+            }
+        }.boxed_local()
+    }
+}
+
+// RateLimit header fields of the IETF httpapi draft
+fn rate_limit_headers(headers: &mut HeaderMap, decision: &Decision) {
+    let fields = [
+        ("ratelimit-limit", decision.limit as u64),
+        ("ratelimit-remaining", decision.remaining as u64),
+        ("ratelimit-reset", decision.reset_secs),
+    ];
+    for (name, value) in fields {
+        headers.insert(HeaderName::from_static(name), HeaderValue::from(value));
+    }
+}
+
+#[derive(Debug)]
+pub struct TooManyRequests(Decision);
+
+impl std::fmt::Display for TooManyRequests {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Rate limit of {} requests exceeded, retry in {} seconds", self.0.limit, self.0.retry_after_secs)
+    }
+}
+
+impl ResponseError for TooManyRequests {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::TOO_MANY_REQUESTS
+    }
+
+    fn error_response(&self) -> actix_web::HttpResponse {
+        let mut response = Problem::new("too-many-requests", "Too many requests", self.status_code(), self.to_string()).error_response();
+        rate_limit_headers(response.headers_mut(), &self.0);
+        response.headers_mut().insert(header::RETRY_AFTER, HeaderValue::from(self.0.retry_after_secs));
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use actix_web::{http::StatusCode, test, web, App};
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::auth::{test_auth, test_token, Authentication};
+    use crate::configs::{Configuration, Limit, RateLimit, RouteLimit};
+    use super::{Bucket, Decision, InProcessStore, RateLimitStore, RateLimiter, MAX_BUCKETS, PRUNED_BUCKETS};
+
+    #[actix_web::test]
+    async fn test_bucket() {
+        let limit = Limit { capacity: 2, refill_per_sec: 0.5 };
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        let mut bucket = Bucket::full(&limit, start);
+
+        assert_eq!(Decision { allowed: true, limit: 2, remaining: 1, reset_secs: 2, retry_after_secs: 0 }, bucket.take(&limit, start));
+        assert_eq!(Decision { allowed: true, limit: 2, remaining: 0, reset_secs: 4, retry_after_secs: 0 }, bucket.take(&limit, start));
+        assert_eq!(Decision { allowed: false, limit: 2, remaining: 0, reset_secs: 4, retry_after_secs: 2 }, bucket.take(&limit, start));
+
+        let later = start + Duration::seconds(1);
+        assert_eq!(Decision { allowed: false, limit: 2, remaining: 0, reset_secs: 3, retry_after_secs: 1 }, bucket.take(&limit, later));
+        assert!(bucket.take(&limit, start + Duration::seconds(2)).allowed);
+
+        // refills stop at the capacity
+        let idle = start + Duration::hours(1);
+        assert_eq!(1, bucket.take(&limit, idle).remaining);
+    }
+
+    #[actix_web::test]
+    async fn test_store_is_capped() {
+        let store = InProcessStore::default();
+        let limit = Limit { capacity: 2, refill_per_sec: 0.001 };
+        let start = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+        // none of the buckets refills in the meantime, a client on many addresses
+        for i in 0..MAX_BUCKETS + 2_500 {
+            let now = start + Duration::milliseconds(i as i64);
+            store.acquire(&format!("ip:{}", i), &limit, now).await.unwrap();
+            assert!(store.buckets.lock().unwrap().len() <= MAX_BUCKETS);
+        }
+
+        // the least recently used went, the latest are kept
+        let buckets = store.buckets.lock().unwrap();
+        assert!(buckets.len() > PRUNED_BUCKETS);
+        assert!(!buckets.contains_key("ip:0"));
+        assert_eq!(1.0, buckets[&format!("ip:{}", MAX_BUCKETS + 2_499)].0.tokens);
+    }
+
+    #[actix_web::test]
+    async fn test_rejects_invalid_limits() {
+        let cfg = RateLimit { default: Some(Limit { capacity: 0, refill_per_sec: 1.0 }), ..RateLimit::default() };
+        let err = RateLimiter::from_config(Some(&cfg), Arc::new(InProcessStore::default())).err().unwrap();
+        assert_eq!("Invalid rate limit of default: capacity and refill_per_sec must be positive", err.to_string());
+
+        let cfg = Configuration::load_from_file("tests/rate_limit.yaml").unwrap();
+        assert!(RateLimiter::from_config(cfg.rate_limit.as_ref(), Arc::new(InProcessStore::default())).is_ok());
+    }
+
+    #[actix_web::test]
+    async fn test_middleware() {
+        let cfg = RateLimit {
+            default: None,
+            routes: vec![RouteLimit { method: Some("post".to_string()), path: "/users/{id}".to_string(), capacity: 2, refill_per_sec: 0.001 }],
+            per_address: None,
+        };
+        let limiter = RateLimiter::from_config(Some(&cfg), Arc::new(InProcessStore::default())).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(test_auth())
+                .wrap(limiter)
+                .wrap(Authentication)
+                .route("/users/{id}", web::post().to(|| async { "ok" }))
+                .route("/users/{id}", web::get().to(|| async { "ok" })),
+        ).await;
+        let post = |user_id: u64| test::TestRequest::post()
+            .uri("/users/1")
+            .insert_header(("Authorization", format!("Bearer {}", test_token(user_id, &["writer"]))))
+            .to_request();
+
+        let resp = test::call_service(&app, post(1)).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert_eq!("2", resp.headers().get("ratelimit-limit").unwrap());
+        assert_eq!("1", resp.headers().get("ratelimit-remaining").unwrap());
+        assert_eq!(StatusCode::OK, test::call_service(&app, post(1)).await.status());
+
+        let resp = test::call_service(&app, post(1)).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, resp.status());
+        assert_eq!("1000", resp.headers().get("retry-after").unwrap());
+        assert_eq!("0", resp.headers().get("ratelimit-remaining").unwrap());
+        assert_eq!("application/problem+json", resp.headers().get("content-type").unwrap());
+
+        // other clients and routes without a limit are not affected
+        assert_eq!(StatusCode::OK, test::call_service(&app, post(2)).await.status());
+        let get = test::TestRequest::get()
+            .uri("/users/1")
+            .insert_header(("Authorization", format!("Bearer {}", test_token(1, &["reader"]))))
+            .to_request();
+        let resp = test::call_service(&app, get).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        assert!(resp.headers().get("ratelimit-limit").is_none());
+    }
+
+    #[actix_web::test]
+    async fn test_per_address_before_authentication() {
+        let cfg = RateLimit { per_address: Some(Limit { capacity: 2, refill_per_sec: 0.001 }), ..RateLimit::default() };
+        let limiter = RateLimiter::per_address(Some(&cfg), Arc::new(InProcessStore::default())).unwrap();
+        let app = test::init_service(
+            App::new()
+                .app_data(test_auth())
+                .wrap(Authentication)
+                .wrap(limiter)
+                .route("/users", web::get().to(|| async { "ok" })),
+        ).await;
+        let guess = |peer: &str| test::TestRequest::get()
+            .uri("/users")
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("X-API-Key", "0000000000000000.guessed"))
+            .to_request();
+
+        // failed authentication draws from the bucket of the address
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, guess("10.0.0.1:4000")).await.status());
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, guess("10.0.0.1:4001")).await.status());
+        let resp = test::call_service(&app, guess("10.0.0.1:4002")).await;
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, resp.status());
+        assert!(resp.headers().get("retry-after").is_some());
+        assert_eq!(StatusCode::UNAUTHORIZED, test::call_service(&app, guess("10.0.0.2:4000")).await.status());
+
+        let err = RateLimiter::per_address(Some(&RateLimit { per_address: Some(Limit { capacity: 1, refill_per_sec: 0.0 }), ..RateLimit::default() }),
+            Arc::new(InProcessStore::default())).err().unwrap();
+        assert_eq!("Invalid rate limit of per_address: capacity and refill_per_sec must be positive", err.to_string());
+    }
+
+    #[actix_web::test]
+    async fn test_anonymous_clients_by_peer_address() {
+        let cfg = RateLimit { default: Some(Limit { capacity: 1, refill_per_sec: 1.0 }), ..RateLimit::default() };
+        let limiter = RateLimiter::from_config(Some(&cfg), Arc::new(InProcessStore::default())).unwrap();
+        let app = test::init_service(App::new().wrap(limiter).route("/auth/login", web::post().to(|| async { "ok" }))).await;
+        let login = |peer: &str| test::TestRequest::post()
+            .uri("/auth/login")
+            .peer_addr(peer.parse().unwrap())
+            .insert_header(("X-Forwarded-For", "10.0.0.9"))
+            .to_request();
+
+        assert_eq!(StatusCode::OK, test::call_service(&app, login("10.0.0.1:4000")).await.status());
+        assert_eq!(StatusCode::TOO_MANY_REQUESTS, test::call_service(&app, login("10.0.0.1:4001")).await.status());
+        assert_eq!(StatusCode::OK, test::call_service(&app, login("10.0.0.2:4000")).await.status());
+    }
+}
@@ -0,0 +1,70 @@
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+
+use crate::model::{Page, SearchHit, SearchQuery, User};
+
+// Scores hits the way pg_trgm does, so every store ranks a search alike.
+
+// pg_trgm.similarity_threshold default, a name at least this similar to the search matches
+pub const THRESHOLD: f64 = 0.3;
+
+// lower case trigrams of every word, padded with two spaces in front and one behind as in pg_trgm
+fn trigrams(text: &str) -> BTreeSet<[char; 3]> {
+    let mut trigrams = BTreeSet::new();
+    for word in text.to_lowercase().split(|c: char| !c.is_alphanumeric()).filter(|w| !w.is_empty()) {
+        let padded: Vec<char> = "  ".chars().chain(word.chars()).chain(" ".chars()).collect();
+        for t in padded.windows(3) {
+            trigrams.insert([t[0], t[1], t[2]]);
+        }
+    }
+    trigrams
+}
+
+// shared trigrams over all distinct ones, in single precision like pg_trgm's similarity()
+pub fn similarity(a: &str, b: &str) -> f64 {
+    let (a, b) = (trigrams(a), trigrams(b));
+    if a.is_empty() || b.is_empty() {
+        return 0.0;
+    }
+    let common = a.intersection(&b).count();
+    (common as f32 / (a.len() + b.len() - common) as f32) as f64
+}
+
+// None unless name or display name is similar enough or contains the search ignoring case
+pub fn score(user: &User, q: &str) -> Option<f64> {
+    let name = user.fields.name.as_str();
+    let display_name = user.fields.display_name.as_deref().unwrap_or("");
+    let score = similarity(name, q).max(similarity(display_name, q));
+
+    let needle = q.to_lowercase();
+    let contains = |text: &str| text.to_lowercase().contains(&needle);
+    (score >= THRESHOLD || contains(name) || contains(display_name)).then_some(score)
+}
+
+// best score first, ties by name and id as in the Postgres query
+pub fn page(mut hits: Vec<SearchHit>, query: &SearchQuery) -> Page<SearchHit> {
+    hits.sort_by(|a, b| b.score.partial_cmp(&a.score).unwrap_or(Ordering::Equal)
+        .then_with(|| a.user.fields.name.cmp(&b.user.fields.name))
+        .then(a.user.id.cmp(&b.user.id)));
+
+    let total = hits.len() as u64;
+    let items = hits.into_iter()
+        .skip(query.offset() as usize)
+        .take(query.page_size as usize)
+        .collect();
+    Page { items, total }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::similarity;
+
+    #[test]
+    fn test_similarity_as_pg_trgm() {
+        // select similarity('alex', 'Alexander'), similarity('Alex Smith', 'alex')
+        assert_eq!((4.0f32 / 11.0) as f64, similarity("alex", "Alexander"));
+        assert_eq!((5.0f32 / 11.0) as f64, similarity("Alex Smith", "alex"));
+        assert_eq!(1.0, similarity("User1", "user1"));
+        assert_eq!(0.0, similarity("%", "User1"));
+    }
+}
@@ -1,27 +1,175 @@
-use std::sync::Mutex;
+use std::ops::{Deref, DerefMut};
+use std::slice;
+use std::sync::{Arc, Mutex};
 
 use crate::configs::Db;
 use crate::configs::InMemory;
+use crate::configs::Sqlite;
+use crate::search;
+use crate::telemetry::SqlSpans;
+use crate::model::BatchError;
+use crate::model::BatchOp;
+use crate::model::Cursor;
 use crate::model::DbUser;
+use crate::model::Page;
+use crate::model::SearchHit;
+use crate::model::SearchQuery;
+use crate::model::SortField;
+use crate::model::SortOrder;
+use crate::model::SqliteUser;
 use crate::model::User;
 use crate::model::UserDAOError;
 use crate::model::UserFields;
-use actix_web::http::StatusCode;
+use crate::model::UserStatus;
+use crate::model::UsersQuery;
+use async_stream::try_stream;
 use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{future, stream::{self, BoxStream}, StreamExt};
+use rbatis::PageRequest;
+use rbatis::core::db::DBPoolConn;
 use rbatis::crud::CRUD;
-use rbatis::py_sql;
+use rbatis::executor::{ExecutorMut, RBatisTxExecutor};
 use rbatis::rbatis::Rbatis;
-use rbatis::rb_py;
+use rbson::Bson;
 use validator::Validate;
 
 #[async_trait]  
 pub trait UserDAO: Sync + Send
 {
-    async fn list(&self) -> Result<Vec<User>, UserDAOError>;
+    async fn list(&self, query: &UsersQuery) -> Result<Page<User>, UserDAOError>;
+    // all users matching the query in its order, read in chunks so memory stays flat, page and page_size are ignored
+    fn list_stream(&self, query: &UsersQuery) -> BoxStream<'static, Result<User, UserDAOError>>;
+    // keyset page of at most limit users following the cursor in the query order, page and page_size are ignored
+    async fn list_after(&self, query: &UsersQuery, after: Option<&Cursor>, limit: u64) -> Result<Vec<User>, UserDAOError>;
+    // active users similar to or containing the search, best match first
+    async fn search(&self, query: &SearchQuery) -> Result<Page<SearchHit>, UserDAOError>;
     async fn find_by_id(&self, id: u64) -> Result<User, UserDAOError>;
+    // names are unique, soft deleted users are not found
+    async fn find_by_name(&self, name: &str) -> Result<User, UserDAOError>;
     async fn create(&self, fields: &UserFields) -> Result<User, UserDAOError>;
-    async fn update(&self, user: &User) -> Result<User, UserDAOError>;
-    async fn delete_by_id(&self, id: u64) -> Result<User, UserDAOError>;
+    // expected_versions guard against lost updates, a user at none of them fails with VersionMismatch
+    async fn update(&self, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError>;
+    // soft delete, the user is hidden from list and find_by_id until restored or purged
+    async fn delete_by_id(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError>;
+    async fn restore(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError>;
+    // hard-deletes users soft deleted before the given time, returns how many were removed
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<u64, UserDAOError>;
+    // runs every operation on its own, a failed one does not stop the rest
+    async fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Result<User, UserDAOError>>, UserDAOError>;
+    // runs all operations in one transaction, none is applied unless all succeed
+    async fn batch_atomic(&self, ops: &[BatchOp]) -> Result<Vec<User>, BatchError>;
+    // cheap check that the store can serve requests, run by the readiness probe
+    async fn health(&self) -> Result<(), UserDAOError>;
+}
+
+// name, email, display_name and status in the column order of inserts and updates
+fn field_args(fields: &UserFields) -> Vec<Bson> {
+    let optional = |value: &Option<String>| value.clone().map(Bson::String).unwrap_or(Bson::Null);
+    let status = rbson::to_bson(fields.status).unwrap_or(Bson::Null);
+    vec![Bson::String(fields.name.clone()), optional(&fields.email), optional(&fields.display_name), status]
+}
+
+// guards a write by the expected versions, the placeholder of the nth argument is given by the dialect
+fn push_versions(sql: &mut String, args: &mut Vec<Bson>, versions: &[u64], placeholder: fn(usize) -> String) {
+    if versions.is_empty() {
+        sql.push_str(" and 1 = 0");
+        return;
+    }
+    let placeholders: Vec<String> = versions.iter()
+        .map(|version| {
+            args.push(Bson::Int64(*version as i64));
+            placeholder(args.len())
+        })
+        .collect();
+    sql.push_str(&format!(" and version in ({})", placeholders.join(", ")));
+}
+
+// users per round-trip of list_stream
+const STREAM_CHUNK: usize = 500;
+
+fn order_sql(query: &UsersQuery) -> &'static str {
+    match (query.sort.field, query.sort.order) {
+        (SortField::Id, SortOrder::Asc) => " order by id asc",
+        (SortField::Id, SortOrder::Desc) => " order by id desc",
+        (SortField::Name, SortOrder::Asc) => " order by name asc, id asc",
+        (SortField::Name, SortOrder::Desc) => " order by name desc, id desc",
+    }
+}
+
+// condition of the rows following the cursor, param renders the placeholder of the n-th argument
+fn after_sql(after: &Cursor, args: &mut Vec<Bson>, param: fn(usize) -> String) -> String {
+    let op = match after.sort.order {
+        SortOrder::Asc => ">",
+        SortOrder::Desc => "<",
+    };
+    match (after.sort.field, &after.name) {
+        (SortField::Name, Some(name)) => {
+            args.push(Bson::String(name.clone()));
+            let name_param = param(args.len());
+            args.push(Bson::Int64(after.id as i64));
+            format!("(name, id) {} ({}, {})", op, name_param, param(args.len()))
+        },
+        _ => {
+            args.push(Bson::Int64(after.id as i64));
+            format!("id {} {}", op, param(args.len()))
+        },
+    }
+}
+
+// rbatis sends BEGIN on a pooled connection, a transaction dropped before commit or rollback
+// would go back to the pool still open. Its connection is closed instead and the database rolls it back.
+struct TxGuard<'a>(Option<RBatisTxExecutor<'a>>);
+
+impl<'a> TxGuard<'a> {
+    async fn begin(rb: &'a Rbatis) -> Result<TxGuard<'a>, rbatis::Error> {
+        Ok(TxGuard(Some(rb.acquire_begin().await?)))
+    }
+}
+
+impl<'a> Deref for TxGuard<'a> {
+    type Target = RBatisTxExecutor<'a>;
+
+    fn deref(&self) -> &Self::Target {
+        self.0.as_ref().expect("transaction taken")
+    }
+}
+
+impl DerefMut for TxGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.0.as_mut().expect("transaction taken")
+    }
+}
+
+impl Drop for TxGuard<'_> {
+    fn drop(&mut self) {
+        match self.0.take() {
+            Some(tx) if !tx.is_done() => match tx.take_conn() {
+                Some(DBPoolConn::Postgres(conn, _)) => drop(conn.detach()),
+                Some(DBPoolConn::Sqlite(conn, _)) => drop(conn.detach()),
+                _ => {},
+            },
+            _ => {},
+        }
+    }
+}
+
+fn check_version(user: &User, expected_versions: Option<&[u64]>) -> Result<(), UserDAOError> {
+    match expected_versions {
+        Some(versions) if !versions.contains(&user.version) => Err(UserDAOError::VersionMismatch),
+        _ => Ok(()),
+    }
+}
+
+// Restoring a user that is not deleted changes nothing,
+// a deleted user left as is was modified concurrently.
+fn restore_unchanged(user: Option<User>, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+    let user = user.ok_or(UserDAOError::NotFound)?;
+    check_version(&user, expected_versions)?;
+    match user.deleted_at {
+        None => Ok(user),
+        Some(_) => Err(UserDAOError::VersionMismatch),
+    }
 }
 
 
@@ -35,10 +183,15 @@ impl UserInMemoryDAO {
         let list = &mut vec![];
         
         if let Some(inmemory) = cfg {
+            let now = Utc::now();
             for i in 1 .. inmemory.users + 1 {
                 let user = User {
                     id: u64::from(i), 
-                    fields: UserFields { name: String::from(format!("User{}", i))}
+                    version: 1,
+                    created_at: now,
+                    updated_at: now,
+                    deleted_at: None,
+                    fields: UserInMemoryDAO::seed_fields(i),
                 };
                 list.push(user);
             }
@@ -46,51 +199,58 @@ impl UserInMemoryDAO {
         UserInMemoryDAO{ users: Mutex::new(list.clone())} 
     }
 
+    pub fn seed_fields(i: u16) -> UserFields {
+        UserFields {
+            name: format!("User{}", i),
+            email: Some(format!("user{}@example.com", i)),
+            display_name: Some(format!("User {}", i)),
+            status: UserStatus::Active,
+        }
+    }
+
     pub fn validate_fields(fields: &UserFields) -> Result<(), UserDAOError> {
         fields.validate()
-            .map_err(|err| UserDAOError::from_validation_errors(&err))
+            .map_err(UserDAOError::from)
 
     }
-}
 
-#[async_trait]
-impl UserDAO for UserInMemoryDAO {
-    
-    async fn list(&self) -> Result<Vec<User>, UserDAOError> {
-        let guard = self.users.lock().unwrap();
-        let users = &*guard;
-        let users_list = users.clone();
-        Ok(users_list)
+    pub fn validate_query(query: &UsersQuery) -> Result<(), UserDAOError> {
+        query.validate()
+            .map_err(UserDAOError::from)
     }
 
-    async fn find_by_id(&self, id: u64) -> Result<User, UserDAOError> {
-        let guard = self.users.lock().unwrap();
-        let users = &*guard;
-        users.iter()
-            .find(|&u| u.id == id)
-            .map(|u| u.clone())
-            .ok_or(UserDAOError {
-                message: "User not found".to_string(), 
-                status: StatusCode::NOT_FOUND.as_u16()
-            })
+    fn matching<'a>(users: &'a [User], query: &UsersQuery) -> Vec<&'a User> {
+        let mut matched: Vec<&User> = users.iter()
+            .filter(|u| query.matches(u))
+            .collect();
+
+        matched.sort_by(|a, b| {
+            let ordering = match query.sort.field {
+                SortField::Id => a.id.cmp(&b.id),
+                SortField::Name => a.fields.name.cmp(&b.fields.name).then(a.id.cmp(&b.id)),
+            };
+            match query.sort.order {
+                SortOrder::Asc => ordering,
+                SortOrder::Desc => ordering.reverse(),
+            }
+        });
+        matched
     }
 
-    async fn create(&self, fields: &UserFields) -> Result<User, UserDAOError> {
+    fn create_in(users: &mut Vec<User>, fields: &UserFields) -> Result<User, UserDAOError> {
         
-        UserInMemoryDAO::validate_fields(&fields)?;
-
-        let mut guard = self.users.lock().unwrap();
-        let users = &mut *guard;
+        UserInMemoryDAO::validate_fields(fields)?;
 
-        let user_exists = users.into_iter().any(|u| u.fields == *fields);
+        let user_exists = users.iter().any(|u| u.fields.conflicts_with(fields));
 
         if user_exists {
-            Err(UserDAOError{ message: String::from("User exists"), status: StatusCode::BAD_REQUEST.as_u16()})
+            Err(UserDAOError::AlreadyExists)
         } else {
-            let max_id = users.into_iter().map(|u| u.id).max();
+            let max_id = users.iter().map(|u| u.id).max();
             let uid = max_id.unwrap_or(0) + 1;
 
-            let user = User {id: uid, fields: fields.clone() };
+            let now = Utc::now();
+            let user = User {id: uid, version: 1, created_at: now, updated_at: now, deleted_at: None, fields: fields.clone() };
 
             users.push(user.clone());
 
@@ -98,161 +258,921 @@ impl UserDAO for UserInMemoryDAO {
         }
     }
 
-    async fn update(&self, user: &User) -> Result<User, UserDAOError> {
+    fn update_in(users: &mut Vec<User>, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
         
-        UserInMemoryDAO::validate_fields(&user.fields)?;
-
-        let mut guard = self.users.lock().unwrap();
-        let users = &mut *guard;
+        UserInMemoryDAO::validate_fields(fields)?;
 
-        let existing_user_idx = users.into_iter().position(|u| u.id == user.id);
+        let existing_user_idx = users.iter().position(|u| u.id == id && u.deleted_at.is_none());
+        let taken = users.iter().any(|u| u.id != id && u.fields.conflicts_with(fields));
 
         match existing_user_idx {
             Some(idx) => {
-                users.remove(idx);
+                check_version(&users[idx], expected_versions)?;
+                if taken {
+                    return Err(UserDAOError::AlreadyExists);
+                }
+                let existing = users.remove(idx);
+                let user = User {
+                    id,
+                    version: existing.version + 1,
+                    created_at: existing.created_at,
+                    updated_at: Utc::now(),
+                    deleted_at: None,
+                    fields: fields.clone(),
+                };
                 users.push(user.clone());
-                Ok(user.clone())
+                Ok(user)
             },
-            None => Err(UserDAOError {message: String::from("User not found"), status: StatusCode::BAD_REQUEST.as_u16()})
+            None => Err(UserDAOError::NotFound)
+        }
+    }
+
+    fn delete_in(users: &mut [User], id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        let existing = users.iter_mut().find(|u| u.id == id && u.deleted_at.is_none())
+            .ok_or(UserDAOError::NotFound)?;
+        check_version(existing, expected_versions)?;
+
+        let now = Utc::now();
+        existing.deleted_at = Some(now);
+        existing.fields.status = UserStatus::Deleted;
+        existing.updated_at = now;
+        existing.version += 1;
+        Ok(existing.clone())
+    }
+
+    fn apply(users: &mut Vec<User>, op: &BatchOp) -> Result<User, UserDAOError> {
+        match op {
+            BatchOp::Create { fields } => UserInMemoryDAO::create_in(users, fields),
+            BatchOp::Update { id, version, fields } => UserInMemoryDAO::update_in(users, *id, fields, version.as_ref().map(slice::from_ref)),
+            BatchOp::Delete { id, version } => UserInMemoryDAO::delete_in(users, *id, version.as_ref().map(slice::from_ref)),
+        }
+    }
+}
+
+#[async_trait]
+impl UserDAO for UserInMemoryDAO {
+    
+    async fn list(&self, query: &UsersQuery) -> Result<Page<User>, UserDAOError> {
+        UserInMemoryDAO::validate_query(query)?;
+
+        let guard = self.users.lock().unwrap();
+        let matched = UserInMemoryDAO::matching(&guard, query);
+
+        let total = matched.len() as u64;
+        let items = matched.into_iter()
+            .skip(query.offset() as usize)
+            .take(query.page_size as usize)
+            .cloned()
+            .collect();
+
+        Ok(Page { items, total })
+    }
+
+    // the store holds every user anyway, the stream runs on a copy of the matching ones
+    fn list_stream(&self, query: &UsersQuery) -> BoxStream<'static, Result<User, UserDAOError>> {
+        if let Err(err) = UserInMemoryDAO::validate_query(query) {
+            return stream::once(future::err(err)).boxed();
         }
+
+        let guard = self.users.lock().unwrap();
+        let users: Vec<Result<User, UserDAOError>> = UserInMemoryDAO::matching(&guard, query).into_iter()
+            .map(|user| Ok(user.clone()))
+            .collect();
+        stream::iter(users).boxed()
     }
 
-    async fn delete_by_id(&self, id: u64) -> Result<User, UserDAOError> {
+    // matching users are in sort order, a binary search finds the first one after the cursor
+    async fn list_after(&self, query: &UsersQuery, after: Option<&Cursor>, limit: u64) -> Result<Vec<User>, UserDAOError> {
+        UserInMemoryDAO::validate_query(query)?;
+
+        let guard = self.users.lock().unwrap();
+        let matched = UserInMemoryDAO::matching(&guard, query);
+        let start = after.map_or(0, |cursor| matched.partition_point(|u| !cursor.precedes(u)));
+
+        Ok(matched[start..].iter()
+            .take(limit as usize)
+            .map(|&u| u.clone())
+            .collect())
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Page<SearchHit>, UserDAOError> {
+        query.validate()?;
+
+        let guard = self.users.lock().unwrap();
+        let hits = guard.iter()
+            .filter(|u| u.deleted_at.is_none())
+            .filter_map(|u| search::score(u, &query.q).map(|score| SearchHit { score, user: u.clone() }))
+            .collect();
+        Ok(search::page(hits, query))
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<User, UserDAOError> {
+        let guard = self.users.lock().unwrap();
+        let users = &*guard;
+        users.iter()
+            .find(|&u| u.id == id && u.deleted_at.is_none())
+            .cloned()
+            .ok_or(UserDAOError::NotFound)
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<User, UserDAOError> {
+        self.users.lock().unwrap().iter()
+            .find(|u| u.fields.name == name && u.deleted_at.is_none())
+            .cloned()
+            .ok_or(UserDAOError::NotFound)
+    }
+
+    async fn create(&self, fields: &UserFields) -> Result<User, UserDAOError> {
         let mut guard = self.users.lock().unwrap();
-        let users = &mut *guard;
+        UserInMemoryDAO::create_in(&mut guard, fields)
+    }
 
-        let existing_user_idx = users.into_iter().position(|u| u.id == id);
+    async fn update(&self, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        let mut guard = self.users.lock().unwrap();
+        UserInMemoryDAO::update_in(&mut guard, id, fields, expected_versions)
+    }
 
-        match existing_user_idx {
-            Some(idx) => {
-                let user = users.remove(idx);
-                Ok(user.clone())
+    async fn delete_by_id(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        let mut guard = self.users.lock().unwrap();
+        UserInMemoryDAO::delete_in(&mut guard, id, expected_versions)
+    }
+
+    async fn restore(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        let mut guard = self.users.lock().unwrap();
+        let users = &mut *guard;
+
+        match users.iter_mut().find(|u| u.id == id) {
+            Some(existing) if existing.deleted_at.is_some() => {
+                check_version(existing, expected_versions)?;
+                existing.deleted_at = None;
+                existing.fields.status = UserStatus::Active;
+                existing.updated_at = Utc::now();
+                existing.version += 1;
+                Ok(existing.clone())
             },
-            None => Err(UserDAOError {message: String::from("User not found"), status: StatusCode::BAD_REQUEST.as_u16()})
+            other => restore_unchanged(other.cloned(), expected_versions),
+        }
+    }
+
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        let mut guard = self.users.lock().unwrap();
+        let users = &mut *guard;
+
+        let count = users.len();
+        users.retain(|u| u.deleted_at.is_none_or(|at| at >= before));
+        Ok((count - users.len()) as u64)
+    }
+
+    async fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Result<User, UserDAOError>>, UserDAOError> {
+        let mut guard = self.users.lock().unwrap();
+        Ok(ops.iter()
+            .map(|op| UserInMemoryDAO::apply(&mut guard, op))
+            .collect())
+    }
+
+    async fn batch_atomic(&self, ops: &[BatchOp]) -> Result<Vec<User>, BatchError> {
+        let mut guard = self.users.lock().unwrap();
+
+        // operations run on a copy which replaces the users only if all of them succeed
+        let mut users = guard.clone();
+        let mut applied = Vec::with_capacity(ops.len());
+        for (index, op) in ops.iter().enumerate() {
+            let user = UserInMemoryDAO::apply(&mut users, op)
+                .map_err(|error| BatchError { index: Some(index), error })?;
+            applied.push(user);
         }
+
+        *guard = users;
+        Ok(applied)
+    }
+
+    async fn health(&self) -> Result<(), UserDAOError> {
+        Ok(())
     }
 }
 
+// a row of the search query, the user columns and the score
+#[derive(serde::Deserialize)]
+struct DbSearchHit {
+    #[serde(flatten)]
+    user: DbUser,
+    score: f64,
+}
+
 pub struct UserDbDAO {
-    rb: Rbatis,
+    // shared with the streams of list_stream which outlive the call
+    rb: Arc<Rbatis>,
 }
 
 impl UserDbDAO {
+    // all parts go in the query, the only part of the URL decoded again, so any character may be used
     fn connection_str(cfg: &Db) -> String {
-        format!("postgres://{2}:{3}@{0}:{4}/{1}", 
-            cfg.host, 
-            cfg.db_name,
-            cfg.user,
-            cfg.password,
-            cfg.port
-        )
+        let port = cfg.port.to_string();
+        let params = [("host", cfg.host.as_str()), ("port", &port), ("dbname", &cfg.db_name), ("user", &cfg.user), ("password", &cfg.password)];
+        format!("postgres://?{}", serde_urlencoded::to_string(params).unwrap_or_default())
     }
 
     pub async fn new(cfg: &Db) -> UserDbDAO {
-        let rbatis = Rbatis::new();
+        let mut rbatis = Rbatis::new();
+        rbatis.set_log_plugin(SqlSpans::default());
         let conn_str = UserDbDAO::connection_str(cfg);
 
         rbatis.link(&conn_str).await.expect("rbatis not linked to db");
 
         UserDbDAO {
-            rb: rbatis,
+            rb: Arc::new(rbatis),
         }
     }
 
-    #[py_sql("insert into users_schema.users(name) values ( #{uname} ) RETURNING id;")]
-    async fn insert_with_identity(rb: &Rbatis, uname: &str) -> u64 { rbatis::impled!(); }
+    // for the stores sharing the connection pool, like the API keys
+    pub fn pool(&self) -> Arc<Rbatis> {
+        self.rb.clone()
+    }
+
+    // LIKE treats % and _ as wildcards, filters must match literally as in UserInMemoryDAO
+    fn escape_like(value: &str) -> String {
+        value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_")
+    }
 
-    #[py_sql("update users_schema.users set name = #{uuser.name} where id = #{uuser.id} RETURNING id;")]
-    async fn update_by_id(rb: &Rbatis, uuser: &DbUser) -> u64 { rbatis::impled!(); }
+    // the filters of list as plain SQL for statements the wrapper can not build
+    fn filter_sql(query: &UsersQuery, after: Option<&Cursor>) -> (String, Vec<Bson>) {
+        let mut conditions = vec![];
+        let mut args = vec![];
+        if !query.include_deleted {
+            conditions.push(String::from("deleted_at is null"));
+        }
+        if let Some(prefix) = &query.name_prefix {
+            args.push(Bson::String(format!("{}%", UserDbDAO::escape_like(prefix))));
+            conditions.push(format!("name like ${}", args.len()));
+        }
+        if let Some(substring) = &query.name_contains {
+            args.push(Bson::String(format!("%{}%", UserDbDAO::escape_like(substring))));
+            conditions.push(format!("name like ${}", args.len()));
+        }
+        if let Some(cursor) = after {
+            conditions.push(after_sql(cursor, &mut args, |n| format!("${}", n)));
+        }
+
+        if conditions.is_empty() {
+            (String::new(), args)
+        } else {
+            (format!(" where {}", conditions.join(" and ")), args)
+        }
+    }
+
+    // Writes take an executor to run either on a pooled connection or in the transaction of a batch
+
+    async fn fetch_by_id<E: ExecutorMut + Send>(rb: &mut E, id: u64) -> Result<Option<User>, UserDAOError> {
+        rb.fetch::<Option<DbUser>>("select * from users_schema.users where id = $1", vec![Bson::Int64(id as i64)])
+            .await
+            .map(|user| user.map(User::from))
+            .map_err(UserDAOError::from)
+    }
+
+    // a write that matched no row hit a deleted or missing user or a stale version
+    async fn not_written<E: ExecutorMut + Send>(rb: &mut E, id: u64) -> UserDAOError {
+        match UserDbDAO::fetch_by_id(rb, id).await {
+            Ok(Some(user)) if user.deleted_at.is_none() => UserDAOError::VersionMismatch,
+            Ok(_) => UserDAOError::NotFound,
+            Err(err) => err,
+        }
+    }
+
+    async fn create_in<E: ExecutorMut + Send>(rb: &mut E, fields: &UserFields) -> Result<User, UserDAOError> {
+        
+        UserInMemoryDAO::validate_fields(fields)?;
+
+        let sql = "insert into users_schema.users(name, email, display_name, status) values ($1, $2, $3, $4) returning *";
+        rb.fetch::<DbUser>(sql, field_args(fields))
+            .await
+            .map(User::from)
+            .map_err(UserDAOError::from)
+    }
+
+    async fn update_in<E: ExecutorMut + Send>(rb: &mut E, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        UserInMemoryDAO::validate_fields(fields)?;
+
+        let mut sql = String::from("update users_schema.users \
+            set name = $1, email = $2, display_name = $3, status = $4, version = version + 1, updated_at = now() \
+            where id = $5 and deleted_at is null");
+        let mut args = field_args(fields);
+        args.push(Bson::Int64(id as i64));
+        if let Some(versions) = expected_versions {
+            push_versions(&mut sql, &mut args, versions, |n| format!("${}", n));
+        }
+        sql.push_str(" returning *");
+
+        let updated = rb.fetch::<Option<DbUser>>(&sql, args)
+            .await
+            .map_err(UserDAOError::from)?;
+
+        match updated {
+            Some(user) => Ok(User::from(user)),
+            None => Err(UserDbDAO::not_written(rb, id).await),
+        }
+    }
+
+    async fn delete_in<E: ExecutorMut + Send>(rb: &mut E, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        let mut sql = String::from("update users_schema.users \
+            set deleted_at = now(), status = 'deleted', updated_at = now(), version = version + 1 \
+            where id = $1 and deleted_at is null");
+        let mut args = vec![Bson::Int64(id as i64)];
+        if let Some(versions) = expected_versions {
+            push_versions(&mut sql, &mut args, versions, |n| format!("${}", n));
+        }
+        sql.push_str(" returning *");
+
+        let deleted = rb.fetch::<Option<DbUser>>(&sql, args)
+            .await
+            .map_err(UserDAOError::from)?;
+
+        match deleted {
+            Some(user) => Ok(User::from(user)),
+            None => Err(UserDbDAO::not_written(rb, id).await),
+        }
+    }
+
+    async fn apply<E: ExecutorMut + Send>(rb: &mut E, op: &BatchOp) -> Result<User, UserDAOError> {
+        match op {
+            BatchOp::Create { fields } => UserDbDAO::create_in(rb, fields).await,
+            BatchOp::Update { id, version, fields } => UserDbDAO::update_in(rb, *id, fields, version.as_ref().map(slice::from_ref)).await,
+            BatchOp::Delete { id, version } => UserDbDAO::delete_in(rb, *id, version.as_ref().map(slice::from_ref)).await,
+        }
+    }
 }
 
 #[async_trait]
 impl UserDAO for UserDbDAO {
-    async fn list(&self) -> Result<Vec<User>, UserDAOError> {
-        let users = self.rb.fetch_list::<DbUser>().await;
+    async fn list(&self, query: &UsersQuery) -> Result<Page<User>, UserDAOError> {
+        UserInMemoryDAO::validate_query(query)?;
+
+        let asc = query.sort.order == SortOrder::Asc;
+        let order = match query.sort.field {
+            SortField::Id => vec![("id", asc)],
+            SortField::Name => vec![("name", asc), ("id", asc)],
+        };
+
+        let mut wrapper = self.rb.new_wrapper();
+        if !query.include_deleted {
+            wrapper = wrapper.is_null("deleted_at");
+        }
+        if let Some(prefix) = &query.name_prefix {
+            wrapper = wrapper.like_right("name", UserDbDAO::escape_like(prefix));
+        }
+        if let Some(substring) = &query.name_contains {
+            wrapper = wrapper.like("name", UserDbDAO::escape_like(substring));
+        }
+        let wrapper = wrapper.order_bys(&order);
+
+        let page_request = PageRequest::new(query.page, query.page_size);
+        let users = self.rb.fetch_page_by_wrapper::<DbUser>(wrapper, &page_request).await;
         users
-            .map(|db_users| {
-                db_users.iter()
-                    .map(|db_user | User { id: db_user.id, fields: UserFields {name: db_user.name.clone()} })
-                    .collect()
-            } )
-            .map_err(|err| UserDAOError {status: 500, message: err.to_string()})
+            .map(|db_page| Page {
+                items: db_page.records.into_iter()
+                    .map(User::from)
+                    .collect(),
+                total: db_page.total,
+            })
+            .map_err(UserDAOError::from)
     }
 
-    async fn find_by_id(&self, id: u64) -> Result<User, UserDAOError> {
-        let user = self.rb.fetch_by_column::<DbUser, u64>("id", id).await;
-        user
-            .map (|db_user| User { id: db_user.id, fields: UserFields {name: db_user.name.clone()} })
-            .map_err(|err| {
-                match err {
-                    rbatis::Error::E(_) => UserDAOError {status: 404, message: "User not found".to_string()},
-                    rbatis::Error::Deserialize(msg) => UserDAOError {status: 500, message: msg.to_string()},
-                    rbatis::Error::Database(msg) => UserDAOError {status: 500, message: msg.to_string()},
-                    _ => UserDAOError {status: 500, message: "Unexpected error".to_string()},
+    // A server-side cursor in a read-only transaction, fetched STREAM_CHUNK rows at a time
+    fn list_stream(&self, query: &UsersQuery) -> BoxStream<'static, Result<User, UserDAOError>> {
+        let rb = self.rb.clone();
+        let query = query.clone();
+
+        Box::pin(try_stream! {
+            UserInMemoryDAO::validate_query(&query)?;
+
+            let (filter, args) = UserDbDAO::filter_sql(&query, None);
+            let declare = format!("declare users_stream no scroll cursor for select * from users_schema.users{}{}", filter, order_sql(&query));
+            let fetch = format!("fetch forward {} from users_stream", STREAM_CHUNK);
+
+            let mut tx = TxGuard::begin(&rb).await?;
+            tx.exec("set transaction read only", vec![]).await?;
+            tx.exec(&declare, args).await?;
+            loop {
+                let chunk: Vec<DbUser> = tx.fetch(&fetch, vec![]).await?;
+                if chunk.is_empty() {
+                    break;
                 }
-            })
+                for user in chunk {
+                    yield User::from(user);
+                }
+            }
+            tx.commit().await?;
+        })
+    }
+
+    async fn list_after(&self, query: &UsersQuery, after: Option<&Cursor>, limit: u64) -> Result<Vec<User>, UserDAOError> {
+        UserInMemoryDAO::validate_query(query)?;
+
+        let (filter, args) = UserDbDAO::filter_sql(query, after);
+        let sql = format!("select * from users_schema.users{}{} limit {}", filter, order_sql(query), limit);
+        self.rb.fetch::<Vec<DbUser>>(&sql, args)
+            .await
+            .map(|users| users.into_iter().map(User::from).collect())
+            .map_err(UserDAOError::from)
+    }
+
+    // the trigram indexes serve both % and ilike, scores are those of search::similarity
+    async fn search(&self, query: &SearchQuery) -> Result<Page<SearchHit>, UserDAOError> {
+        query.validate()?;
+
+        let matches = "deleted_at is null \
+            and (name % $1 or display_name % $1 or name ilike $2 or display_name ilike $2)";
+        let args = vec![
+            Bson::String(query.q.clone()),
+            Bson::String(format!("%{}%", UserDbDAO::escape_like(&query.q))),
+        ];
+
+        let total: u64 = self.rb.fetch(&format!("select count(*) from users_schema.users where {}", matches), args.clone()).await?;
+        let sql = format!("select *, greatest(similarity(name, $1), similarity(coalesce(display_name, ''), $1))::float8 as score \
+            from users_schema.users where {} \
+            order by score desc, name asc, id asc limit {} offset {}", matches, query.page_size, query.offset());
+        let hits: Vec<DbSearchHit> = self.rb.fetch(&sql, args).await?;
+
+        Ok(Page {
+            items: hits.into_iter()
+                .map(|hit| SearchHit { score: hit.score, user: User::from(hit.user) })
+                .collect(),
+            total,
+        })
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<User, UserDAOError> {
+        UserDbDAO::fetch_by_id(&mut self.rb.acquire().await?, id).await?
+            .filter(|user| user.deleted_at.is_none())
+            .ok_or(UserDAOError::NotFound)
+    }
 
+    async fn find_by_name(&self, name: &str) -> Result<User, UserDAOError> {
+        let sql = "select * from users_schema.users where name = $1 and deleted_at is null";
+        self.rb.fetch::<Option<DbUser>>(sql, vec![Bson::String(name.to_string())])
+            .await?
+            .map(User::from)
+            .ok_or(UserDAOError::NotFound)
     }
 
     async fn create(&self, fields: &UserFields) -> Result<User, UserDAOError> {
-        
-        UserInMemoryDAO::validate_fields(&fields)?;
+        UserDbDAO::create_in(&mut self.rb.acquire().await?, fields).await
+    }
+
+    async fn update(&self, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        UserDbDAO::update_in(&mut self.rb.acquire().await?, id, fields, expected_versions).await
+    }
+
+    async fn delete_by_id(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        UserDbDAO::delete_in(&mut self.rb.acquire().await?, id, expected_versions).await
+    }
+
+    async fn restore(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        let mut sql = String::from("update users_schema.users \
+            set deleted_at = null, status = 'active', updated_at = now(), version = version + 1 \
+            where id = $1 and deleted_at is not null");
+        let mut args = vec![Bson::Int64(id as i64)];
+        if let Some(versions) = expected_versions {
+            push_versions(&mut sql, &mut args, versions, |n| format!("${}", n));
+        }
+        sql.push_str(" returning *");
 
-        let uid: u64 = UserDbDAO::insert_with_identity(&self.rb, &fields.name)
+        let restored = self.rb.fetch::<Option<DbUser>>(&sql, args)
             .await
-            .map_err(|err: rbatis::Error| UserDAOError {status: 400, message: err.to_string()})?;
+            .map_err(UserDAOError::from)?;
+
+        match restored {
+            Some(user) => Ok(User::from(user)),
+            None => restore_unchanged(UserDbDAO::fetch_by_id(&mut self.rb.acquire().await?, id).await?, expected_versions),
+        }
+    }
+
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        let args = vec![Bson::String(before.to_rfc3339())];
+        self.rb.exec("delete from users_schema.users where deleted_at < $1::timestamptz", args)
+            .await
+            .map(|result| result.rows_affected)
+            .map_err(UserDAOError::from)
+    }
+
+    async fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Result<User, UserDAOError>>, UserDAOError> {
+        let mut conn = self.rb.acquire().await?;
+
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            results.push(UserDbDAO::apply(&mut conn, op).await);
+        }
+        Ok(results)
+    }
+
+    async fn batch_atomic(&self, ops: &[BatchOp]) -> Result<Vec<User>, BatchError> {
+        let mut tx = TxGuard::begin(&self.rb).await?;
+
+        let mut applied = Vec::with_capacity(ops.len());
+        for (index, op) in ops.iter().enumerate() {
+            match UserDbDAO::apply(&mut *tx, op).await {
+                Ok(user) => applied.push(user),
+                Err(error) => {
+                    tx.rollback().await?;
+                    return Err(BatchError { index: Some(index), error });
+                },
+            }
+        }
+
+        tx.commit().await?;
+        Ok(applied)
+    }
 
-        Ok(User {id: uid, fields: fields.clone()})          
+    async fn health(&self) -> Result<(), UserDAOError> {
+        self.rb.fetch::<i64>("select 1", vec![]).await?;
+        Ok(())
     }
+}
+
+pub struct UserSqliteDAO {
+    // shared with the streams of list_stream which outlive the call
+    rb: Arc<Rbatis>,
+}
+
+// sqlx reads `*` rows of tables altered by ADDED_COLUMNS past their last column
+macro_rules! sqlite_columns {
+    () => { "id, name, version, email, display_name, status, created_at, updated_at, deleted_at" };
+}
 
-    async fn update(&self, user: &User) -> Result<User, UserDAOError> {
-        UserInMemoryDAO::validate_fields(&user.fields)?;
+impl UserSqliteDAO {
+    const CREATE_USERS_TABLE: &'static str = "CREATE TABLE IF NOT EXISTS users (\
+        id INTEGER PRIMARY KEY AUTOINCREMENT, \
+        name TEXT NOT NULL UNIQUE\
+    )";
+    // Columns added after the first release, appended to older files on startup.
+    // SQLite can not add columns with non constant defaults, timestamps are set by every write.
+    const ADDED_COLUMNS: &'static [(&'static str, &'static str)] = &[
+        ("version", "ALTER TABLE users ADD COLUMN version INTEGER NOT NULL DEFAULT 1"),
+        ("email", "ALTER TABLE users ADD COLUMN email TEXT"),
+        ("display_name", "ALTER TABLE users ADD COLUMN display_name TEXT"),
+        ("status", "ALTER TABLE users ADD COLUMN status TEXT NOT NULL DEFAULT 'active' \
+            CHECK (status IN ('active', 'suspended', 'deleted'))"),
+        ("created_at", "ALTER TABLE users ADD COLUMN created_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00.000Z'"),
+        ("updated_at", "ALTER TABLE users ADD COLUMN updated_at TEXT NOT NULL DEFAULT '1970-01-01T00:00:00.000Z'"),
+        ("deleted_at", "ALTER TABLE users ADD COLUMN deleted_at TEXT"),
+    ];
+    const CREATE_EMAIL_INDEX: &'static str = "CREATE UNIQUE INDEX IF NOT EXISTS users_email_lower_key ON users (lower(email))";
+    // older files may hold only one of the deleted status and deleted_at, like V9__user_deleted_status.sql
+    const SYNC_DELETED_STATUS: &'static [&'static str] = &[
+        "UPDATE users SET status = 'deleted' WHERE deleted_at IS NOT NULL AND status <> 'deleted'",
+        "UPDATE users SET deleted_at = updated_at WHERE deleted_at IS NULL AND status = 'deleted'",
+    ];
+    const NOW: &'static str = "strftime('%Y-%m-%dT%H:%M:%fZ', 'now')";
+    const RETURNING: &'static str = concat!(" returning ", sqlite_columns!());
+    const SELECT: &'static str = concat!("select ", sqlite_columns!(), " from users");
+    const SELECT_BY_ID: &'static str = concat!("select ", sqlite_columns!(), " from users where id = ?");
+
+    pub async fn new(cfg: &Sqlite) -> UserSqliteDAO {
+        let mut rbatis = Rbatis::new();
+        rbatis.set_log_plugin(SqlSpans::default());
+        let conn_str = format!("sqlite://{}?mode=rwc", cfg.path);
+
+        rbatis.link(&conn_str).await.expect("rbatis not linked to sqlite");
+        rbatis.exec(UserSqliteDAO::CREATE_USERS_TABLE, vec![]).await.expect("users table not created");
+
+        for (column, add_column) in UserSqliteDAO::ADDED_COLUMNS {
+            let args = vec![Bson::String(column.to_string())];
+            let count: u64 = rbatis.fetch("select count(*) from pragma_table_info('users') where name = ?", args)
+                .await.expect("users table not inspected");
+            if count == 0 {
+                rbatis.exec(add_column, vec![]).await.expect("users column not added");
+            }
+        }
+        rbatis.exec(UserSqliteDAO::CREATE_EMAIL_INDEX, vec![]).await.expect("email index not created");
+        for sync in UserSqliteDAO::SYNC_DELETED_STATUS {
+            rbatis.exec(sync, vec![]).await.expect("deleted status not synced");
+        }
 
-        let db_user = DbUser {id: user.id, name: user.fields.name.clone()};
-        UserDbDAO::update_by_id(&self.rb, &db_user)
+        UserSqliteDAO {
+            rb: Arc::new(rbatis),
+        }
+    }
+
+    pub fn pool(&self) -> Arc<Rbatis> {
+        self.rb.clone()
+    }
+
+    async fn fetch_by_id<E: ExecutorMut + Send>(rb: &mut E, id: u64) -> Result<Option<User>, UserDAOError> {
+        rb.fetch::<Option<SqliteUser>>(UserSqliteDAO::SELECT_BY_ID, vec![Bson::Int64(id as i64)])
             .await
-            .map_err(|err: rbatis::Error| UserDAOError {status: 400, message: err.to_string()})?;
-        
-        Ok(user.clone())
+            .map(|user| user.map(User::from))
+            .map_err(UserDAOError::from)
+    }
+
+    async fn not_written<E: ExecutorMut + Send>(rb: &mut E, id: u64) -> UserDAOError {
+        match UserSqliteDAO::fetch_by_id(rb, id).await {
+            Ok(Some(user)) if user.deleted_at.is_none() => UserDAOError::VersionMismatch,
+            Ok(_) => UserDAOError::NotFound,
+            Err(err) => err,
+        }
+    }
+
+    async fn create_in<E: ExecutorMut + Send>(rb: &mut E, fields: &UserFields) -> Result<User, UserDAOError> {
+        UserInMemoryDAO::validate_fields(fields)?;
+
+        let sql = format!("insert into users(name, email, display_name, status, created_at, updated_at) \
+            values (?, ?, ?, ?, {0}, {0}){1}", UserSqliteDAO::NOW, UserSqliteDAO::RETURNING);
+        rb.fetch::<SqliteUser>(&sql, field_args(fields))
+            .await
+            .map(User::from)
+            .map_err(UserDAOError::from)
+    }
+
+    async fn update_in<E: ExecutorMut + Send>(rb: &mut E, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        UserInMemoryDAO::validate_fields(fields)?;
+
+        let mut sql = format!("update users \
+            set name = ?, email = ?, display_name = ?, status = ?, version = version + 1, updated_at = {} \
+            where id = ? and deleted_at is null", UserSqliteDAO::NOW);
+        let mut args = field_args(fields);
+        args.push(Bson::Int64(id as i64));
+        if let Some(versions) = expected_versions {
+            push_versions(&mut sql, &mut args, versions, |_| "?".to_string());
+        }
+        sql.push_str(UserSqliteDAO::RETURNING);
+
+        let updated = rb.fetch::<Option<SqliteUser>>(&sql, args)
+            .await
+            .map_err(UserDAOError::from)?;
+
+        match updated {
+            Some(user) => Ok(User::from(user)),
+            None => Err(UserSqliteDAO::not_written(rb, id).await),
+        }
+    }
+
+    async fn delete_in<E: ExecutorMut + Send>(rb: &mut E, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        let mut sql = format!("update users \
+            set deleted_at = {0}, status = 'deleted', updated_at = {0}, version = version + 1 \
+            where id = ? and deleted_at is null", UserSqliteDAO::NOW);
+        let mut args = vec![Bson::Int64(id as i64)];
+        if let Some(versions) = expected_versions {
+            push_versions(&mut sql, &mut args, versions, |_| "?".to_string());
+        }
+        sql.push_str(UserSqliteDAO::RETURNING);
+
+        let deleted = rb.fetch::<Option<SqliteUser>>(&sql, args)
+            .await
+            .map_err(UserDAOError::from)?;
+
+        match deleted {
+            Some(user) => Ok(User::from(user)),
+            None => Err(UserSqliteDAO::not_written(rb, id).await),
+        }
+    }
+
+    // users matching the query that follow the cursor, in the query order
+    async fn fetch_after(rb: &Rbatis, query: &UsersQuery, after: Option<&Cursor>, limit: u64) -> Result<Vec<User>, UserDAOError> {
+        let mut conditions = vec![];
+        let mut args = vec![];
+        if !query.include_deleted {
+            conditions.push(String::from("deleted_at is null"));
+        }
+        if let Some(prefix) = &query.name_prefix {
+            conditions.push(String::from("instr(name, ?) = 1"));
+            args.push(Bson::String(prefix.clone()));
+        }
+        if let Some(substring) = &query.name_contains {
+            conditions.push(String::from("instr(name, ?) > 0"));
+            args.push(Bson::String(substring.clone()));
+        }
+        if let Some(cursor) = after {
+            conditions.push(after_sql(cursor, &mut args, |_| String::from("?")));
+        }
+
+        let mut sql = String::from(UserSqliteDAO::SELECT);
+        if !conditions.is_empty() {
+            sql.push_str(" where ");
+            sql.push_str(&conditions.join(" and "));
+        }
+        sql.push_str(order_sql(query));
+        sql.push_str(&format!(" limit {}", limit));
+
+        rb.fetch::<Vec<SqliteUser>>(&sql, args)
+            .await
+            .map(|users| users.into_iter().map(User::from).collect())
+            .map_err(UserDAOError::from)
     }
 
-    async fn delete_by_id(&self, id: u64) -> Result<User, UserDAOError> {
-        let user = self.find_by_id(id).await?;
-        self.rb.remove_by_column::<DbUser, u64>("id", id).await
-            .map_err(|err: rbatis::Error| UserDAOError {status: 500, message: err.to_string()})?;
-        Ok(user)
+    async fn apply<E: ExecutorMut + Send>(rb: &mut E, op: &BatchOp) -> Result<User, UserDAOError> {
+        match op {
+            BatchOp::Create { fields } => UserSqliteDAO::create_in(rb, fields).await,
+            BatchOp::Update { id, version, fields } => UserSqliteDAO::update_in(rb, *id, fields, version.as_ref().map(slice::from_ref)).await,
+            BatchOp::Delete { id, version } => UserSqliteDAO::delete_in(rb, *id, version.as_ref().map(slice::from_ref)).await,
+        }
+    }
+}
+
+#[async_trait]
+impl UserDAO for UserSqliteDAO {
+    async fn list(&self, query: &UsersQuery) -> Result<Page<User>, UserDAOError> {
+        UserInMemoryDAO::validate_query(query)?;
+
+        let asc = query.sort.order == SortOrder::Asc;
+        let order = match query.sort.field {
+            SortField::Id => vec![("id", asc)],
+            SortField::Name => vec![("name", asc), ("id", asc)],
+        };
+
+        // SQLite LIKE ignores case, instr keeps filters case sensitive as in the other DAOs
+        let mut wrapper = self.rb.new_wrapper();
+        if !query.include_deleted {
+            wrapper = wrapper.is_null("deleted_at");
+        }
+        if let Some(prefix) = &query.name_prefix {
+            wrapper = wrapper.and().push_sql("instr(name, ?) = 1").push_arg(prefix);
+        }
+        if let Some(substring) = &query.name_contains {
+            wrapper = wrapper.and().push_sql("instr(name, ?) > 0").push_arg(substring);
+        }
+        let wrapper = wrapper.order_bys(&order);
+
+        let page_request = PageRequest::new(query.page, query.page_size);
+        self.rb.fetch_page_by_wrapper::<SqliteUser>(wrapper, &page_request).await
+            .map(|db_page| Page {
+                items: db_page.records.into_iter()
+                    .map(User::from)
+                    .collect(),
+                total: db_page.total,
+            })
+            .map_err(UserDAOError::from)
+    }
+
+    // SQLite has no cursors, chunks continue after the sort key of the last user read
+    fn list_stream(&self, query: &UsersQuery) -> BoxStream<'static, Result<User, UserDAOError>> {
+        let rb = self.rb.clone();
+        let query = query.clone();
+
+        Box::pin(try_stream! {
+            UserInMemoryDAO::validate_query(&query)?;
+
+            let mut after: Option<Cursor> = None;
+            loop {
+                let chunk = UserSqliteDAO::fetch_after(&rb, &query, after.as_ref(), STREAM_CHUNK as u64).await?;
+                let done = chunk.len() < STREAM_CHUNK;
+                after = chunk.last().map(|user| Cursor::after(user, query.sort));
+                for user in chunk {
+                    yield user;
+                }
+                if done {
+                    break;
+                }
+            }
+        })
+    }
+
+    async fn list_after(&self, query: &UsersQuery, after: Option<&Cursor>, limit: u64) -> Result<Vec<User>, UserDAOError> {
+        UserInMemoryDAO::validate_query(query)?;
+        UserSqliteDAO::fetch_after(&self.rb, query, after, limit).await
+    }
+
+    // SQLite has no trigram index, active users are read in chunks and scored as in UserInMemoryDAO
+    async fn search(&self, query: &SearchQuery) -> Result<Page<SearchHit>, UserDAOError> {
+        query.validate()?;
+
+        let active = UsersQuery::default();
+        let mut hits = vec![];
+        let mut after: Option<Cursor> = None;
+        loop {
+            let chunk = UserSqliteDAO::fetch_after(&self.rb, &active, after.as_ref(), STREAM_CHUNK as u64).await?;
+            let done = chunk.len() < STREAM_CHUNK;
+            after = chunk.last().map(|user| Cursor::after(user, active.sort));
+            hits.extend(chunk.into_iter()
+                .filter_map(|user| search::score(&user, &query.q).map(|score| SearchHit { score, user })));
+            if done {
+                break;
+            }
+        }
+        Ok(search::page(hits, query))
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<User, UserDAOError> {
+        UserSqliteDAO::fetch_by_id(&mut self.rb.acquire().await?, id).await?
+            .filter(|user| user.deleted_at.is_none())
+            .ok_or(UserDAOError::NotFound)
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<User, UserDAOError> {
+        let sql = format!("{} where name = ? and deleted_at is null", UserSqliteDAO::SELECT);
+        self.rb.fetch::<Option<SqliteUser>>(&sql, vec![Bson::String(name.to_string())])
+            .await?
+            .map(User::from)
+            .ok_or(UserDAOError::NotFound)
+    }
+
+    async fn create(&self, fields: &UserFields) -> Result<User, UserDAOError> {
+        UserSqliteDAO::create_in(&mut self.rb.acquire().await?, fields).await
+    }
+
+    async fn update(&self, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        UserSqliteDAO::update_in(&mut self.rb.acquire().await?, id, fields, expected_versions).await
+    }
+
+    async fn delete_by_id(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        UserSqliteDAO::delete_in(&mut self.rb.acquire().await?, id, expected_versions).await
+    }
+
+    async fn restore(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        let mut sql = format!("update users \
+            set deleted_at = null, status = 'active', updated_at = {}, version = version + 1 \
+            where id = ? and deleted_at is not null", UserSqliteDAO::NOW);
+        let mut args = vec![Bson::Int64(id as i64)];
+        if let Some(versions) = expected_versions {
+            push_versions(&mut sql, &mut args, versions, |_| "?".to_string());
+        }
+        sql.push_str(UserSqliteDAO::RETURNING);
+
+        let restored = self.rb.fetch::<Option<SqliteUser>>(&sql, args)
+            .await
+            .map_err(UserDAOError::from)?;
+
+        match restored {
+            Some(user) => Ok(User::from(user)),
+            None => restore_unchanged(UserSqliteDAO::fetch_by_id(&mut self.rb.acquire().await?, id).await?, expected_versions),
+        }
+    }
+
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        // timestamps are stored as text in the NOW format, so they compare in time order
+        let args = vec![Bson::String(before.format("%Y-%m-%dT%H:%M:%S%.3fZ").to_string())];
+        self.rb.exec("delete from users where deleted_at < ?", args)
+            .await
+            .map(|result| result.rows_affected)
+            .map_err(UserDAOError::from)
+    }
+
+    async fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Result<User, UserDAOError>>, UserDAOError> {
+        let mut conn = self.rb.acquire().await?;
+
+        let mut results = Vec::with_capacity(ops.len());
+        for op in ops {
+            results.push(UserSqliteDAO::apply(&mut conn, op).await);
+        }
+        Ok(results)
+    }
+
+    async fn batch_atomic(&self, ops: &[BatchOp]) -> Result<Vec<User>, BatchError> {
+        let mut tx = TxGuard::begin(&self.rb).await?;
+
+        let mut applied = Vec::with_capacity(ops.len());
+        for (index, op) in ops.iter().enumerate() {
+            match UserSqliteDAO::apply(&mut *tx, op).await {
+                Ok(user) => applied.push(user),
+                Err(error) => {
+                    tx.rollback().await?;
+                    return Err(BatchError { index: Some(index), error });
+                },
+            }
+        }
+
+        tx.commit().await?;
+        Ok(applied)
+    }
+
+    async fn health(&self) -> Result<(), UserDAOError> {
+        self.rb.fetch::<i64>("select 1", vec![]).await?;
+        Ok(())
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use actix_web::http::StatusCode;
+    use actix_web::{http::StatusCode, ResponseError};
     use futures::executor::block_on;
 
-    use crate::{configs::InMemory, model::{User, UserDAOError, UserFields}};
+    use crate::{configs::InMemory, model::{Page, Sort, SortField, SortOrder, User, UserDAOError, UsersQuery}};
+    use crate::conformance::{deleted, fields, seeded, timeless, user, versioned};
+
+    use rbatis::core::db::DBConnectOption;
 
-    use super::{UserInMemoryDAO, UserDAO};
+    use crate::configs::{Db, MigrateMode};
+    use super::{UserDbDAO, UserInMemoryDAO, UserDAO};
 
     #[test]
     fn test_empty_list() {
         let dao = UserInMemoryDAO::new(None);
-        let users = block_on(dao.list());
-        assert_eq!(0, users.unwrap().len());
+        let users = block_on(dao.list(&UsersQuery::default()));
+        assert_eq!(0, users.unwrap().items.len());
     }
 
     #[test]
     fn test_non_empty_list() {
         let dao = UserInMemoryDAO::new(Some(&InMemory { users: 1}));
-        let users = block_on(dao.list());
-        assert_eq!(users, Ok(vec![User { id: 1, fields: UserFields { name: "User1".to_string() }}]));
+        let users = block_on(dao.list(&UsersQuery::default()));
+        let items: Vec<User> = users.unwrap().items.into_iter().map(timeless).collect();
+        assert_eq!(vec![seeded(1)], items);
     }
 
     #[test]
     fn test_find_by_id_ok() {
         let dao = UserInMemoryDAO::new(Some(&InMemory { users: 2}));
         let user2 = block_on(dao.find_by_id(2));
-        let expected = User{ id: 2, fields: UserFields { name: "User2".to_string() }};
-        assert_eq!(Ok(expected), user2);  
+        assert_eq!(Ok(seeded(2)), user2.map(timeless));
+        assert_eq!(Some("user2@example.com".to_string()), seeded(2).fields.email);
     }
 
     #[test]
@@ -261,10 +1181,7 @@ mod tests {
         let user5 = block_on(dao.find_by_id(5));
         assert_eq!(
             Err(
-                UserDAOError { 
-                    message: "User not found".to_string(), 
-                    status: StatusCode::NOT_FOUND.as_u16() 
-                }
+                UserDAOError::NotFound
             ), 
             user5);
     }
@@ -274,9 +1191,7 @@ mod tests {
         let dao = UserInMemoryDAO::new(None); 
         let user5 = block_on(dao.find_by_id(1));
         assert_eq!(
-            Err(UserDAOError { 
-                message: "User not found".to_string(), 
-                status: StatusCode::NOT_FOUND.as_u16()}
+            Err(UserDAOError::NotFound
             ), 
             user5);
     }
@@ -284,13 +1199,12 @@ mod tests {
     #[test]
     fn test_create_on_empty_list() {
         let dao = UserInMemoryDAO::new(None);
-        let user = block_on(dao.create(&UserFields { name: "User".to_string() })).unwrap();
-        let expected = User { id: 1, fields: UserFields{ name: "User".to_string() }};
+        let expected = block_on(dao.create(&fields("User"))).unwrap();
 
-        assert_eq!(expected, user);
+        assert_eq!(user(1, "User"), timeless(expected.clone()));
 
-        let user_in_list = block_on(dao.list()).unwrap().contains(&expected);
-        assert_eq!(true, user_in_list);
+        let user_in_list = block_on(dao.list(&UsersQuery::default())).unwrap().items.contains(&expected);
+        assert!(user_in_list);
 
         let finded_user = block_on(dao.find_by_id(expected.id));
         assert_eq!(Ok(expected) ,finded_user);
@@ -299,69 +1213,226 @@ mod tests {
     #[test]
     fn test_create_with_existing_name() {
         let dao = UserInMemoryDAO::new(Some(&InMemory {users: 1}));
-        let result = block_on(dao.create(&UserFields { name: "User1".to_string() }));
-        assert_eq!(Err(UserDAOError {message: "User exists".to_string(), status: StatusCode::BAD_REQUEST.as_u16()}), result);
+        let result = block_on(dao.create(&fields("User1")));
+        assert_eq!(Err(UserDAOError::AlreadyExists), result);
     }
 
     #[test]
     fn test_create_with_empty_name() {
         let dao = UserInMemoryDAO::new(None);
-        let result = block_on(dao.create(&UserFields { name: "".to_string() }));
-        assert_eq!(Err(UserDAOError {
-            message: "Validation failed for: field: 'name' errors: 'length, regex'".to_string(),
-            status: StatusCode::BAD_REQUEST.as_u16()
-        }), result);
+        let result = block_on(dao.create(&fields(""))).unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, result.status_code());
+        assert_eq!("Validation failed for: field: 'name' errors: 'length, regex'", result.to_string());
     }
 
     #[test]
     fn test_update_existed() {
         let dao = UserInMemoryDAO::new(Some(&InMemory {users: 2}));
-        let updated_user = User {id: 2, fields: UserFields { name: "Update_user".to_string() } };
-        let user = block_on(dao.update(&updated_user)).unwrap();
-        assert_eq!(updated_user, user);
+        let updated_user = versioned(2, 2, "Update_user");
+        let user = block_on(dao.update(2, &updated_user.fields, None)).unwrap();
+        assert_eq!(updated_user, timeless(user));
 
         let finded_user2= block_on(dao.find_by_id(2)).unwrap();
-        assert_eq!(updated_user, finded_user2);
+        assert_eq!(updated_user, timeless(finded_user2));
 
         let finded_user1 = block_on(dao.find_by_id(1)).unwrap();
-        assert_eq!(User {id: 1, fields: UserFields { name: "User1".to_string() }}, finded_user1);
+        assert_eq!(seeded(1), timeless(finded_user1));
     }
 
     #[test]
     fn test_update_non_existed() {
         let dao = UserInMemoryDAO::new(Some(&InMemory {users: 1}));
-        let non_existed_user = User {id: 2, fields: UserFields { name: "Test".to_string() }};
-        let result = block_on(dao.update(&non_existed_user)).unwrap_err();
+        let non_existed_user = user(2, "Test");
+        let result = block_on(dao.update(non_existed_user.id, &non_existed_user.fields, None)).unwrap_err();
 
-        assert_eq!(UserDAOError { message: "User not found".to_string(), status: StatusCode::BAD_REQUEST.as_u16() }, result);
+        assert_eq!(UserDAOError::NotFound, result);
 
-        let exists = block_on(dao.list()).unwrap().contains(&User {id: 1, fields: UserFields { name: "User1".to_string() }});
-        assert_eq!(true, exists);
+        let exists = block_on(dao.list(&UsersQuery::default())).unwrap().items.into_iter().map(timeless).any(|u| u == seeded(1));
+        assert!(exists);
     }
 
     #[test]
     fn test_delete_existed() {
         let dao = UserInMemoryDAO::new(Some(&InMemory {users: 1}));
-        assert_eq!(false, block_on(dao.list()).unwrap().is_empty());
+        assert!(!block_on(dao.list(&UsersQuery::default())).unwrap().items.is_empty());
 
-        let expected_user = User {id: 1, fields: UserFields { name: "User1".to_string() }};
-        let deleted_user = block_on(dao.delete_by_id(expected_user.id)).unwrap();
+        let expected_user = deleted(seeded(1), 2);
+        let deleted_user = block_on(dao.delete_by_id(expected_user.id, None)).unwrap();
         
-        assert_eq!(expected_user, deleted_user);
+        assert_eq!(expected_user, timeless(deleted_user));
 
-        assert_eq!(true, block_on(dao.list()).unwrap().is_empty());
+        assert!(block_on(dao.list(&UsersQuery::default())).unwrap().items.is_empty());
     }
 
     #[test]
     fn test_delete_not_existed() {
         let dao = UserInMemoryDAO::new(Some(&InMemory {users: 0}));
-        assert_eq!(true, block_on(dao.list()).unwrap().is_empty());
+        assert!(block_on(dao.list(&UsersQuery::default())).unwrap().items.is_empty());
+
+        let result = block_on(dao.delete_by_id(1, None)).unwrap_err();
+        assert_eq!(UserDAOError::NotFound, result);
 
-        let result = block_on(dao.delete_by_id(1)).unwrap_err();
-        assert_eq!(UserDAOError {message: "User not found".to_string(), status: StatusCode::BAD_REQUEST.as_u16()}, result);
+        assert!(block_on(dao.list(&UsersQuery::default())).unwrap().items.is_empty());
+    }
 
-        assert_eq!(true, block_on(dao.list()).unwrap().is_empty());
+    fn names(page: &Page<User>) -> Vec<String> {
+        page.items.iter().map(|u| u.fields.name.clone()).collect()
     }
 
+    #[test]
+    fn test_list_pages() {
+        let dao = UserInMemoryDAO::new(Some(&InMemory {users: 5}));
+
+        let page = block_on(dao.list(&UsersQuery { page: 2, page_size: 2, ..UsersQuery::default() })).unwrap();
+        assert_eq!(5, page.total);
+        assert_eq!(vec!["User3", "User4"], names(&page));
+
+        let last = block_on(dao.list(&UsersQuery { page: 3, page_size: 2, ..UsersQuery::default() })).unwrap();
+        assert_eq!(vec!["User5"], names(&last));
+
+        let beyond = block_on(dao.list(&UsersQuery { page: 4, page_size: 2, ..UsersQuery::default() })).unwrap();
+        assert_eq!(5, beyond.total);
+        assert!(beyond.items.is_empty());
+    }
+
+    #[test]
+    fn test_list_sorted_desc() {
+        let dao = UserInMemoryDAO::new(Some(&InMemory {users: 3}));
+        let query = UsersQuery { sort: Sort { field: SortField::Id, order: SortOrder::Desc }, ..UsersQuery::default() };
+        let page = block_on(dao.list(&query)).unwrap();
+        assert_eq!(vec!["User3", "User2", "User1"], names(&page));
+    }
+
+    #[test]
+    fn test_list_sorted_by_name() {
+        let dao = UserInMemoryDAO::new(Some(&InMemory {users: 2}));
+        block_on(dao.create(&fields("Alice"))).unwrap();
+
+        let query = UsersQuery { sort: Sort { field: SortField::Name, order: SortOrder::Asc }, ..UsersQuery::default() };
+        let page = block_on(dao.list(&query)).unwrap();
+        assert_eq!(vec!["Alice", "User1", "User2"], names(&page));
+    }
+
+    #[test]
+    fn test_list_filtered() {
+        let dao = UserInMemoryDAO::new(Some(&InMemory {users: 12}));
+
+        let by_prefix = UsersQuery { name_prefix: Some("User1".to_string()), ..UsersQuery::default() };
+        let page = block_on(dao.list(&by_prefix)).unwrap();
+        assert_eq!(4, page.total);
+        assert_eq!(vec!["User1", "User10", "User11", "User12"], names(&page));
+
+        let by_substring = UsersQuery { name_contains: Some("2".to_string()), ..UsersQuery::default() };
+        let page = block_on(dao.list(&by_substring)).unwrap();
+        assert_eq!(vec!["User2", "User12"], names(&page));
+    }
+
+    #[test]
+    fn test_list_invalid_page_size() {
+        let dao = UserInMemoryDAO::new(None);
+        let result = block_on(dao.list(&UsersQuery { page_size: 0, ..UsersQuery::default() })).unwrap_err();
+        assert_eq!(StatusCode::BAD_REQUEST, result.status_code());
+    }
+
+    #[test]
+    fn test_connection_str_special_characters() {
+        let cfg = Db {
+            host: "db.example.com".to_string(),
+            port: 5433,
+            db_name: "users db".to_string(),
+            user: "rw@user".to_string(),
+            password: "p@ss/w:rd#1 %+&=?".to_string(),
+            migrate: MigrateMode::Auto,
+        };
+        let option = DBConnectOption::from(&UserDbDAO::connection_str(&cfg)).unwrap();
+        let parsed = format!("{:?}", option.postgres.unwrap());
+        for part in [r#"host: "db.example.com""#, "port: 5433", r#"username: "rw@user""#,
+            r#"password: Some("p@ss/w:rd#1 %+&=?")"#, r#"database: Some("users db")"#] {
+            assert!(parsed.contains(part), "{} not in {}", part, parsed);
+        }
+    }
 }
 
+#[cfg(test)]
+mod in_memory_conformance {
+    use crate::configs::InMemory;
+    use crate::conformance::user_dao_conformance;
+
+    use super::{UserDAO, UserInMemoryDAO};
+
+    async fn dao(users: u16) -> Box<dyn UserDAO> {
+        Box::new(UserInMemoryDAO::new(Some(&InMemory { users })))
+    }
+
+    user_dao_conformance!(dao);
+}
+
+#[cfg(test)]
+mod sqlite_conformance {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use crate::configs::Sqlite;
+    use crate::conformance::user_dao_conformance;
+    use crate::model::UserFields;
+
+    use super::{UserDAO, UserInMemoryDAO, UserSqliteDAO};
+
+    static DB_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+    async fn dao(users: u16) -> Box<dyn UserDAO> {
+        let file_name = format!("users_{}_{}.db", std::process::id(), DB_COUNTER.fetch_add(1, Ordering::SeqCst));
+        let path = std::env::temp_dir().join(file_name);
+        let _ = std::fs::remove_file(&path);
+
+        let dao = UserSqliteDAO::new(&Sqlite { path: path.to_string_lossy().to_string() }).await;
+        for i in 1 .. users + 1 {
+            dao.create(&UserInMemoryDAO::seed_fields(i)).await.unwrap();
+        }
+        Box::new(dao)
+    }
+
+    user_dao_conformance!(dao);
+
+    #[actix_web::test]
+    async fn test_adds_version_column_to_existing_file() {
+        let file_name = format!("users_{}_{}.db", std::process::id(), DB_COUNTER.fetch_add(1, Ordering::SeqCst));
+        let path = std::env::temp_dir().join(file_name).to_string_lossy().to_string();
+        let _ = std::fs::remove_file(&path);
+
+        let rb = rbatis::rbatis::Rbatis::new();
+        rb.link(&format!("sqlite://{}?mode=rwc", path)).await.unwrap();
+        rb.exec("CREATE TABLE users (id INTEGER PRIMARY KEY AUTOINCREMENT, name TEXT NOT NULL UNIQUE)", vec![]).await.unwrap();
+        rb.exec("insert into users(name) values ('User1')", vec![]).await.unwrap();
+
+        let dao = UserSqliteDAO::new(&Sqlite { path }).await;
+        assert_eq!(1, dao.find_by_id(1).await.unwrap().version);
+        assert_eq!(2, dao.update(1, &UserFields { name: "User2".to_string(), ..UserFields::default() }, Some(&[1])).await.unwrap().version);
+    }
+}
+
+// Runs against the database from tests/db_full.yaml, created with sql/create_users_database.sql.
+// The tests share one table, so run them one by one:
+// cargo test postgres_conformance -- --ignored --test-threads=1
+#[cfg(test)]
+mod postgres_conformance {
+    use crate::configs::Configuration;
+    use crate::conformance::user_dao_conformance;
+    use crate::migrations;
+
+    use super::{UserDAO, UserDbDAO, UserInMemoryDAO};
+
+    async fn dao(users: u16) -> Box<dyn UserDAO> {
+        let cfg = Configuration::load_from_file("tests/db_full.yaml").unwrap();
+        let db = cfg.store.unwrap().db.unwrap();
+        migrations::migrate(&db).await.unwrap();
+
+        let dao = UserDbDAO::new(&db).await;
+        dao.rb.exec("TRUNCATE users_schema.users RESTART IDENTITY CASCADE", vec![]).await.unwrap();
+        for i in 1 .. users + 1 {
+            dao.create(&UserInMemoryDAO::seed_fields(i)).await.unwrap();
+        }
+        Box::new(dao)
+    }
+
+    user_dao_conformance!(dao, #[ignore = "needs a local Postgres"]);
+}
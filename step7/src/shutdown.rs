@@ -0,0 +1,324 @@
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{forward_ready, Server, Service, ServiceRequest, ServiceResponse, Transform},
+    rt,
+    web::{Bytes, Data},
+    Error,
+};
+use futures::{future::{join_all, LocalBoxFuture}, FutureExt};
+use rbatis::rbatis::Rbatis;
+use tokio::sync::watch;
+
+use crate::configs::ServerConfig;
+use crate::health::Readiness;
+
+// how often the drain checks for requests still running
+const DRAIN_POLL: Duration = Duration::from_millis(50);
+
+// Handed to background tasks, resolves once shutdown started
+#[derive(Clone)]
+pub struct Cancellation(watch::Receiver<bool>);
+
+impl Cancellation {
+    pub async fn cancelled(&mut self) {
+        // the sender lives in Tasks, dropping it cancels as well
+        let _ = self.0.wait_for(|cancelled| *cancelled).await;
+    }
+}
+
+// Background tasks of the server, cancelled cooperatively on shutdown
+pub struct Tasks {
+    cancel: watch::Sender<bool>,
+    handles: Vec<rt::task::JoinHandle<()>>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Tasks { cancel: watch::channel(false).0, handles: Vec::new() }
+    }
+}
+
+impl Tasks {
+    pub fn cancellation(&self) -> Cancellation {
+        Cancellation(self.cancel.subscribe())
+    }
+
+    pub fn add(&mut self, handle: rt::task::JoinHandle<()>) {
+        self.handles.push(handle);
+    }
+
+    // tasks finish the work at hand until the deadline, the ones still running are aborted then
+    async fn stop(mut self, deadline: Instant) {
+        let _ = self.cancel.send(true);
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if rt::time::timeout(remaining, join_all(self.handles.iter_mut())).await.is_err() {
+            let running = self.handles.iter().filter(|handle| !handle.is_finished()).count();
+            log::warn!("{} background tasks not stopped in time, aborting them", running);
+            self.handles.iter().for_each(|handle| handle.abort());
+        }
+    }
+}
+
+// Middleware counting the requests in flight, a response counts until its body is sent.
+// The graceful stop of actix-server may drop connections still served, shutdown drains by this count instead.
+#[derive(Clone, Default)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl InFlight {
+    pub fn count(&self) -> usize {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn enter(&self) -> InFlightGuard {
+        self.0.fetch_add(1, Ordering::SeqCst);
+        InFlightGuard(self.0.clone())
+    }
+}
+
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for InFlight
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<TrackedBody<B>>;
+    type Error = Error;
+    type Transform = InFlightMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<InFlightMiddleware<S>, ()>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(InFlightMiddleware { service: Rc::new(service), in_flight: self.clone() }))
+    }
+}
+
+pub struct InFlightMiddleware<S> {
+    service: Rc<S>,
+    in_flight: InFlight,
+}
+
+impl<S, B> Service<ServiceRequest> for InFlightMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: MessageBody + 'static,
+{
+    type Response = ServiceResponse<TrackedBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<ServiceResponse<TrackedBody<B>>, Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let guard = self.in_flight.enter();
+        async move {
+            let res = service.call(req).await?;
+            Ok(res.map_body(|_, body| TrackedBody { body: Box::pin(body), _guard: guard }))
+        }.boxed_local()
+    }
+}
+
+// Body keeping its request in flight until sent or dropped
+pub struct TrackedBody<B> {
+    body: Pin<Box<B>>,
+    _guard: InFlightGuard,
+}
+
+impl<B: MessageBody> MessageBody for TrackedBody<B> {
+    type Error = B::Error;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        self.get_mut().body.as_mut().poll_next(cx)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Shutdown {
+    pub timeout: Duration,
+    pub delay: Duration,
+}
+
+impl Shutdown {
+    pub fn from_config(cfg: &ServerConfig) -> Shutdown {
+        Shutdown {
+            timeout: Duration::from_secs(cfg.shutdown_timeout_secs),
+            delay: Duration::from_secs(cfg.shutdown_delay_secs),
+        }
+    }
+
+    // Runs the server until the signal, then stops in order: readiness fails, after the delay the server
+    // stops accepting and the requests in flight get to finish, background tasks stop and the pool is closed.
+    // All of it is done within the timeout from the signal on. The server is expected to be built with signals disabled.
+    pub async fn serve(self, server: Server, signal: impl Future<Output = ()> + 'static, readiness: Data<Readiness>,
+        in_flight: InFlight, tasks: Tasks, pool: Option<Arc<Rbatis>>) -> std::io::Result<()> {
+        let server_handle = server.handle();
+        let stopping = rt::spawn(async move {
+            signal.await;
+            let deadline = Instant::now() + self.timeout;
+            log::info!("Shutdown signal received, draining {} requests in flight", in_flight.count());
+            readiness.shut_down();
+            rt::time::sleep(self.delay.min(self.timeout)).await;
+            server_handle.pause().await;
+            while in_flight.count() > 0 && Instant::now() < deadline {
+                rt::time::sleep(DRAIN_POLL).await;
+            }
+            if in_flight.count() > 0 {
+                log::warn!("{} requests still in flight after {} s, dropping them", in_flight.count(), self.timeout.as_secs());
+            }
+            // idle keep-alive connections are closed right away
+            server_handle.stop(false).await;
+            deadline
+        });
+        server.await?;
+
+        let deadline = stopping.await.unwrap_or_else(|_| Instant::now());
+        tasks.stop(deadline).await;
+        if let Some(pool) = pool {
+            match pool.get_pool() {
+                Ok(pool) => pool.close().await,
+                Err(err) => log::warn!("Connection pool not closed: {}", err),
+            }
+        }
+        log::info!("Server stopped");
+        Ok(())
+    }
+}
+
+// SIGTERM of orchestrators or Ctrl-C. SIGTERM is listened to from the call on, not only once awaited.
+pub fn signal() -> std::io::Result<impl Future<Output = ()>> {
+    #[cfg(unix)]
+    {
+        use actix_web::rt::signal::unix::{self, SignalKind};
+        let mut terminate = unix::signal(SignalKind::terminate())?;
+        Ok(async move {
+            futures::future::select(Box::pin(terminate.recv()), Box::pin(rt::signal::ctrl_c())).await;
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        Ok(async {
+            let _ = rt::signal::ctrl_c().await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    use actix_web::{rt, web::{self, Data}, App, HttpResponse, HttpServer};
+    use tokio::sync::Notify;
+
+    use crate::configs::{Purge, Sqlite};
+    use crate::conformance::fields;
+    use crate::health::Readiness;
+    use crate::purge;
+    use crate::services::{UserDAO, UserSqliteDAO};
+    use super::{InFlight, Shutdown, Tasks};
+
+    fn get(addr: std::net::SocketAddr, path: &str) -> std::io::Result<String> {
+        let mut stream = std::net::TcpStream::connect(addr)?;
+        write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path)?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response)?;
+        Ok(response)
+    }
+
+    #[actix_web::test]
+    async fn test_tasks_stop_by_one_deadline() {
+        let mut tasks = Tasks::default();
+        let mut cancellation = tasks.cancellation();
+        tasks.add(rt::spawn(async move { cancellation.cancelled().await }));
+        // deaf to cancellation, aborted at the deadline
+        for _ in 0..3 {
+            tasks.add(rt::spawn(rt::time::sleep(Duration::from_secs(60))));
+        }
+
+        let start = Instant::now();
+        tasks.stop(start + Duration::from_millis(200)).await;
+        assert!(start.elapsed() < Duration::from_millis(400), "{:?}", start.elapsed());
+    }
+
+    #[actix_web::test]
+    async fn test_drains_in_flight_request() {
+        let path = std::env::temp_dir().join(format!("shutdown-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sqlite = UserSqliteDAO::new(&Sqlite { path: path.to_string_lossy().to_string() }).await;
+        let pool = sqlite.pool();
+        let dao: Data<Box<dyn UserDAO>> = Data::new(Box::new(sqlite));
+        let readiness = Data::new(Readiness::default());
+        let in_flight = InFlight::default();
+
+        let mut tasks = Tasks::default();
+        tasks.add(purge::spawn(dao.clone(), &Purge { retention_days: 30, interval_secs: 3600 }, tasks.cancellation()));
+
+        // the handler writes to the store long after the signal
+        let started = Arc::new(Notify::new());
+        let app_started = started.clone();
+        let app_dao = dao.clone();
+        let app_in_flight = in_flight.clone();
+        let server = HttpServer::new(move || {
+            let started = app_started.clone();
+            App::new()
+                .app_data(app_dao.clone())
+                .wrap(app_in_flight.clone())
+                .route("/slow", web::get().to(move |dao: Data<Box<dyn UserDAO>>| {
+                    let started = started.clone();
+                    async move {
+                        started.notify_one();
+                        rt::time::sleep(Duration::from_millis(500)).await;
+                        let user = dao.create(&fields("Latecomer")).await.unwrap();
+                        HttpResponse::Ok().body(user.id.to_string())
+                    }
+                }))
+        })
+        .workers(1)
+        .disable_signals()
+        .bind(("127.0.0.1", 0)).unwrap();
+        let addr = server.addrs()[0];
+        let shutdown = Shutdown { timeout: Duration::from_secs(5), delay: Duration::from_millis(100) };
+        // stands in for SIGTERM, the real signal is sent to the binary in tests/shutdown.rs
+        let (stop, stopped) = futures::channel::oneshot::channel::<()>();
+        let signal = async { let _ = stopped.await; };
+        let serving = rt::spawn(shutdown.serve(server.run(), signal, readiness.clone(), in_flight.clone(), tasks, Some(pool)));
+
+        let request = rt::task::spawn_blocking(move || get(addr, "/slow"));
+        started.notified().await;
+        let start = Instant::now();
+        stop.send(()).unwrap();
+
+        let response = request.await.unwrap().unwrap();
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.ends_with("\r\n\r\n1"), "{}", response);
+        assert!(readiness.is_shutting_down());
+
+        serving.await.unwrap().unwrap();
+        // the purge task stopped on cancellation instead of being aborted at the timeout
+        assert!(start.elapsed() < Duration::from_secs(5));
+        assert_eq!(0, in_flight.count());
+        assert!(rt::task::spawn_blocking(move || get(addr, "/slow")).await.unwrap().is_err());
+        assert!(dao.health().await.is_err());
+        let _ = std::fs::remove_file(&path);
+    }
+}
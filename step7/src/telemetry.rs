@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use actix_web::http::header::HeaderMap;
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use futures::{stream::BoxStream, StreamExt};
+use opentelemetry::{propagation::{Extractor, TextMapPropagator}, Context};
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{
+    error::{OTelSdkError, OTelSdkResult},
+    propagation::TraceContextPropagator,
+    trace::{Sampler, SdkTracerProvider, SpanData, SpanExporter},
+    Resource,
+};
+use rbatis::plugin::log::{LogPlugin, RbatisLogPlugin};
+use serde_json::{json, Map, Value};
+use tracing::{field::Empty, Instrument, Span};
+
+use crate::configs::{TraceExporter, Tracing};
+use crate::model::{BatchError, BatchOp, Cursor, Page, SearchHit, SearchQuery, User, UserDAOError, UserFields, UsersQuery};
+use crate::services::UserDAO;
+
+// statements in flight before the ones of cancelled queries are dropped
+const MAX_OPEN_STATEMENTS: usize = 1024;
+const STALE_STATEMENT: Duration = Duration::from_secs(300);
+
+// OTLP spans leave in batches from a thread of their own, stdout spans are written as they end
+pub fn tracer_provider(cfg: &Tracing) -> std::io::Result<SdkTracerProvider> {
+    if !(0.0..=1.0).contains(&cfg.sample_ratio) {
+        return Err(std::io::Error::other(format!("Invalid tracing sample_ratio {}, must be between 0 and 1", cfg.sample_ratio)));
+    }
+    let builder = SdkTracerProvider::builder()
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(cfg.sample_ratio))))
+        .with_resource(Resource::builder().with_service_name(cfg.service_name.clone()).build());
+    let builder = match cfg.exporter {
+        TraceExporter::Otlp => {
+            let exporter = opentelemetry_otlp::SpanExporter::builder()
+                .with_http()
+                .with_endpoint(cfg.endpoint.clone())
+                .build()
+                .map_err(|err| std::io::Error::other(format!("OTLP exporter error: {}", err)))?;
+            builder.with_batch_exporter(exporter)
+        },
+        TraceExporter::Stdout => builder.with_simple_exporter(StdoutExporter::new(std::io::stdout())),
+    };
+    Ok(builder.build())
+}
+
+// W3C trace context of the caller, empty without a valid traceparent header
+pub fn remote_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+// Writes every span as a JSON line
+pub struct StdoutExporter {
+    out: Mutex<Box<dyn Write + Send>>,
+}
+
+impl StdoutExporter {
+    pub fn new(out: impl Write + Send + 'static) -> StdoutExporter {
+        StdoutExporter { out: Mutex::new(Box::new(out)) }
+    }
+}
+
+impl std::fmt::Debug for StdoutExporter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("StdoutExporter")
+    }
+}
+
+impl SpanExporter for StdoutExporter {
+    async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+        let mut out = self.out.lock().unwrap();
+        for span in batch {
+            let line = span_json(&span).to_string();
+            writeln!(out, "{}", line).map_err(|err| OTelSdkError::InternalFailure(err.to_string()))?;
+        }
+        out.flush().map_err(|err| OTelSdkError::InternalFailure(err.to_string()))
+    }
+}
+
+fn span_json(span: &SpanData) -> Value {
+    let attributes: Map<String, Value> = span.attributes.iter()
+        .map(|kv| (kv.key.to_string(), Value::String(kv.value.to_string())))
+        .collect();
+    let duration = span.end_time.duration_since(span.start_time).unwrap_or_default();
+    json!({
+        "name": span.name,
+        "trace_id": span.span_context.trace_id().to_string(),
+        "span_id": span.span_context.span_id().to_string(),
+        "parent_span_id": span.parent_span_id.to_string(),
+        "kind": format!("{:?}", span.span_kind),
+        "start": DateTime::<Utc>::from(span.start_time).to_rfc3339(),
+        "duration_ms": duration.as_secs_f64() * 1000.0,
+        "status": format!("{:?}", span.status),
+        "attributes": attributes,
+    })
+}
+
+// rbatis log plugin opening a span per SQL statement, rbatis logs every statement before and after it runs.
+// The statements are still logged like by the default plugin, their arguments are not.
+#[derive(Debug, Default)]
+pub struct SqlSpans {
+    log: RbatisLogPlugin,
+    // by rbatis task id, with their start to drop the ones of cancelled queries
+    open: Mutex<HashMap<i64, (Span, Instant)>>,
+}
+
+impl SqlSpans {
+    fn trace(&self, id: i64, data: &str, failed: bool) {
+        if let Some((_, statement)) = data.split_once("==> ") {
+            // the arguments follow on the next line, they may hold password hashes
+            let statement = statement.lines().next().unwrap_or_default().trim();
+            let verb = statement.split_whitespace().next().unwrap_or_default().to_uppercase();
+            let span = tracing::info_span!("sql", otel.name = %verb, otel.kind = "client", db.statement = %statement,
+                db.rows = Empty, otel.status_code = Empty, error = Empty);
+            let mut open = self.open.lock().unwrap();
+            if open.len() >= MAX_OPEN_STATEMENTS {
+                open.retain(|_, (_, started)| started.elapsed() < STALE_STATEMENT);
+            }
+            open.insert(id, (span, Instant::now()));
+        } else if let Some((outcome, value)) = data.split_once("<== ") {
+            let Some((span, _)) = self.open.lock().unwrap().remove(&id) else { return };
+            if failed {
+                span.record("otel.status_code", "ERROR");
+                span.record("error", value.trim());
+            } else if outcome.starts_with("ReturnRows") || outcome.starts_with("RowsAffected") {
+                span.record("db.rows", value.trim());
+            }
+        }
+    }
+}
+
+impl LogPlugin for SqlSpans {
+    fn get_level_filter(&self) -> log::LevelFilter {
+        self.log.get_level_filter()
+    }
+
+    fn set_level_filter(&self, level: log::LevelFilter) {
+        self.log.set_level_filter(level)
+    }
+
+    fn info(&self, id: i64, data: &str) {
+        // statements are logged without their arguments on the next line, they may hold password hashes
+        self.log.info(id, data.lines().next().unwrap_or_default());
+        self.trace(id, data, false);
+    }
+
+    fn error(&self, id: i64, data: &str) {
+        self.log.error(id, data);
+        self.trace(id, data, true);
+    }
+}
+
+// UserDAO running every call of the wrapped store in a span, the SQL spans of the call are its children
+pub struct TracedUserDAO {
+    inner: Box<dyn UserDAO>,
+    backend: &'static str,
+}
+
+impl TracedUserDAO {
+    pub fn new(inner: Box<dyn UserDAO>, backend: &'static str) -> TracedUserDAO {
+        TracedUserDAO { inner, backend }
+    }
+
+    fn span(&self, operation: &str) -> Span {
+        tracing::info_span!("dao", otel.name = %format!("UserDAO.{}", operation), dao.operation = operation,
+            dao.backend = self.backend, otel.status_code = Empty, error = Empty)
+    }
+
+    async fn trace<T, E: std::fmt::Debug>(&self, operation: &str, call: impl std::future::Future<Output = Result<T, E>>) -> Result<T, E> {
+        let span = self.span(operation);
+        let result = call.instrument(span.clone()).await;
+        if let Err(err) = &result {
+            span.record("otel.status_code", "ERROR");
+            span.record("error", format!("{:?}", err));
+        }
+        result
+    }
+}
+
+#[async_trait]
+impl UserDAO for TracedUserDAO {
+    async fn list(&self, query: &UsersQuery) -> Result<Page<User>, UserDAOError> {
+        self.trace("list", self.inner.list(query)).await
+    }
+
+    // the span covers the whole stream, it ends when the stream is dropped
+    fn list_stream(&self, query: &UsersQuery) -> BoxStream<'static, Result<User, UserDAOError>> {
+        let span = self.span("list_stream");
+        let mut users = span.in_scope(|| self.inner.list_stream(query));
+        async_stream::stream! {
+            while let Some(user) = users.next().instrument(span.clone()).await {
+                yield user;
+            }
+        }.boxed()
+    }
+
+    async fn list_after(&self, query: &UsersQuery, after: Option<&Cursor>, limit: u64) -> Result<Vec<User>, UserDAOError> {
+        self.trace("list_after", self.inner.list_after(query, after, limit)).await
+    }
+
+    async fn search(&self, query: &SearchQuery) -> Result<Page<SearchHit>, UserDAOError> {
+        self.trace("search", self.inner.search(query)).await
+    }
+
+    async fn find_by_id(&self, id: u64) -> Result<User, UserDAOError> {
+        self.trace("find_by_id", self.inner.find_by_id(id)).await
+    }
+
+    async fn find_by_name(&self, name: &str) -> Result<User, UserDAOError> {
+        self.trace("find_by_name", self.inner.find_by_name(name)).await
+    }
+
+    async fn create(&self, fields: &UserFields) -> Result<User, UserDAOError> {
+        self.trace("create", self.inner.create(fields)).await
+    }
+
+    async fn update(&self, id: u64, fields: &UserFields, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        self.trace("update", self.inner.update(id, fields, expected_versions)).await
+    }
+
+    async fn delete_by_id(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        self.trace("delete_by_id", self.inner.delete_by_id(id, expected_versions)).await
+    }
+
+    async fn restore(&self, id: u64, expected_versions: Option<&[u64]>) -> Result<User, UserDAOError> {
+        self.trace("restore", self.inner.restore(id, expected_versions)).await
+    }
+
+    async fn purge_deleted(&self, before: DateTime<Utc>) -> Result<u64, UserDAOError> {
+        self.trace("purge_deleted", self.inner.purge_deleted(before)).await
+    }
+
+    async fn batch(&self, ops: &[BatchOp]) -> Result<Vec<Result<User, UserDAOError>>, UserDAOError> {
+        self.trace("batch", self.inner.batch(ops)).await
+    }
+
+    async fn batch_atomic(&self, ops: &[BatchOp]) -> Result<Vec<User>, BatchError> {
+        self.trace("batch_atomic", self.inner.batch_atomic(ops)).await
+    }
+
+    async fn health(&self) -> Result<(), UserDAOError> {
+        self.trace("health", self.inner.health()).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, web::Data, App};
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use serde_json::Value;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    use crate::auth::{test_auth, test_token, Authentication};
+    use crate::configs::{Sqlite, TraceExporter, Tracing};
+    use crate::handlers;
+    use crate::logging::{Captured, RequestLogging};
+    use crate::services::{UserDAO, UserInMemoryDAO, UserSqliteDAO};
+    use super::{tracer_provider, StdoutExporter, TracedUserDAO};
+
+    const TRACE_ID: &str = "4bf92f3577b34da6a3ce929d0e0e4736";
+    const CALLER_SPAN_ID: &str = "00f067aa0ba902b7";
+
+    fn span<'a>(spans: &'a [Value], name: &str) -> &'a Value {
+        spans.iter().find(|span| span["name"] == name).unwrap_or_else(|| panic!("no span {} in {:#?}", name, spans))
+    }
+
+    #[actix_web::test]
+    async fn test_spans_of_request() {
+        let path = std::env::temp_dir().join(format!("telemetry-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let sqlite = UserSqliteDAO::new(&Sqlite { path: path.to_string_lossy().to_string() }).await;
+        sqlite.create(&UserInMemoryDAO::seed_fields(1)).await.unwrap();
+
+        // the statements above ran without tracer
+        let captured = Captured::default();
+        let provider = SdkTracerProvider::builder().with_simple_exporter(StdoutExporter::new(captured.clone())).build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let dao: Box<dyn UserDAO> = Box::new(TracedUserDAO::new(Box::new(sqlite), "sqlite"));
+        let app = test::init_service(
+            App::new()
+                .app_data(Data::new(dao))
+                .app_data(test_auth())
+                .wrap(Authentication)
+                .wrap(RequestLogging)
+                .service(handlers::get_user_by_id),
+        ).await;
+        let req = test::TestRequest::get()
+            .uri("/users/1")
+            .insert_header(("Authorization", format!("Bearer {}", test_token(1, &["reader"]))))
+            .insert_header(("traceparent", format!("00-{}-{}-01", TRACE_ID, CALLER_SPAN_ID)))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(StatusCode::OK, resp.status());
+        // the request span ends with the response body
+        drop(resp);
+        provider.force_flush().unwrap();
+
+        let spans = captured.lines();
+        assert!(spans.iter().all(|span| span["trace_id"] == TRACE_ID), "{:#?}", spans);
+        let request = span(&spans, "GET /users/{id}");
+        assert_eq!(CALLER_SPAN_ID, request["parent_span_id"]);
+        assert_eq!("Server", request["kind"]);
+        assert_eq!("200", request["attributes"]["http.response.status_code"]);
+        let handler = span(&spans, "get_user_by_id");
+        assert_eq!(request["span_id"], handler["parent_span_id"]);
+        let dao = span(&spans, "UserDAO.find_by_id");
+        assert_eq!(handler["span_id"], dao["parent_span_id"]);
+        assert_eq!("sqlite", dao["attributes"]["dao.backend"]);
+        let sql = span(&spans, "SELECT");
+        assert_eq!(dao["span_id"], sql["parent_span_id"]);
+        assert_eq!("Client", sql["kind"]);
+        assert!(sql["attributes"]["db.statement"].as_str().unwrap().ends_with("from users where id = ?"), "{:#?}", sql);
+        assert_eq!("1", sql["attributes"]["db.rows"]);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[actix_web::test]
+    async fn test_failed_dao_call() {
+        let captured = Captured::default();
+        let provider = SdkTracerProvider::builder().with_simple_exporter(StdoutExporter::new(captured.clone())).build();
+        let subscriber = tracing_subscriber::registry().with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _guard = tracing::subscriber::set_default(subscriber);
+
+        let dao = TracedUserDAO::new(Box::new(UserInMemoryDAO::new(None)), "inmemory");
+        assert!(dao.find_by_id(7).await.is_err());
+        provider.force_flush().unwrap();
+
+        let spans = captured.lines();
+        let dao = span(&spans, "UserDAO.find_by_id");
+        assert_eq!("0000000000000000", dao["parent_span_id"]);
+        assert_eq!("NotFound", dao["attributes"]["error"]);
+        assert!(dao["status"].as_str().unwrap().starts_with("Error"), "{:#?}", dao);
+    }
+
+    #[actix_web::test]
+    async fn test_rejects_invalid_sample_ratio() {
+        let cfg = Tracing { exporter: TraceExporter::Stdout, endpoint: String::new(), service_name: "users".to_string(), sample_ratio: 1.5 };
+        let err = tracer_provider(&cfg).err().unwrap();
+        assert_eq!("Invalid tracing sample_ratio 1.5, must be between 0 and 1", err.to_string());
+    }
+}
@@ -0,0 +1,370 @@
+use std::collections::HashMap;
+
+use actix_web::web::Bytes;
+use chrono::{DateTime, Utc};
+use futures::{future, stream::{self, BoxStream}, Stream, StreamExt};
+use serde::{Serialize, Deserialize};
+use validator::Validate;
+
+use crate::{model::{User, UserDAOError, UserFields, UserStatus, UsersQuery}, problem::Problem, services::UserDAO};
+
+// most users encoded into one chunk of the body
+const CHUNK_USERS: usize = 100;
+pub const MAX_IMPORT_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    #[default]
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl DataFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            DataFormat::Json => "application/json",
+            DataFormat::Ndjson => "application/x-ndjson",
+            DataFormat::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            DataFormat::Json => "json",
+            DataFormat::Ndjson => "ndjson",
+            DataFormat::Csv => "csv",
+        }
+    }
+
+    pub fn from_content_type(mime: &str) -> Option<DataFormat> {
+        match mime {
+            "application/json" => Some(DataFormat::Json),
+            "application/x-ndjson" | "application/ndjson" => Some(DataFormat::Ndjson),
+            "text/csv" => Some(DataFormat::Csv),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+pub struct ExportQuery {
+    #[serde(default)]
+    pub format: DataFormat,
+    #[serde(default)]
+    pub include_deleted: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Default)]
+pub struct ImportQuery {
+    // validates the rows without creating users
+    #[serde(default)]
+    pub dry_run: bool,
+}
+
+// line is the line number in CSV and NDJSON bodies and the element position in JSON arrays
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImportLineError {
+    pub line: u64,
+    #[serde(flatten)]
+    pub problem: Problem,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub total: usize,
+    pub imported: usize,
+    pub failed: usize,
+    pub errors: Vec<ImportLineError>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImportRow {
+    pub line: u64,
+    pub fields: Result<UserFields, Problem>,
+}
+
+// CSV has no nesting, users are written column by column
+#[derive(Serialize)]
+struct CsvUser<'a> {
+    id: u64,
+    version: u64,
+    name: &'a str,
+    email: Option<&'a str>,
+    display_name: Option<&'a str>,
+    status: UserStatus,
+    created_at: DateTime<Utc>,
+    updated_at: DateTime<Utc>,
+    deleted_at: Option<DateTime<Utc>>,
+}
+
+const CSV_HEADER: &str = "id,version,name,email,display_name,status,created_at,updated_at,deleted_at\n";
+
+// other columns, e.g. ids of an export, are ignored on import
+#[derive(Deserialize)]
+struct CsvFields {
+    name: String,
+    email: Option<String>,
+    display_name: Option<String>,
+    status: Option<UserStatus>,
+}
+
+impl From<CsvFields> for UserFields {
+    fn from(row: CsvFields) -> Self {
+        UserFields {
+            name: row.name,
+            email: row.email,
+            display_name: row.display_name,
+            status: row.status.unwrap_or_default(),
+        }
+    }
+}
+
+fn storage_error<E: std::error::Error + Send + Sync + 'static>(err: E) -> UserDAOError {
+    UserDAOError::Storage(Box::new(err))
+}
+
+fn encode(format: DataFormat, users: &[User], written: usize) -> Result<Bytes, UserDAOError> {
+    let mut out = vec![];
+    match format {
+        DataFormat::Json => {
+            for (i, user) in users.iter().enumerate() {
+                if written + i > 0 {
+                    out.push(b',');
+                }
+                serde_json::to_writer(&mut out, user).map_err(storage_error)?;
+            }
+        },
+        DataFormat::Ndjson => {
+            for user in users {
+                serde_json::to_writer(&mut out, user).map_err(storage_error)?;
+                out.push(b'\n');
+            }
+        },
+        DataFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new().has_headers(false).from_writer(out);
+            for user in users {
+                writer.serialize(CsvUser {
+                    id: user.id,
+                    version: user.version,
+                    name: &user.fields.name,
+                    email: user.fields.email.as_deref(),
+                    display_name: user.fields.display_name.as_deref(),
+                    status: user.fields.status,
+                    created_at: user.created_at,
+                    updated_at: user.updated_at,
+                    deleted_at: user.deleted_at,
+                }).map_err(storage_error)?;
+            }
+            out = writer.into_inner().map_err(|err| storage_error(err.into_error()))?;
+        },
+    }
+    Ok(Bytes::from(out))
+}
+
+fn header(format: DataFormat) -> Bytes {
+    match format {
+        DataFormat::Json => Bytes::from_static(b"["),
+        DataFormat::Ndjson => Bytes::new(),
+        DataFormat::Csv => Bytes::from_static(CSV_HEADER.as_bytes()),
+    }
+}
+
+fn footer(format: DataFormat) -> Bytes {
+    match format {
+        DataFormat::Json => Bytes::from_static(b"]\n"),
+        _ => Bytes::new(),
+    }
+}
+
+// Encodes users as they come, a chunk takes the users ready at that moment
+pub fn encode_stream(users: BoxStream<'static, Result<User, UserDAOError>>, format: DataFormat) -> impl Stream<Item = Result<Bytes, UserDAOError>> {
+    let mut written = 0;
+    let body = users.ready_chunks(CHUNK_USERS).map(move |chunk| {
+        let users = chunk.into_iter().collect::<Result<Vec<User>, UserDAOError>>()?;
+        let bytes = encode(format, &users, written);
+        written += users.len();
+        bytes
+    });
+
+    stream::once(future::ok(header(format)))
+        .chain(body)
+        .chain(stream::once(future::ok(footer(format))))
+}
+
+pub fn export(dao: &dyn UserDAO, query: &ExportQuery) -> impl Stream<Item = Result<Bytes, UserDAOError>> {
+    let users = UsersQuery { include_deleted: query.include_deleted, ..UsersQuery::default() };
+    encode_stream(dao.list_stream(&users), query.format)
+}
+
+fn malformed<E: ToString>(err: E) -> Problem {
+    Problem::malformed_request(err.to_string())
+}
+
+// A body that can not be split into records fails as a whole,
+// records that do not read as user fields fail on their own line.
+pub fn parse(format: DataFormat, body: &[u8]) -> Result<Vec<ImportRow>, actix_web::Error> {
+    let rows = match format {
+        DataFormat::Json => {
+            let values: Vec<serde_json::Value> = serde_json::from_slice(body).map_err(malformed)?;
+            values.into_iter().enumerate()
+                .map(|(i, value)| ImportRow {
+                    line: i as u64 + 1,
+                    fields: serde_json::from_value(value).map_err(malformed),
+                })
+                .collect()
+        },
+        DataFormat::Ndjson => {
+            let text = std::str::from_utf8(body).map_err(malformed)?;
+            text.lines().enumerate()
+                .filter(|(_, line)| !line.trim().is_empty())
+                .map(|(i, line)| ImportRow {
+                    line: i as u64 + 1,
+                    fields: serde_json::from_str(line).map_err(malformed),
+                })
+                .collect()
+        },
+        DataFormat::Csv => {
+            let mut reader = csv::Reader::from_reader(body);
+            let headers = reader.headers().map_err(malformed)?.clone();
+            reader.records()
+                .map(|record| match record {
+                    Ok(record) => ImportRow {
+                        line: record.position().map_or(0, |p| p.line()),
+                        fields: record.deserialize::<CsvFields>(Some(&headers)).map(UserFields::from).map_err(malformed),
+                    },
+                    Err(err) => ImportRow {
+                        line: err.position().map_or(0, |p| p.line()),
+                        fields: Err(malformed(err)),
+                    },
+                })
+                .collect()
+        },
+    };
+    Ok(rows)
+}
+
+// Applies the UserFields rules to every row and fails rows taking the name or email of an earlier row,
+// conflicts with stored users are only found by the DAO.
+pub fn check(rows: Vec<ImportRow>) -> Vec<ImportRow> {
+    let mut names: HashMap<String, u64> = HashMap::new();
+    let mut emails: HashMap<String, u64> = HashMap::new();
+
+    rows.into_iter()
+        .map(|row| {
+            let fields = match row.fields {
+                Ok(fields) => fields,
+                Err(problem) => return ImportRow { line: row.line, fields: Err(problem) },
+            };
+            if let Err(err) = fields.validate() {
+                return ImportRow { line: row.line, fields: Err(Problem::validation(&err)) };
+            }
+
+            let email = fields.email.as_ref().map(|email| email.to_lowercase());
+            let taken = names.get(&fields.name)
+                .or_else(|| email.as_ref().and_then(|email| emails.get(email)));
+            if let Some(first) = taken {
+                let conflict = Problem::from(&UserDAOError::AlreadyExists);
+                let problem = Problem { detail: format!("User exists on line {}", first), ..conflict };
+                return ImportRow { line: row.line, fields: Err(problem) };
+            }
+
+            names.insert(fields.name.clone(), row.line);
+            if let Some(email) = email {
+                emails.insert(email, row.line);
+            }
+            ImportRow { line: row.line, fields: Ok(fields) }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::web::Bytes;
+    use futures::TryStreamExt;
+
+    use crate::{configs::InMemory, model::{UserFields, UserStatus}, problem::Problem, services::UserInMemoryDAO};
+    use super::{check, export, parse, DataFormat, ExportQuery};
+
+    async fn exported(users: u16, format: DataFormat) -> String {
+        let dao = UserInMemoryDAO::new(Some(&InMemory { users }));
+        let chunks: Vec<Bytes> = export(&dao, &ExportQuery { format, include_deleted: false })
+            .try_collect().await.unwrap();
+        String::from_utf8(chunks.concat()).unwrap()
+    }
+
+    #[actix_web::test]
+    async fn test_export_json_in_chunks() {
+        let users: Vec<serde_json::Value> = serde_json::from_str(&exported(205, DataFormat::Json).await).unwrap();
+        assert_eq!(205, users.len());
+        assert_eq!(Some(205), users[204]["id"].as_u64());
+
+        assert_eq!("[]\n", exported(0, DataFormat::Json).await);
+    }
+
+    #[actix_web::test]
+    async fn test_export_ndjson() {
+        let ndjson = exported(2, DataFormat::Ndjson).await;
+        let names: Vec<String> = ndjson.lines()
+            .map(|line| serde_json::from_str::<serde_json::Value>(line).unwrap()["name"].as_str().unwrap().to_string())
+            .collect();
+        assert_eq!(vec!["User1", "User2"], names);
+    }
+
+    #[actix_web::test]
+    async fn test_export_csv_imports_back() {
+        let csv = exported(2, DataFormat::Csv).await;
+        assert!(csv.starts_with("id,version,name,email,display_name,status,created_at,updated_at,deleted_at\n1,1,User1,user1@example.com,User 1,active,"), "{}", csv);
+
+        let rows = parse(DataFormat::Csv, csv.as_bytes()).unwrap();
+        let fields: Vec<(u64, UserFields)> = rows.into_iter().map(|row| (row.line, row.fields.unwrap())).collect();
+        assert_eq!(vec![(2, UserInMemoryDAO::seed_fields(1)), (3, UserInMemoryDAO::seed_fields(2))], fields);
+    }
+
+    #[test]
+    fn test_parse_csv_optional_columns() {
+        let csv = "name,status\nSuspended,suspended\nActive,\nBroken,sleeping\n";
+        let rows = parse(DataFormat::Csv, csv.as_bytes()).unwrap();
+
+        assert_eq!(Ok(UserFields { name: "Suspended".to_string(), status: UserStatus::Suspended, ..UserFields::default() }), rows[0].fields);
+        assert_eq!(Ok(UserFields { name: "Active".to_string(), ..UserFields::default() }), rows[1].fields);
+        assert_eq!((4, 400), (rows[2].line, rows[2].fields.as_ref().unwrap_err().status));
+    }
+
+    #[test]
+    fn test_parse_ndjson_lines() {
+        let ndjson = "{\"name\": \"First\"}\n\n{\"nam\": \"Second\"}\n";
+        let rows = parse(DataFormat::Ndjson, ndjson.as_bytes()).unwrap();
+
+        assert_eq!(vec![1, 3], rows.iter().map(|row| row.line).collect::<Vec<u64>>());
+        assert!(rows[0].fields.is_ok());
+        assert!(rows[1].fields.as_ref().unwrap_err().detail.contains("missing field `name`"));
+    }
+
+    #[test]
+    fn test_parse_malformed_json() {
+        let err = parse(DataFormat::Json, b"{\"name\": \"First\"}").unwrap_err();
+        let problem = err.as_error::<Problem>().unwrap();
+        assert_eq!("/problems/malformed-request", problem.problem_type);
+    }
+
+    #[test]
+    fn test_check_rows() {
+        let json = "[{\"name\": \"First\", \"email\": \"one@example.com\"}, {\"name\": \"ab\"}, \
+            {\"name\": \"First\"}, {\"name\": \"Second\", \"email\": \"ONE@example.com\"}, {\"name\": \"Third\"}]";
+        let rows = check(parse(DataFormat::Json, json.as_bytes()).unwrap());
+
+        let outcome: Vec<(u64, Result<String, String>)> = rows.into_iter()
+            .map(|row| (row.line, row.fields.map(|f| f.name).map_err(|p| p.detail)))
+            .collect();
+        assert_eq!(vec![
+            (1, Ok("First".to_string())),
+            (2, Err("Invalid value of: name".to_string())),
+            (3, Err("User exists on line 1".to_string())),
+            (4, Err("User exists on line 1".to_string())),
+            (5, Ok("Third".to_string())),
+        ], outcome);
+    }
+}
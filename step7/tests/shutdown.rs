@@ -0,0 +1,78 @@
+// Sends SIGTERM to the server binary while a request is in flight
+#![cfg(unix)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::process::{Child, Command, Stdio};
+use std::thread::sleep;
+use std::time::{Duration, Instant};
+
+const STARTUP: Duration = Duration::from_secs(30);
+
+fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port()
+}
+
+fn get(addr: SocketAddr, path: &str) -> std::io::Result<String> {
+    let mut stream = TcpStream::connect(addr)?;
+    write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n", path)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+fn wait_ready(server: &mut Child, addr: SocketAddr) {
+    let start = Instant::now();
+    while start.elapsed() < STARTUP {
+        assert!(server.try_wait().unwrap().is_none(), "server exited on startup");
+        if get(addr, "/health/ready").map(|response| response.starts_with("HTTP/1.1 200")).unwrap_or(false) {
+            return;
+        }
+        sleep(Duration::from_millis(50));
+    }
+    panic!("server not ready within {} s", STARTUP.as_secs());
+}
+
+#[test]
+fn test_sigterm_drains_in_flight_request() {
+    let dir = std::env::temp_dir().join(format!("shutdown-it-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let port = free_port();
+    std::fs::write(dir.join("application.yaml"), format!(
+        "server:\n  host: 127.0.0.1\n  port: {}\n  shutdown_delay_secs: 1\n  shutdown_timeout_secs: 10\nstore:\n  inmemory:\n    users: 1\n",
+        port)).unwrap();
+    let mut server = Command::new(env!("CARGO_BIN_EXE_rest_database_orm"))
+        .current_dir(&dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let addr = SocketAddr::from(([127, 0, 0, 1], port));
+    wait_ready(&mut server, addr);
+
+    // the body is held back, the request stays in flight across the signal
+    let body = r#"{"name":"Latecomer"}"#;
+    let mut request = TcpStream::connect(addr).unwrap();
+    write!(request, "POST /users HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+        body.len(), &body[..5]).unwrap();
+    sleep(Duration::from_millis(200));
+    let killed = Command::new("kill").args(["-TERM", &server.id().to_string()]).status().unwrap();
+    assert!(killed.success());
+
+    // not ready during the delay, the connection is still served
+    sleep(Duration::from_millis(200));
+    let ready = get(addr, "/health/ready").unwrap();
+    assert!(ready.starts_with("HTTP/1.1 503"), "{}", ready);
+
+    request.write_all(&body.as_bytes()[5..]).unwrap();
+    let mut response = String::new();
+    request.read_to_string(&mut response).unwrap();
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+
+    let status = server.wait().unwrap();
+    assert!(status.success());
+    let mut logs = String::new();
+    server.stdout.take().unwrap().read_to_string(&mut logs).unwrap();
+    assert!(logs.contains("Server stopped"), "{}", logs);
+    assert!(get(addr, "/health/live").is_err());
+    let _ = std::fs::remove_dir_all(&dir);
+}